@@ -0,0 +1,46 @@
+//! Derived parameters computed from decoded samples
+
+pub mod acc;
+pub mod rpeak;
+pub mod reo;
+
+/// Span of a recording with constant derived state
+#[derive(Debug, Clone)]
+pub struct Segment<T> {
+    // Seconds from the recording start
+    pub start: f32,
+    pub end: f32,
+    pub value: T,
+}
+
+/// Merges consecutive equal epochs into segments
+pub fn segments<T: PartialEq + Clone>(epochs: impl Iterator<Item = (f32, f32, T)>) -> Vec<Segment<T>> {
+    let mut out: Vec<Segment<T>> = Vec::new();
+    for (start, end, value) in epochs {
+        match out.last_mut() {
+            Some(last) if last.value == value => last.end = end,
+            _ => out.push(Segment { start, end, value }),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn equal_epochs_merge() {
+        let epochs = vec![(0., 10., 'a'), (10., 20., 'a'), (20., 30., 'b'), (30., 40., 'a')];
+        let spans: Vec<_> = segments(epochs.into_iter())
+            .into_iter()
+            .map(|s| (s.start, s.end, s.value))
+            .collect();
+        assert_eq!(spans, vec![(0., 20., 'a'), (20., 30., 'b'), (30., 40., 'a')]);
+    }
+
+    #[test]
+    fn no_epochs_no_segments() {
+        assert!(segments(std::iter::empty::<(f32, f32, u8)>()).is_empty());
+    }
+}
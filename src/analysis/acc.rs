@@ -0,0 +1,261 @@
+use crate::vis::Group;
+use crate::vis::history::History;
+
+use super::Segment;
+
+// Summary interval, seconds
+const EPOCH_SEC: f32 = 10.;
+// Gravity low pass time constant, seconds
+const GRAVITY_TAU: f32 = 2.;
+// Dynamic acceleration levels, fractions of g
+const LIGHT: f32 = 0.03;
+const MODERATE: f32 = 0.1;
+const VIGOROUS: f32 = 0.3;
+// Step detection
+const STEP_THRESHOLD: f32 = 0.15;
+const STEP_MIN_INTERVAL: f32 = 0.3;
+// Upright if the body axis is within this angle of vertical, degrees
+const UPRIGHT_ANGLE: f32 = 45.;
+
+// Sensor axes as mounted on the chest: X to the patient's left,
+// Y to the head, Z out of the chest
+const AXIS_X: usize = 0;
+const AXIS_Y: usize = 1;
+const AXIS_Z: usize = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Position {
+    Upright,
+    Supine,
+    Prone,
+    Left,
+    Right,
+    Unknown,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Activity {
+    Rest,
+    Light,
+    Moderate,
+    Vigorous,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct State {
+    pub position: Position,
+    pub activity: Activity,
+    // Mean dynamic acceleration over the epoch, g
+    pub intensity: f32,
+    pub steps_per_min: f32,
+}
+
+impl Default for State {
+    fn default() -> Self {
+        Self {
+            position: Position::Unknown,
+            activity: Activity::Rest,
+            intensity: 0.,
+            steps_per_min: 0.,
+        }
+    }
+}
+
+/// Streaming accelerometer analysis, fed with raw ACC_IN samples
+pub struct Analyzer {
+    rate: f32,
+    gravity: Option<[f32; 3]>,
+    // Samples since start
+    n: u64,
+    // Epoch accumulators
+    epoch_n: u32,
+    dyn_sum: f32,
+    steps: u32,
+    last_step: Option<u64>,
+    above: bool,
+    state: State,
+}
+
+impl Analyzer {
+    pub fn new(rate: f32) -> Self {
+        Self {
+            rate,
+            gravity: None,
+            n: 0,
+            epoch_n: 0,
+            dyn_sum: 0.,
+            steps: 0,
+            last_step: None,
+            above: false,
+            state: State::default(),
+        }
+    }
+
+    pub fn state(&self) -> State {
+        self.state
+    }
+
+    /// Returns the finished epoch summary, if any
+    pub fn push(&mut self, sample: &[i32]) -> Option<State> {
+        if sample.len() < 3 {
+            return None;
+        }
+        let a = [sample[0] as f32, sample[1] as f32, sample[2] as f32];
+        let alpha = 1. / (GRAVITY_TAU * self.rate);
+        let g = match self.gravity.as_mut() {
+            Some(g) => {
+                for i in 0 .. 3 {
+                    g[i] += alpha * (a[i] - g[i]);
+                }
+                *g
+            }
+            None => *self.gravity.get_or_insert(a),
+        };
+        let g_norm = norm(g).max(1.);
+
+        // Dynamic part in g units
+        let d = norm([a[0] - g[0], a[1] - g[1], a[2] - g[2]]) / g_norm;
+        self.dyn_sum += d;
+
+        // Step is a rising edge of dynamic acceleration, debounced
+        if d > STEP_THRESHOLD && !self.above {
+            let far = self.last_step.map_or(true, |l| (self.n - l) as f32 >= STEP_MIN_INTERVAL * self.rate);
+            if far {
+                self.steps += 1;
+                self.last_step = Some(self.n);
+            }
+        }
+        self.above = d > STEP_THRESHOLD;
+
+        self.n += 1;
+        self.epoch_n += 1;
+        if self.epoch_n as f32 >= EPOCH_SEC * self.rate {
+            let intensity = self.dyn_sum / self.epoch_n as f32;
+            self.state = State {
+                position: position(g),
+                activity: activity(intensity),
+                intensity,
+                steps_per_min: self.steps as f32 * 60. / EPOCH_SEC,
+            };
+            self.epoch_n = 0;
+            self.dyn_sum = 0.;
+            self.steps = 0;
+            return Some(self.state);
+        }
+        None
+    }
+}
+
+/// Position and activity segments of a decoded recording
+pub fn timeline(h: &History) -> Vec<Segment<(Position, Activity)>> {
+    if h.group != Group::ACC_IN {
+        return Vec::new();
+    }
+    let mut analyzer = Analyzer::new(h.group.sample_rate());
+    let epochs = (h.start() .. h.end()).filter_map(|idx| {
+        let sample: Vec<i32> = (0 .. 3).filter_map(|ch| h.value(idx, ch)).collect();
+        analyzer.push(&sample).map(|s| {
+            let end = h.time(idx + 1);
+            (end - EPOCH_SEC, end, (s.position, s.activity))
+        })
+    });
+    super::segments(epochs)
+}
+
+fn norm(v: [f32; 3]) -> f32 {
+    (v[0] * v[0] + v[1] * v[1] + v[2] * v[2]).sqrt()
+}
+
+fn activity(intensity: f32) -> Activity {
+    if intensity >= VIGOROUS {
+        Activity::Vigorous
+    } else if intensity >= MODERATE {
+        Activity::Moderate
+    } else if intensity >= LIGHT {
+        Activity::Light
+    } else {
+        Activity::Rest
+    }
+}
+
+/// Body position from the static acceleration in sensor axes
+fn position(g: [f32; 3]) -> Position {
+    let n = norm(g);
+    if n < 1. {
+        return Position::Unknown;
+    }
+    // At rest the sensor reads 1g pointing up, along +Y when standing
+    let (x, y, z) = (g[AXIS_X] / n, g[AXIS_Y] / n, g[AXIS_Z] / n);
+    if y >= UPRIGHT_ANGLE.to_radians().cos() {
+        return Position::Upright;
+    }
+    if z.abs() >= x.abs() {
+        if z > 0. { Position::Supine } else { Position::Prone }
+    } else if x < 0. {
+        // Left side down
+        Position::Left
+    } else {
+        Position::Right
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RATE: f32 = 25.;
+    const G: i32 = 1000;
+
+    fn epoch(a: &mut Analyzer, sample: impl Fn(u64) -> [i32; 3]) -> State {
+        for n in 0 .. (EPOCH_SEC * RATE) as u64 - 1 {
+            assert_eq!(a.push(&sample(n)), None);
+        }
+        a.push(&sample((EPOCH_SEC * RATE) as u64 - 1)).unwrap()
+    }
+
+    #[test]
+    fn position_from_gravity() {
+        let g = G as f32;
+        assert_eq!(position([0., g, 0.]), Position::Upright);
+        assert_eq!(position([0., 0., g]), Position::Supine);
+        assert_eq!(position([0., 0., -g]), Position::Prone);
+        assert_eq!(position([-g, 0., 0.]), Position::Left);
+        assert_eq!(position([g, 0., 0.]), Position::Right);
+        // Leaning back by 30 degrees is still upright
+        assert_eq!(position([0., g * 0.866, g * 0.5]), Position::Upright);
+        assert_eq!(position([0., 0., 0.]), Position::Unknown);
+    }
+
+    #[test]
+    fn activity_levels() {
+        assert_eq!(activity(0.), Activity::Rest);
+        assert_eq!(activity(LIGHT), Activity::Light);
+        assert_eq!(activity(MODERATE), Activity::Moderate);
+        assert_eq!(activity(VIGOROUS + 1.), Activity::Vigorous);
+    }
+
+    #[test]
+    fn still_patient_rests() {
+        let mut a = Analyzer::new(RATE);
+        let s = epoch(&mut a, |_| [0, 0, G]);
+        assert_eq!((s.position, s.activity), (Position::Supine, Activity::Rest));
+        assert_eq!(s.steps_per_min, 0.);
+        assert_eq!(a.state(), s);
+    }
+
+    #[test]
+    fn steps_are_counted() {
+        let mut a = Analyzer::new(RATE);
+        // Jolt every 12 samples (0.48 s), 21 of them in the epoch
+        let s = epoch(&mut a, |n| if n % 12 == 6 { [0, 0, G + 500] } else { [0, 0, G] });
+        assert_eq!(s.steps_per_min, 21. * 60. / EPOCH_SEC);
+        assert!(s.activity > Activity::Rest);
+    }
+
+    #[test]
+    fn short_sample_is_ignored() {
+        let mut a = Analyzer::new(RATE);
+        assert_eq!(a.push(&[1, 2]), None);
+        assert_eq!(a.n, 0);
+    }
+}
@@ -0,0 +1,314 @@
+use std::collections::VecDeque;
+
+use crate::vis::Group;
+use crate::vis::history::History;
+
+use super::Segment;
+
+// Respiration low pass time constant, seconds (~0.7 Hz corner)
+const RESP_TAU: f32 = 0.25;
+// Baseline drift time constant, seconds
+const BASELINE_TAU: f32 = 10.;
+// Breath detection hysteresis, fraction of the running respiration amplitude
+const HYSTERESIS: f32 = 0.2;
+// Breaths averaged for the rate
+const RATE_BREATHS: usize = 5;
+// No breath for this long is an apnea-like pause, seconds
+const APNEA_SEC: f32 = 10.;
+// Cardiac component is measured in this window after an R-peak, seconds
+const CARDIAC_WIN_SEC: f32 = 0.4;
+// Kept cardiac component history, seconds
+const CARDIAC_HISTORY_SEC: f32 = 2.;
+// Beats averaged for the cardiac amplitude
+const CARDIAC_BEATS: usize = 8;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct State {
+    // Breaths per minute
+    pub breath_rate: Option<f32>,
+    pub apnea: bool,
+    // Mean beat to beat impedance swing, raw units
+    pub cardiac_dz: Option<f32>,
+}
+
+/// Streaming REO analysis, fed with raw impedance samples
+pub struct Analyzer {
+    rate: f32,
+    n: u64,
+    resp: Option<f32>,
+    baseline: Option<f32>,
+    amplitude: f32,
+    inhale: bool,
+    breaths: VecDeque<u64>,
+    cardiac: VecDeque<f32>,
+    // R-peaks mapped to REO sample indexes, waiting for their window
+    pending: VecDeque<u64>,
+    beats: VecDeque<f32>,
+    // Beats measured by the last push: window start and swing
+    new_beats: Vec<(u64, f32)>,
+}
+
+impl Analyzer {
+    pub fn new(rate: f32) -> Self {
+        Self {
+            rate,
+            n: 0,
+            resp: None,
+            baseline: None,
+            amplitude: 0.,
+            inhale: false,
+            breaths: VecDeque::new(),
+            cardiac: VecDeque::new(),
+            pending: VecDeque::new(),
+            beats: VecDeque::new(),
+            new_beats: Vec::new(),
+        }
+    }
+
+    /// Returns true when a breath started at this sample
+    pub fn push(&mut self, x: i32) -> bool {
+        let x = x as f32;
+        let resp = ema(&mut self.resp, x, 1. / (RESP_TAU * self.rate));
+        let base = ema(&mut self.baseline, x, 1. / (BASELINE_TAU * self.rate));
+
+        // Respiration is the slow part above baseline, the rest is cardiac
+        let r = resp - base;
+        self.amplitude += (r.abs() - self.amplitude) / (BASELINE_TAU * self.rate);
+        let h = HYSTERESIS * self.amplitude;
+
+        self.new_beats.clear();
+        let mut breath = false;
+        if !self.inhale && r > h {
+            self.inhale = true;
+            breath = true;
+            self.breaths.push_back(self.n);
+            if self.breaths.len() > RATE_BREATHS + 1 {
+                let _ = self.breaths.pop_front();
+            }
+        } else if self.inhale && r < -h {
+            self.inhale = false;
+        }
+
+        self.cardiac.push_back(x - resp);
+        if self.cardiac.len() as f32 > CARDIAC_HISTORY_SEC * self.rate {
+            let _ = self.cardiac.pop_front();
+        }
+        self.n += 1;
+        self.measure_beats();
+
+        breath
+    }
+
+    /// Registers an R-peak given as time in seconds from the stream start
+    pub fn r_peak(&mut self, time: f32) {
+        self.pending.push_back((time * self.rate) as u64);
+    }
+
+    fn measure_beats(&mut self) {
+        let win = (CARDIAC_WIN_SEC * self.rate) as u64;
+        let first = self.n - self.cardiac.len() as u64;
+        while let Some(&start) = self.pending.front() {
+            if start + win > self.n {
+                break;
+            }
+            let _ = self.pending.pop_front();
+            if start < first {
+                continue;
+            }
+            let from = (start - first) as usize;
+            let w = self.cardiac.iter().skip(from).take(win as usize);
+            let (min, max) = w.fold((f32::MAX, f32::MIN), |(a, b), &v| (a.min(v), b.max(v)));
+            self.beats.push_back(max - min);
+            self.new_beats.push((start, max - min));
+            if self.beats.len() > CARDIAC_BEATS {
+                let _ = self.beats.pop_front();
+            }
+        }
+    }
+
+    pub fn state(&self) -> State {
+        let breath_rate = if self.breaths.len() >= 2 {
+            let span = (self.breaths[self.breaths.len() - 1] - self.breaths[0]) as f32 / self.rate;
+            Some(60. * (self.breaths.len() - 1) as f32 / span)
+        } else {
+            None
+        };
+        let since = self.breaths.back().map_or(self.n, |&b| self.n - b) as f32 / self.rate;
+        let cardiac_dz = if self.beats.is_empty() {
+            None
+        } else {
+            Some(self.beats.iter().sum::<f32>() / self.beats.len() as f32)
+        };
+        State {
+            breath_rate,
+            apnea: since >= APNEA_SEC && self.n as f32 > APNEA_SEC * self.rate,
+            cardiac_dz,
+        }
+    }
+}
+
+/// Analysis of a decoded recording
+#[derive(Debug, Clone, Default)]
+pub struct Report {
+    pub mean_breath_rate: Option<f32>,
+    pub apneas: Vec<Segment<()>>,
+    // Per beat impedance swing: R-peak time in seconds and value
+    pub cardiac: Vec<(f32, f32)>,
+}
+
+/// `r_peaks` are ECG R-peak times in seconds, if ECG is available
+pub fn report(h: &History, r_peaks: &[f32]) -> Report {
+    if h.group != Group::REO {
+        return Report::default();
+    }
+    let mut a = Analyzer::new(h.group.sample_rate());
+    // Analyzer counts samples from the history start
+    let t0 = h.time(h.start());
+    let mut breaths = Vec::new();
+    let mut peaks = r_peaks.iter().peekable();
+    let mut cardiac = Vec::new();
+
+    for idx in h.start() .. h.end() {
+        let t = h.time(idx);
+        while let Some(&&rt) = peaks.peek() {
+            if rt > t {
+                break;
+            }
+            a.r_peak(rt - t0);
+            let _ = peaks.next();
+        }
+        if let Some(v) = h.value(idx, 0) {
+            if a.push(v) {
+                breaths.push(t);
+            }
+            for &(start, dz) in &a.new_beats {
+                cardiac.push((t0 + start as f32 / a.rate, dz));
+            }
+        }
+    }
+
+    let mean_breath_rate = if breaths.len() >= 2 {
+        let span = breaths[breaths.len() - 1] - breaths[0];
+        Some(60. * (breaths.len() - 1) as f32 / span)
+    } else {
+        None
+    };
+
+    let end = h.time(h.end());
+    let mut prev = h.time(h.start());
+    let apneas = breaths
+        .iter()
+        .chain(std::iter::once(&end))
+        .filter_map(|&b| {
+            let gap = (prev, b);
+            prev = b;
+            if gap.1 - gap.0 >= APNEA_SEC {
+                Some(Segment { start: gap.0, end: gap.1, value: () })
+            } else {
+                None
+            }
+        })
+        .collect();
+
+    Report {
+        mean_breath_rate,
+        apneas,
+        cardiac,
+    }
+}
+
+fn ema(state: &mut Option<f32>, x: f32, alpha: f32) -> f32 {
+    let s = state.get_or_insert(x);
+    *s += alpha * (x - *s);
+    *s
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RATE: f32 = 25.;
+    const BASE: f32 = 10_000.;
+
+    // Breathing at 15/min
+    fn breath(n: u64, rate: f32) -> i32 {
+        let t = n as f32 / rate;
+        (BASE + 1000. * (2. * std::f32::consts::PI * 0.25 * t).sin()) as i32
+    }
+
+    #[test]
+    fn breath_rate_is_measured() {
+        let mut a = Analyzer::new(RATE);
+        let breaths = (0 .. (60. * RATE) as u64).filter(|&n| a.push(breath(n, RATE))).count();
+        assert!((13 ..= 16).contains(&breaths), "{} breaths", breaths);
+        let s = a.state();
+        let rate = s.breath_rate.unwrap();
+        assert!((rate - 15.).abs() < 1., "{} per minute", rate);
+        assert!(!s.apnea);
+    }
+
+    #[test]
+    fn pause_is_an_apnea() {
+        let mut a = Analyzer::new(RATE);
+        let mut n = 0;
+        while n < (30. * RATE) as u64 {
+            a.push(breath(n, RATE));
+            n += 1;
+        }
+        assert!(!a.state().apnea);
+        let last = breath(n, RATE);
+        for _ in 0 .. (APNEA_SEC * RATE) as u64 + 2 * RATE as u64 {
+            a.push(last);
+        }
+        assert!(a.state().apnea);
+    }
+
+    #[test]
+    fn no_apnea_before_enough_data() {
+        let mut a = Analyzer::new(RATE);
+        for _ in 0 .. (APNEA_SEC * RATE) as u64 - 1 {
+            a.push(BASE as i32);
+        }
+        assert!(!a.state().apnea);
+    }
+
+    #[test]
+    fn cardiac_swing_follows_r_peaks() {
+        let mut a = Analyzer::new(RATE);
+        assert_eq!(a.state().cardiac_dz, None);
+        for n in 0 .. (10. * RATE) as u64 {
+            // Beat every second, the impedance pulse comes 0.1 s after the R-peak
+            if n % RATE as u64 == 0 {
+                a.r_peak(n as f32 / RATE);
+            }
+            let pulse = if n % RATE as u64 == 2 || n % RATE as u64 == 3 { 200 } else { 0 };
+            a.push(BASE as i32 + pulse);
+        }
+        let dz = a.state().cardiac_dz.unwrap();
+        assert!(dz > 150. && dz < 260., "dz {}", dz);
+    }
+
+    #[test]
+    fn report_lists_apneas() {
+        let mut h = History::new(Group::REO, 1);
+        let rate = Group::REO.sample_rate();
+        let total = (60. * rate) as u64;
+        for n in 0 .. total {
+            let v = if (n as f32) < 20. * rate { breath(n, rate) } else { BASE as i32 };
+            h.push(&[v]);
+        }
+        let r = report(&h, &[]);
+        assert!(r.mean_breath_rate.is_some());
+        assert_eq!(r.apneas.len(), 1, "{:?}", r.apneas);
+        let a = &r.apneas[0];
+        assert!(a.start > 15. && a.start < 21., "{:?}", a);
+        assert_eq!(a.end, 60.);
+        assert!(r.cardiac.is_empty());
+    }
+
+    #[test]
+    fn report_needs_reo_history() {
+        let h = History::new(Group::ECG, 1);
+        assert!(report(&h, &[]).apneas.is_empty());
+    }
+}
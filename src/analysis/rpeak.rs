@@ -0,0 +1,147 @@
+use std::collections::VecDeque;
+
+use crate::vis::history::History;
+
+// Moving integration window, seconds
+const INTEGRATION_SEC: f32 = 0.08;
+// Refractory period after a beat, seconds
+const REFRACTORY_SEC: f32 = 0.25;
+// Threshold learning period, seconds
+const LEARN_SEC: f32 = 2.;
+
+/// Simplified Pan-Tompkins QRS detector on a single ECG channel
+pub struct Detector {
+    rate: f32,
+    n: u64,
+    prev: Option<f32>,
+    window: VecDeque<f32>,
+    sum: f32,
+    // Running signal and noise peak levels of the integrated signal
+    spk: f32,
+    npk: f32,
+    // Current excursion above threshold: index and value of its maximum
+    peak: Option<(u64, f32)>,
+    last: Option<u64>,
+}
+
+impl Detector {
+    pub fn new(rate: f32) -> Self {
+        Self {
+            rate,
+            n: 0,
+            prev: None,
+            window: VecDeque::new(),
+            sum: 0.,
+            spk: 0.,
+            npk: 0.,
+            peak: None,
+            last: None,
+        }
+    }
+
+    fn threshold(&self) -> f32 {
+        self.npk + 0.25 * (self.spk - self.npk)
+    }
+
+    /// Returns the sample index of a detected R-peak
+    pub fn push(&mut self, x: i32) -> Option<u64> {
+        let x = x as f32;
+        let d = x - self.prev.unwrap_or(x);
+        self.prev = Some(x);
+
+        self.window.push_back(d * d);
+        self.sum += d * d;
+        if self.window.len() > (INTEGRATION_SEC * self.rate) as usize {
+            self.sum -= self.window.pop_front().unwrap_or(0.);
+        }
+        let m = self.sum / self.window.len() as f32;
+
+        let idx = self.n;
+        self.n += 1;
+
+        if (idx as f32) < LEARN_SEC * self.rate {
+            self.spk = self.spk.max(m);
+            self.npk = 0.125 * self.spk;
+            return None;
+        }
+
+        let refractory = self.last.map_or(false, |l| ((idx - l) as f32) < REFRACTORY_SEC * self.rate);
+        if m > self.threshold() && !refractory {
+            match self.peak {
+                Some((_, v)) if v >= m => (),
+                _ => self.peak = Some((idx, m)),
+            }
+            None
+        } else if let Some((pidx, v)) = self.peak.take() {
+            self.spk = 0.125 * v + 0.875 * self.spk;
+            // Integration delays the maximum by about half the window
+            let r = pidx.saturating_sub((INTEGRATION_SEC * self.rate / 2.) as u64);
+            self.last = Some(pidx);
+            Some(r)
+        } else {
+            if !refractory {
+                self.npk = 0.125 * m + 0.875 * self.npk;
+            }
+            None
+        }
+    }
+}
+
+/// R-peak indexes of a decoded ECG channel
+pub fn detect(h: &History, ch: usize) -> Vec<u64> {
+    let mut det = Detector::new(h.group.sample_rate());
+    (h.start() .. h.end())
+        .filter_map(|idx| h.value(idx, ch))
+        .filter_map(|v| det.push(v))
+        .map(|r| r + h.start())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RATE: f32 = 250.;
+
+    // Flat line with a 5 sample QRS spike at every peak index
+    fn ecg(len: u64, peaks: &[u64]) -> Vec<i32> {
+        (0 .. len)
+            .map(|n| {
+                let d = peaks.iter().map(|&p| (n as i64 - p as i64).abs()).min().unwrap_or(i64::MAX);
+                if d <= 2 { 1000 - 400 * d as i32 } else { 0 }
+            })
+            .collect()
+    }
+
+    fn run(signal: &[i32]) -> Vec<u64> {
+        let mut det = Detector::new(RATE);
+        signal.iter().filter_map(|&x| det.push(x)).collect()
+    }
+
+    #[test]
+    fn beats_after_learning() {
+        let peaks: Vec<u64> = (0 .. 10).map(|i| 125 + 250 * i).collect();
+        let found = run(&ecg(2500, &peaks));
+        // Peaks inside the learning period only train the thresholds
+        let expected: Vec<u64> = peaks.iter().copied().filter(|&p| p as f32 > LEARN_SEC * RATE).collect();
+        assert_eq!(found.len(), expected.len(), "{:?}", found);
+        for (f, e) in found.iter().zip(&expected) {
+            assert!((*f as i64 - *e as i64).abs() <= 12, "{} detected for {}", f, e);
+        }
+    }
+
+    #[test]
+    fn flat_line_has_no_beats() {
+        assert!(run(&vec![100; 2500]).is_empty());
+    }
+
+    #[test]
+    fn refractory_period_skips_close_spike() {
+        let mut peaks: Vec<u64> = (0 .. 10).map(|i| 125 + 250 * i).collect();
+        // T wave like spike 0.12 s after a beat
+        peaks.push(1125 + 30);
+        peaks.sort();
+        let found = run(&ecg(2500, &peaks));
+        assert!(!found.iter().any(|&f| f > 1140 && f < 1250), "{:?}", found);
+    }
+}
@@ -1,25 +1,20 @@
-
 use seed::prelude::*;
 
-use crate::device::Desc;
-
-pub async fn load(_desc: Desc) -> String {
-    let scheme = fetch_scheme().await;
-
-    scheme
-}
+use crate::error::Error;
 
-async fn fetch_scheme() -> String {
-    let response = fetch("public/scheme.json")
+/// Loads the scheme named by the device profile
+pub async fn load(url: String) -> Result<String, Error> {
+    fetch_scheme(&url)
         .await
-        .expect("HTTP request failed");
+        .map_err(|e| Error::Scheme(format!("{}: {:?}", url, e)))
+}
 
-    let user: String = response
-        .check_status() // ensure we've got 2xx status
-        .expect("status check failed")
+async fn fetch_scheme(url: &str) -> Result<String, seed::browser::fetch::FetchError> {
+    let scheme = fetch(url)
+        .await?
+        .check_status()? // ensure we've got 2xx status
         .text()
-        .await
-        .expect("Failed to des");
+        .await?;
 
-    user
+    Ok(scheme)
 }
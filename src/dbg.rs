@@ -0,0 +1,202 @@
+use std::convert::TryFrom;
+use std::rc::Rc;
+
+use ellocopo2::owned::{Msg as DevMsg, Value};
+use ellocopo2::AnswerCode;
+
+use holter_support::error::Error as HolterError;
+
+use crate::cmd;
+use crate::device;
+
+// Oldest entries are dropped past this
+const HISTORY_LEN: usize = 200;
+
+/// `/dbg` registers read at once
+#[derive(Debug, Clone)]
+pub struct Snapshot {
+    pub last: String,
+    pub last_code: u32,
+    pub flags: u32,
+}
+
+#[derive(Debug, Clone)]
+pub enum Source {
+    // `/dbg/last_code` changed between reads
+    Dbg,
+    // ERR_CUSTOM answer to a request
+    Answer(String),
+}
+
+#[derive(Debug, Clone)]
+pub struct Entry {
+    pub t: f64,
+    pub serial: String,
+    pub source: Source,
+    pub code: u32,
+    pub text: String,
+}
+
+#[derive(Default)]
+pub struct Panel {
+    pub snapshot: Option<Snapshot>,
+    pub history: Vec<Entry>,
+    pub reading: bool,
+}
+
+impl Panel {
+    /// Keeps the snapshot, a new `last_code` goes to the history
+    pub fn update(&mut self, now: f64, serial: &str, snapshot: Snapshot) {
+        let changed = self.snapshot.as_ref().map_or(true, |s| s.last_code != snapshot.last_code);
+        if changed && snapshot.last_code != 0 {
+            let text = if snapshot.last.is_empty() {
+                decode(snapshot.last_code)
+            } else {
+                format!("{} ({})", decode(snapshot.last_code), snapshot.last)
+            };
+            self.push(now, serial, Source::Dbg, snapshot.last_code, text);
+        }
+        self.snapshot = Some(snapshot);
+    }
+
+    pub fn answer_error(&mut self, now: f64, serial: &str, path: String, code: u32) {
+        let text = decode(code);
+        log::error!("{}: {}", path, text);
+        self.push(now, serial, Source::Answer(path), code, text);
+    }
+
+    fn push(&mut self, now: f64, serial: &str, source: Source, code: u32, text: String) {
+        if self.history.len() >= HISTORY_LEN {
+            self.history.remove(0);
+        }
+        self.history.push(Entry { t: now, serial: serial.to_string(), source, code, text });
+    }
+}
+
+/// Firmware error name, raw code if this build does not know it
+pub fn decode(code: u32) -> String {
+    match HolterError::try_from(code) {
+        Ok(err) => format!("{:?}", err),
+        Err(_) => format!("unknown code {}", code),
+    }
+}
+
+/// Every bit up to the highest named or set one, names come from the device profile
+pub fn flags(flags: u32, names: &[String]) -> Vec<(u32, String, bool)> {
+    let top = (32 - flags.leading_zeros()).max(names.len().min(32) as u32);
+    (0 .. top)
+        .map(|bit| {
+            let name = names
+                .get(bit as usize)
+                .filter(|n| !n.is_empty())
+                .cloned()
+                .unwrap_or_else(|| format!("bit {}", bit));
+            (bit, name, flags & (1 << bit) != 0)
+        })
+        .collect()
+}
+
+pub async fn read(device: Rc<device::Device>) -> Result<Snapshot, String> {
+    async fn get(device: &Rc<device::Device>, path: &str) -> Result<Value, String> {
+        cmd(device, DevMsg(AnswerCode::OK_READ, path.to_string(), Value::UNIT(())))
+            .await
+            .map_err(|()| format!("no answer to {}", path))
+    }
+
+    let last = match get(&device, "/dbg/last").await? {
+        Value::STR(s) => s,
+        v => return Err(format!("/dbg/last: {:?}", v)),
+    };
+    let last_code = match get(&device, "/dbg/last_code").await? {
+        Value::U32(code) => code,
+        v => return Err(format!("/dbg/last_code: {:?}", v)),
+    };
+    let flags = match get(&device, "/dbg/flags").await? {
+        Value::U32(flags) => flags,
+        v => return Err(format!("/dbg/flags: {:?}", v)),
+    };
+    Ok(Snapshot { last, last_code, flags })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names(list: &[&str]) -> Vec<String> {
+        list.iter().map(|n| n.to_string()).collect()
+    }
+
+    fn snapshot(last_code: u32) -> Snapshot {
+        Snapshot { last: String::new(), last_code, flags: 0 }
+    }
+
+    #[test]
+    fn flags_named_and_unnamed() {
+        let bits = flags(0b101, &names(&["power", "", "sd"]));
+        assert_eq!(bits, vec![
+            (0, "power".to_string(), true),
+            (1, "bit 1".to_string(), false),
+            (2, "sd".to_string(), true),
+        ]);
+    }
+
+    #[test]
+    fn flags_up_to_highest_set_bit() {
+        let bits = flags(1 << 4, &names(&["power"]));
+        assert_eq!(bits.len(), 5);
+        assert_eq!(bits[0], (0, "power".to_string(), false));
+        assert_eq!(bits[4], (4, "bit 4".to_string(), true));
+
+        let top = flags(1 << 31, &[]);
+        assert_eq!(top.len(), 32);
+        assert!(top[31].2);
+    }
+
+    #[test]
+    fn flags_clear_shows_named_only() {
+        assert!(flags(0, &[]).is_empty());
+        let bits = flags(0, &names(&["power", "sd"]));
+        assert_eq!(bits.len(), 2);
+        assert!(bits.iter().all(|(_, _, set)| !set));
+    }
+
+    #[test]
+    fn update_keeps_code_changes_only() {
+        let mut panel = Panel::default();
+        panel.update(1., "A1", snapshot(0));
+        assert!(panel.history.is_empty());
+
+        panel.update(2., "A1", snapshot(5));
+        panel.update(3., "A1", snapshot(5));
+        assert_eq!(panel.history.len(), 1);
+        assert_eq!(panel.history[0].code, 5);
+        assert_eq!(panel.history[0].t, 2.);
+
+        panel.update(4., "A1", snapshot(0));
+        panel.update(5., "A1", snapshot(5));
+        panel.update(6., "A1", snapshot(7));
+        let codes = panel.history.iter().map(|e| e.code).collect::<Vec<_>>();
+        assert_eq!(codes, vec![5, 5, 7]);
+        assert_eq!(panel.snapshot.as_ref().map(|s| s.last_code), Some(7));
+    }
+
+    #[test]
+    fn update_text_carries_last() {
+        let mut panel = Panel::default();
+        panel.update(1., "A1", Snapshot { last: "sd init".into(), last_code: 3, flags: 0 });
+        assert!(panel.history[0].text.ends_with("(sd init)"));
+        assert_eq!(panel.history[0].serial, "A1");
+    }
+
+    #[test]
+    fn history_capped() {
+        let mut panel = Panel::default();
+        let total = HISTORY_LEN as u32 + 5;
+        for code in 1 ..= total {
+            panel.update(code as f64, "A1", snapshot(code));
+        }
+        assert_eq!(panel.history.len(), HISTORY_LEN);
+        assert_eq!(panel.history.first().map(|e| e.code), Some(6));
+        assert_eq!(panel.history.last().map(|e| e.code), Some(total));
+    }
+}
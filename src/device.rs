@@ -1,8 +1,9 @@
 
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
+use std::rc::Rc;
 use std::convert::TryInto;
 
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use seed::log;
 use wasm_bindgen::prelude::*;
 use wasm_bindgen::JsCast;
@@ -14,9 +15,11 @@ use ellocopo2::ParseMsg;
 use ellocopo2::ParserError;
 use ellocopo2::MAX_MSG_SZ;
 
-use holter_dfu::dfu::defs::*;
+pub mod registry;
+pub mod inspect;
+pub mod capture;
 
-use crate::js_debug;
+use registry::{Feature, Profile, Registry};
 
 #[wasm_bindgen]
 extern "C" {
@@ -38,19 +41,25 @@ extern "C" {
     #[wasm_bindgen(method)]
     fn js_send_dfu(this: &DeviceJs, bRequest: u8, data: &[u8], wValue: u32) -> js_sys::Promise;
     #[wasm_bindgen(method)]
-    fn js_recv_file(this: &DeviceJs, size: u32) -> js_sys::Promise;
+    fn js_recv_config(this: &DeviceJs, wLength: u16) -> js_sys::Promise;
     #[wasm_bindgen(method)]
-    fn js_recv_vis(this: &DeviceJs, size: usize) -> js_sys::Promise;
+    fn js_recv_file(this: &DeviceJs, ep: u8, size: u32) -> js_sys::Promise;
+    #[wasm_bindgen(method)]
+    fn js_recv_vis(this: &DeviceJs, ep: u8, size: usize) -> js_sys::Promise;
 
     #[wasm_bindgen(method)]
     fn js_descriptor(this: &DeviceJs) -> js_sys::Map;
 
     #[wasm_bindgen]
-    pub fn js_requestDevice() -> js_sys::Promise;
+    pub fn js_requestDevice(filters: &JsValue) -> js_sys::Promise;
+    #[wasm_bindgen]
+    pub fn js_getDevices() -> js_sys::Promise;
+    #[wasm_bindgen]
+    fn js_onUsbEvents(on_connect: &Closure<dyn Fn(DeviceJs)>, on_disconnect: &Closure<dyn Fn(DeviceJs)>);
 }
 
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum Error {
     NotConnected,
     NotSelected,
@@ -59,9 +68,17 @@ pub enum Error {
     RawJs(JsValue),
     DevTypeApi,
     EpStall,
+    UnknownDevice { vid: u16, pid: u16 },
+    BadCapture(&'static str),
+    ReplayEnd,
+    // Transfer went through but its content makes no sense
+    Protocol(String),
+    // No answer in time, the request may still be pending
+    Timeout,
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum Type {
     Holter,
     Loader,
@@ -69,47 +86,195 @@ pub enum Type {
 
 #[allow(non_snake_case)]
 #[derive(Default, Debug)]
-#[derive(Deserialize, Clone, Eq, PartialEq)]
+#[derive(Deserialize, Serialize, Clone, Eq, PartialEq)]
 pub struct Desc {
     productName: String,
     serialNumber: String,
     manufacturerName: String,
     pid: u16,
     vid: u16,
+    #[serde(default)]
+    interfaces: u8,
 }
 
 #[derive(Default)]
 pub struct Device {
     ty: Type,
+    profile: Rc<Profile>,
     desc: Desc,
-    d: RefCell<Option<DeviceJs>>,
+    // Cloned out for every transfer, dropping it never collides with one in flight
+    d: RefCell<Option<Rc<DeviceJs>>>,
+    // Serves a recorded session instead of USB
+    replay: Option<RefCell<capture::Replay>>,
+    seen: Cell<f64>,
+    pending: Cell<u32>,
+    lost: RefCell<Option<String>>,
+}
+
+/// Counts a transfer in flight while alive
+struct InFlight<'a>(&'a Cell<u32>);
+
+impl<'a> InFlight<'a> {
+    fn new(pending: &'a Cell<u32>) -> Self {
+        pending.set(pending.get() + 1);
+        Self(pending)
+    }
+}
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        self.0.set(self.0.get() - 1);
+    }
 }
 
 impl Device {
-    pub async fn request_device() -> Result<Device, Error> {
-        let dev = DeviceJs::request_device()
+    pub async fn request_device(registry: &Registry) -> Result<Device, Error> {
+        let dev = DeviceJs::request_device(&registry.filters())
             .await?;
         
+        Self::open(dev, registry).await
+    }
+
+    /// Opens the first permitted device accepted by `pred`, no picker needed
+    pub async fn find_permitted(
+        registry: &Registry,
+        pred: impl Fn(&Profile, &Desc) -> bool,
+    ) -> Result<Option<Device>, Error> {
+        let devs = DeviceJs::permitted().await?;
+        for dev in devs {
+            let desc = dev.descriptor().await?;
+            if registry.find(&desc).map_or(false, |p| pred(&p, &desc)) {
+                dev.connect().await?;
+                return Ok(Some(Self::open(dev, registry).await?));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Reports devices plugged in or out, handlers live as long as the page
+    pub fn watch(on_connect: impl Fn(Desc) + 'static, on_disconnect: impl Fn(Desc) + 'static) {
+        let on_connect = Closure::wrap(Box::new(move |dev: DeviceJs| {
+            if let Ok(desc) = dev.js_descriptor().into_serde() {
+                on_connect(desc);
+            }
+        }) as Box<dyn Fn(DeviceJs)>);
+        let on_disconnect = Closure::wrap(Box::new(move |dev: DeviceJs| {
+            if let Ok(desc) = dev.js_descriptor().into_serde() {
+                on_disconnect(desc);
+            }
+        }) as Box<dyn Fn(DeviceJs)>);
+        js_onUsbEvents(&on_connect, &on_disconnect);
+        on_connect.forget();
+        on_disconnect.forget();
+    }
+
+    async fn open(dev: DeviceJs, registry: &Registry) -> Result<Device, Error> {
         let desc = dev.descriptor()
             .await?;
 
+        let profile = registry
+            .find(&desc)
+            .ok_or(Error::UnknownDevice { vid: desc.vid, pid: desc.pid })?;
+        log::info!("Device profile: {}", profile.name);
+
         dev.reset().await?;
 
-        let ty = match desc.pid {
-            0xBABA => Type::Holter,
-            0xDEDA => Type::Loader,
-            _ => unreachable!("Wrong pid"),
-        };
-        
         Ok(Device {
-            ty,
+            ty: profile.kind,
+            profile,
+            desc,
+            d: RefCell::new(Some(Rc::new(dev))),
+            replay: None,
+            seen: Cell::new(js_sys::Date::now()),
+            pending: Cell::new(0),
+            lost: RefCell::new(None),
+        })
+    }
+
+    /// Device backed by a capture file, no hardware needed
+    pub fn replay(capture: &[u8], registry: &Registry) -> Result<Device, Error> {
+        let (mut desc, replay) = capture::Replay::parse(capture)?;
+        let profile = registry
+            .find(&desc)
+            .ok_or(Error::UnknownDevice { vid: desc.vid, pid: desc.pid })?;
+        // Keeps the real device apart in the device list
+        desc.serialNumber = format!("{}-replay", desc.serialNumber);
+
+        Ok(Device {
+            ty: profile.kind,
+            profile,
             desc,
-            d: RefCell::new(Some(dev)),
+            d: RefCell::new(None),
+            replay: Some(RefCell::new(replay)),
+            seen: Cell::new(js_sys::Date::now()),
+            pending: Cell::new(0),
+            lost: RefCell::new(None),
         })
     }
 
+    pub fn is_replay(&self) -> bool {
+        self.replay.is_some()
+    }
+
+    /// USB reset, a loader leaves for the application after manifestation
+    pub async fn usb_reset(&self) -> Result<(), Error> {
+        if self.replay.is_some() { return Err(Error::DevTypeApi); }
+        if !self.is_connected() { return Err(Error::NotConnected) }
+
+        let dev = self.handle()?;
+        let _busy = InFlight::new(&self.pending);
+        let r = dev.reset().await;
+        // Device re-enumerates either way
+        self.lose("USB reset".into());
+
+        Ok(r?)
+    }
+
+    /// Handle for one transfer, the cell is not borrowed across `.await`
+    fn handle(&self) -> Result<Rc<DeviceJs>, Error> {
+        self.d.borrow().clone().ok_or(Error::NotConnected)
+    }
+
+    /// Drops a device that was unplugged
+    pub fn detach(&self) {
+        self.lose("unplugged".into());
+    }
+
+    /// Drops the handle and remembers why
+    pub fn lose(&self, reason: String) {
+        if self.d.borrow_mut().take().is_some() {
+            log::warn!("Device {} lost: {}", self.desc.serial(), reason);
+            *self.lost.borrow_mut() = Some(reason);
+        }
+    }
+
+    /// Why the handle was dropped, none while connected
+    pub fn lost_reason(&self) -> Option<String> {
+        if self.is_connected() { None } else { self.lost.borrow().clone() }
+    }
+
+    /// Time of the last successful transfer, ms since the epoch
+    pub fn last_seen(&self) -> Option<f64> {
+        Some(self.seen.get()).filter(|&t| t > 0.)
+    }
+
+    /// A transfer is in flight, a heartbeat would queue behind it
+    pub fn is_busy(&self) -> bool {
+        self.pending.get() > 0
+    }
+
+    /// Notes a finished transfer, the handle is dropped only when the device is gone
+    fn settle<T>(&self, r: Result<T, Error>) -> Result<T, Error> {
+        match &r {
+            Ok(_) => self.seen.set(js_sys::Date::now()),
+            Err(e) if e.is_gone() => self.lose(format!("{:?}", e)),
+            Err(e) => log::warn!("Transfer failed, link kept: {:?}", e),
+        }
+        r
+    }
+
     pub fn descriptor(&self) -> Option<&Desc> {
-        if self.d.borrow().is_some() {
+        if self.is_connected() {
             Some(&self.desc)
         } else {
             None
@@ -117,15 +282,23 @@ impl Device {
     }
 
     pub fn _dev_type(&self) -> Option<Type> {
-        if self.d.borrow().is_some() {
+        if self.is_connected() {
             Some(self.ty)
         } else {
             None
         }
     }
 
+    pub fn profile(&self) -> &Profile {
+        &self.profile
+    }
+
+    pub fn has(&self, feature: Feature) -> bool {
+        self.is_connected() && self.profile.has(feature)
+    }
+
     pub fn is_connected(&self) -> bool {
-        self.d.borrow().is_some()
+        self.d.borrow().is_some() || self.replay.is_some()
     }
 
     pub fn is_dfu_mode(&self) -> bool {
@@ -134,123 +307,185 @@ impl Device {
     }
 
     pub fn is_reconnecting(&self, dev: &Self) -> bool {
-        if self.desc == dev.desc {
-            true
-        } else {
-            false
-        }
+        self.desc.same_unit(&dev.desc)
     }
 
     pub async fn send_recv_cmd(&self, msg: DevMsg) -> Result<DevMsg,Error> {
         if let Type::Loader = self.ty { return Err(Error::DevTypeApi); }
         if !self.is_connected() { return Err(Error::NotConnected) }
         
-        let r = {
-            let dev = self.d.borrow();
-            DeviceJs::send_recv_cmd(dev.as_ref().unwrap(), msg)
-                .await
+        let mut ex = inspect::Exchange::new(self.desc.serial(), &msg);
+        if let Some(replay) = &self.replay {
+            let r = replay.borrow_mut().cmd(&msg);
+            match &r {
+                Ok((ans, out, raw_in)) => {
+                    ex.raw_out = out.clone();
+                    ex.raw_in = raw_in.clone();
+                    ex.answer = Some(ans.clone());
+                }
+                Err(e) => ex.error = Some(format!("{:?}", e)),
+            }
+            inspect::record(ex);
+            return r.map(|(ans, _, _)| ans);
+        }
+
+        let r = match self.handle() {
+            Ok(dev) => {
+                let _busy = InFlight::new(&self.pending);
+                DeviceJs::send_recv_cmd(&dev, msg, &mut ex).await
+            }
+            Err(e) => Err(e),
         };
-        
-        if r.is_err() {
-            let mut dev = self.d.borrow_mut();
-            *dev = None;
+
+        match &r {
+            Ok(ans) => ex.answer = Some(ans.clone()),
+            Err(e) => ex.error = Some(format!("{:?}", e)),
         }
+        capture::cmd(&self.desc, &ex.raw_out, match &ex.error {
+            None => Ok(&ex.raw_in),
+            Some(e) => Err(e.clone()),
+        });
+        inspect::record(ex);
+        
+        self.settle(r)
+    }
+
+    /// Request kept out of the inspector and the session capture, for link checks
+    pub async fn probe(&self, msg: DevMsg) -> Result<DevMsg, Error> {
+        if let Type::Loader = self.ty { return Err(Error::DevTypeApi); }
+        // A replay only has answers to what was recorded
+        if self.replay.is_some() { return Err(Error::DevTypeApi); }
+        if !self.is_connected() { return Err(Error::NotConnected) }
 
-       Ok(r?)
+        let mut ex = inspect::Exchange::new(self.desc.serial(), &msg);
+        let r = match self.handle() {
+            Ok(dev) => {
+                let _busy = InFlight::new(&self.pending);
+                DeviceJs::send_recv_cmd(&dev, msg, &mut ex).await
+            }
+            Err(e) => Err(e),
+        };
+
+        self.settle(r)
     }
 
-    pub async fn send_recv_dfu(&self, data: Vec<u8>) -> Result<(), Error> {
-        if let Type::Holter = self.ty { return Err(Error::DevTypeApi); }
+    /// Configuration descriptor followed by interface and class specific ones
+    pub async fn config_descriptor(&self) -> Result<Vec<u8>, Error> {
+        if self.replay.is_some() { return Err(Error::DevTypeApi); }
         if !self.is_connected() { return Err(Error::NotConnected) }
-        
+
+        let dev = self.handle()?;
+        let _busy = InFlight::new(&self.pending);
         let r = {
-            let dev = self.d.borrow();
-            DeviceJs::send_recv_dfu(dev.as_ref().unwrap(), data)
-                .await
+            // wTotalLength comes first, then the whole set
+            match dev.config_descriptor(9).await {
+                Ok(head) if head.len() >= 4 => {
+                    let total = u16::from_le_bytes([head[2], head[3]]);
+                    dev.config_descriptor(total).await
+                }
+                Ok(head) => Ok(head),
+                Err(e) => Err(e),
+            }
         };
-        
-        if r.is_err() {
-            let mut dev = self.d.borrow_mut();
-            *dev = None;
-        }
 
-       Ok(r?)
+        self.settle(r)
     }
 
-    pub async fn dfu_upload(&self) -> Result<Vec<u8>, Error> {
+    /// Class request to the DFU interface, device to host
+    pub async fn dfu_control_in(&self, request: u8, len: u16, value: u32) -> Result<Vec<u8>, Error> {
+        if self.replay.is_some() { return Err(Error::DevTypeApi); }
         if let Type::Holter = self.ty { return Err(Error::DevTypeApi); }
         if !self.is_connected() { return Err(Error::NotConnected) }
         
-        let r = {
-            let dev = self.d.borrow();
-            DeviceJs::dfu_upload(dev.as_ref().unwrap())
-                .await
-        };
+        let dev = self.handle()?;
+        let _busy = InFlight::new(&self.pending);
+        let r = DeviceJs::dfu_control_in(&dev, request, len, value).await;
         
-        if r.is_err() {
-            let mut dev = self.d.borrow_mut();
-            *dev = None;
-        }
+        self.settle(r)
+    }
 
-       Ok(r?)
+    /// Class request to the DFU interface, host to device
+    pub async fn dfu_control_out(&self, request: u8, data: &[u8], value: u32) -> Result<(), Error> {
+        if self.replay.is_some() { return Err(Error::DevTypeApi); }
+        if let Type::Holter = self.ty { return Err(Error::DevTypeApi); }
+        if !self.is_connected() { return Err(Error::NotConnected) }
+        
+        let dev = self.handle()?;
+        let _busy = InFlight::new(&self.pending);
+        let r = DeviceJs::dfu_control_out(&dev, request, data, value).await;
+        
+        self.settle(r)
     }
     
     pub async fn recv_file_block(&self, tran_size: u32) -> Result<Vec<u8>,Error> {
         if let Type::Loader = self.ty { return Err(Error::DevTypeApi); }
         if !self.is_connected() { return Err(Error::NotConnected)}
+        if let Some(replay) = &self.replay {
+            return replay.borrow_mut().file();
+        }
 
-        let r = { 
-            let dev = self.d.borrow();
-            DeviceJs::recv_file(dev.as_ref().unwrap(), tran_size).await
-        };
+        let dev = self.handle()?;
+        let _busy = InFlight::new(&self.pending);
+        let r = DeviceJs::recv_file(&dev, self.profile.endpoints.file, tran_size).await;
 
-        if r.is_err() {
-            let mut dev = self.d.borrow_mut();
-            *dev = None;
+        if let Ok(buf) = &r {
+            capture::file(&self.desc, buf);
         }
-
-        Ok(r?)
+        self.settle(r)
     }
 
     pub async fn recv_vis(&self, buf: &mut [u8]) -> Result<usize,Error> {
         if let Type::Loader = self.ty { return Err(Error::DevTypeApi); }
         if !self.is_connected() { return Err(Error::NotConnected)}
-
-        let r = { 
-            let dev = self.d.borrow();
-            DeviceJs::recv_vis(dev.as_ref().unwrap(), buf).await
-        };
-
-        if r.is_err() {
-            let mut dev = self.d.borrow_mut();
-            *dev = None;
+        if let Some(replay) = &self.replay {
+            let (data, wait) = replay.borrow_mut().vis()?;
+            capture::pace(wait).await;
+            let n = data.len().min(buf.len());
+            buf[.. n].copy_from_slice(&data[.. n]);
+            return Ok(n);
         }
 
-        Ok(r?)
+        let dev = self.handle()?;
+        let _busy = InFlight::new(&self.pending);
+        let r = DeviceJs::recv_vis(&dev, self.profile.endpoints.vis, buf).await;
+        if let Ok(n) = &r {
+            capture::vis(&self.desc, &buf[.. *n]);
+        }
+        self.settle(r)
     }
 }
 
 impl DeviceJs {
 
-    async fn request_device() -> Result<DeviceJs,JsValue> {
-        let result = wasm_bindgen_futures::JsFuture::from(js_requestDevice()).await;
+    async fn request_device(filters: &JsValue) -> Result<DeviceJs,JsValue> {
+        let result = wasm_bindgen_futures::JsFuture::from(js_requestDevice(filters)).await;
         let val = result?;
         log!(&val);
         let dev: DeviceJs = JsCast::dyn_into(val)?;
-        // connect
-        let result = wasm_bindgen_futures::JsFuture::from(dev.js_connect()).await;
-        let val = result?;
-        log!("connect ", val);
-
-        //let v = js_sys::Uint8Array::new(&val).to_vec();
+        dev.connect().await?;
         
         Ok(dev)
     }
 
-    async fn descriptor(&self) -> Result<Desc,JsValue> {
+    async fn permitted() -> Result<Vec<DeviceJs>,JsValue> {
+        let result = wasm_bindgen_futures::JsFuture::from(js_getDevices()).await;
+        let devs: js_sys::Array = JsCast::dyn_into(result?)?;
+        devs.iter()
+            .map(|d| d.dyn_into::<DeviceJs>())
+            .collect()
+    }
+
+    async fn connect(&self) -> Result<(),JsValue> {
+        let result = wasm_bindgen_futures::JsFuture::from(self.js_connect()).await;
+        let val = result?;
+        log!("connect ", val);
+        Ok(())
+    }
+
+    async fn descriptor(&self) -> Result<Desc, Error> {
         let desc: Desc = self.js_descriptor()
             .into_serde()
-            .unwrap();
+            .map_err(|e| Error::Protocol(format!("device descriptor: {}", e)))?;
 
         log::info!("dev desc: {:#?}", &desc);
         Ok(desc)
@@ -269,105 +504,61 @@ impl DeviceJs {
         Ok(())
     }
 
-    async fn send_recv_dfu(&self, data: Vec<u8>) -> Result<(), Error> {
-        
-        log::info!("DFU");
+    async fn config_descriptor(&self, len: u16) -> Result<Vec<u8>, Error> {
+        let future_in = wasm_bindgen_futures::JsFuture::from(self.js_recv_config(len));
+        let trans_result = future_in.await?;
+        let buf = transfer_data(&trans_result)?;
+        log::debug!("Config descriptor => {:x?}", buf);
+        Ok(buf)
+    }
 
-        // Allocating recv transaction
-        //let future_in = wasm_bindgen_futures::JsFuture::from(self.js_recv_cmd());
-        
-        // Send
-        let future_in = wasm_bindgen_futures::JsFuture::from(self.js_recv_dfu(dfu_request::DFU_GETSTATE, 1, 0));
-        let msg_ans = future_in.await?;
-        let data_view = js_sys::Reflect::get(&msg_ans, &JsValue::from_str("data")).unwrap();
-        let array_buf = js_sys::Reflect::get(&data_view, &JsValue::from_str("buffer")).unwrap();
-        let cmd_buf = js_sys::Uint8Array::new(&array_buf).to_vec();
-        log::info!("IN => {:x?}",cmd_buf);
-        log::info!("data len{:?}", &data.len());
-
-        let mut j: usize = 0;
-        let mut i = 0;
-        while j < data.len() {
-            if data.len() > j + 64 {
-                let future_in = wasm_bindgen_futures::JsFuture::from(self.js_send_dfu(dfu_request::DFU_DNLOAD, &data[j..j+64], i));
-                let _msg_ans = future_in.await?;
-            }
-            else {
-                let future_in = wasm_bindgen_futures::JsFuture::from(self.js_send_dfu(dfu_request::DFU_DNLOAD, &data[j..data.len()], i));
-                let _msg_ans = future_in.await?;
-            }
+    async fn dfu_control_in(&self, request: u8, len: u16, value: u32) -> Result<Vec<u8>, Error> {
+        let future_in = wasm_bindgen_futures::JsFuture::from(self.js_recv_dfu(request, len, value));
+        let trans_result = future_in.await?;
+        let status = js_sys::Reflect::get(&trans_result, &JsValue::from_str("status"))?;
 
-            let future_in = wasm_bindgen_futures::JsFuture::from(self.js_recv_dfu(dfu_request::DFU_GETSTATUS, 6, 0));
-            let _msg_ans = future_in.await?;
-            j += 64;
-            i += 1;
-            log::info!("packet size: 64");
+        match status.as_string().unwrap_or_default().as_str() {
+            "stall" => Err(Error::EpStall),
+            "ok" => {
+                let buf = transfer_data(&trans_result)?;
+                log::debug!("DFU IN {} => {:x?}", request, buf);
+                Ok(buf)
+            }
+            s => Err(Error::Protocol(format!("DFU request {} status {:?}", request, s))),
         }
-        let buf = [0u8; 0];
-        let future_in = wasm_bindgen_futures::JsFuture::from(self.js_send_dfu(dfu_request::DFU_DNLOAD, &buf, i));
-        let _msg_ans = future_in.await?;
-
-        let future_in = wasm_bindgen_futures::JsFuture::from(self.js_recv_dfu(dfu_request::DFU_GETSTATUS, 6, 0));
-        let msg_ans = future_in.await?;
-        let data_view = js_sys::Reflect::get(&msg_ans, &JsValue::from_str("data")).unwrap();
-        let array_buf = js_sys::Reflect::get(&data_view, &JsValue::from_str("buffer")).unwrap();
-        let cmd_buf = js_sys::Uint8Array::new(&array_buf).to_vec();
-        log::info!("output {:?}",cmd_buf);
-
-        Ok(())
     }
 
-    async fn dfu_upload(&self) -> Result<Vec<u8>, Error> {
-        log::info!("DFU");
+    async fn dfu_control_out(&self, request: u8, data: &[u8], value: u32) -> Result<(), Error> {
+        let future_out = wasm_bindgen_futures::JsFuture::from(self.js_send_dfu(request, data, value));
+        let trans_result = future_out.await?;
+        let status = js_sys::Reflect::get(&trans_result, &JsValue::from_str("status"))?;
 
-        let future_in = wasm_bindgen_futures::JsFuture::from(self.js_recv_dfu(dfu_request::DFU_GETSTATUS, 6, 0));
-        let msg_ans = future_in.await?;
-        let data_view = js_sys::Reflect::get(&msg_ans, &JsValue::from_str("data")).unwrap();
-        let array_buf = js_sys::Reflect::get(&data_view, &JsValue::from_str("buffer")).unwrap();
-        let cmd_buf = js_sys::Uint8Array::new(&array_buf).to_vec();
-        log::info!("IN => {:x?}",cmd_buf);
-
-        let mut state = 0;
-        let mut data: Vec<u8> = Vec::new();
-
-        while state != 2 {
-            let future_in = wasm_bindgen_futures::JsFuture::from(self.js_recv_dfu(dfu_request::DFU_UPLOAD, 64, 0));
-            let msg_ans = future_in.await?;
-            let data_view = js_sys::Reflect::get(&msg_ans, &JsValue::from_str("data")).unwrap();
-            let array_buf = js_sys::Reflect::get(&data_view, &JsValue::from_str("buffer")).unwrap();
-            let mut cmd_buf = js_sys::Uint8Array::new(&array_buf).to_vec();
-            log::info!("cmd buf len: {}", &cmd_buf.len());
-            data.append(&mut cmd_buf);
-
-            let future_in = wasm_bindgen_futures::JsFuture::from(self.js_recv_dfu(dfu_request::DFU_GETSTATE, 1, 0));
-            let msg_ans = future_in.await?;
-            let data_view = js_sys::Reflect::get(&msg_ans, &JsValue::from_str("data")).unwrap();
-            let array_buf = js_sys::Reflect::get(&data_view, &JsValue::from_str("buffer")).unwrap();
-            let cmd_buf = js_sys::Uint8Array::new(&array_buf).to_vec();
-            state = cmd_buf[0];
+        match status.as_string().unwrap_or_default().as_str() {
+            "stall" => Err(Error::EpStall),
+            "ok" => Ok(()),
+            s => Err(Error::Protocol(format!("DFU request {} status {:?}", request, s))),
         }
-        log::info!("dta len: {}", &data.len());
-
-        Ok(data)
     }
 
-    async fn send_recv_cmd(&self, msg: DevMsg) -> Result<DevMsg, JsValue> {
+    async fn send_recv_cmd(&self, msg: DevMsg, ex: &mut inspect::Exchange) -> Result<DevMsg, Error> {
         
-        log::info!("OUT => {:#?}", &msg);
+        log::debug!("OUT => {:?}", &msg);
         let DevMsg(code, ref path, ref value) = msg;
         
         let mut buf_out = [0u8;MAX_MSG_SZ];
         let buf_out = {
+            let code = code.try_into()
+                .map_err(|_| Error::Protocol(format!("{}: answer code can't be sent", path)))?;
             let mut req = RequestBuilder::new(&mut buf_out);
             let sz = req 
                 .path(&path)
-                .code(code.try_into().unwrap())
+                .code(code)
                 .payload(value.into())
                 .build()
-                .unwrap();
+                .map_err(|e| Error::Protocol(format!("{}: request not built: {:?}", path, e)))?;
             &buf_out[..sz]
         };
-        log::info!("OUT => {:x?}", &buf_out);
+        ex.raw_out = buf_out.to_vec();
 
         // Allocating recv transaction
         let future_in = wasm_bindgen_futures::JsFuture::from(self.js_recv_cmd());
@@ -375,99 +566,68 @@ impl DeviceJs {
         // Send
         let _send_ok = wasm_bindgen_futures::JsFuture::from(self.js_send_cmd(buf_out))
             .await?;
-        js_debug(&_send_ok);
         
         // Awaiting recv future
         let msg_ans = future_in.await?;
-        js_debug(&msg_ans);
-        let data_view = js_sys::Reflect::get(&msg_ans, &JsValue::from_str("data")).unwrap();
-        js_debug(&data_view);
-        let array_buf = js_sys::Reflect::get(&data_view, &JsValue::from_str("buffer")).unwrap();
-        js_debug(&array_buf);
-
-        let mut cmd_buf = js_sys::Uint8Array::new(&array_buf).to_vec();
-        log::info!("IN => {:x?}",cmd_buf);
+        let mut cmd_buf = transfer_data(&msg_ans)?;
         
         let mut parser = ParseMsg::new();
-        let mut parsed_msg: Option<DevMsg> = None;
-        while { let len = cmd_buf.len(); len < MAX_MSG_SZ } {
-
-            let res = parser.try_parse(&cmd_buf);
-            match res {
-                Ok(msg) => {
-                    parsed_msg = Some(msg.into());
-                    break;
-                }
+        let parsed_msg = loop {
+            if cmd_buf.len() >= MAX_MSG_SZ {
+                ex.raw_in = cmd_buf;
+                return Err(Error::Protocol(format!("{}: answer exceeds {} bytes", path, MAX_MSG_SZ)));
+            }
+
+            match parser.try_parse(&cmd_buf) {
+                Ok(msg) => break DevMsg::from(msg),
                 Err(ParserError::NeedMoreData) => {
                     // Allocate recv transaction
                     let future_in = wasm_bindgen_futures::JsFuture::from(self.js_recv_cmd());
                     // Awaiting recv callback
-                    let result_in = future_in.await;
-                    let val_in = result_in.unwrap();
-                    js_debug(&val_in);
-                    let data_view = js_sys::Reflect::get(&val_in, &JsValue::from_str("data")).unwrap();
-                    js_debug(&data_view);
-                    let array_buf = js_sys::Reflect::get(&data_view, &JsValue::from_str("buffer")).unwrap();
-                    js_debug(&array_buf);
-                    let tmp_buf = js_sys::Uint8Array::new(&array_buf).to_vec();
-                    log::info!("{:?}", &tmp_buf);
-
-                    cmd_buf.extend(&tmp_buf);
+                    let val_in = future_in.await?;
+                    cmd_buf.extend(&transfer_data(&val_in)?);
+                }
+                Err(e) => {
+                    ex.raw_in = cmd_buf;
+                    return Err(Error::Protocol(format!("{}: bad answer: {:?}", path, e)));
                 }
-                Err(e) => panic!("{:?}", e),
             }
-        }
+        };
+        ex.raw_in = cmd_buf;
         
-        log::info!("IN => {:#?}", parsed_msg.as_ref().unwrap());
+        log::debug!("IN => {:?}", &parsed_msg);
 
-        Ok(
-            parsed_msg.unwrap()
-        )
+        Ok(parsed_msg)
     }
 
-    async fn recv_file(&self, tran_size: u32) -> Result<Vec<u8>, Error> {
+    async fn recv_file(&self, ep: u8, tran_size: u32) -> Result<Vec<u8>, Error> {
         
         // Allocating recv transaction
-        let future_in = wasm_bindgen_futures::JsFuture::from(self.js_recv_file(tran_size));
+        let future_in = wasm_bindgen_futures::JsFuture::from(self.js_recv_file(ep, tran_size));
 
         // Awaiting recv future
         let trans_result = future_in.await?;
         // Check transfer status
-        let status = js_sys::Reflect::get(&trans_result, &JsValue::from_str("status")).unwrap();
+        let status = js_sys::Reflect::get(&trans_result, &JsValue::from_str("status"))?;
 
-        match status.as_string().unwrap().as_str() {
+        match status.as_string().unwrap_or_default().as_str() {
             "stall" => Err(Error::EpStall),
-            "ok" => {
-                //js_debug(&msg_ans);
-                let data_view = js_sys::Reflect::get(&trans_result, &JsValue::from_str("data")).unwrap();
-                //js_debug(&data_view);
-                let array_buf = js_sys::Reflect::get(&data_view, &JsValue::from_str("buffer")).unwrap();
-                //js_debug(&array_buf);
-
-                let buf = js_sys::Uint8Array::new(&array_buf).to_vec();
-                //log::info!("{:x?}",buf);
-    
-                 Ok(buf)
-            }
-            s => panic!("File transaction unknow status: {}", s),
+            "ok" => transfer_data(&trans_result),
+            s => Err(Error::Protocol(format!("file transfer status {:?}", s))),
         }
     }
 
-    async fn recv_vis(&self, buf: &mut [u8]) -> Result<usize, JsValue> {
+    async fn recv_vis(&self, ep: u8, buf: &mut [u8]) -> Result<usize, Error> {
         
         // Allocating recv transaction
-        let future_in = wasm_bindgen_futures::JsFuture::from(self.js_recv_vis(buf.len()));
+        let future_in = wasm_bindgen_futures::JsFuture::from(self.js_recv_vis(ep, buf.len()));
 
         // Awaiting recv future
         let msg_ans = future_in.await?;
-        //js_debug(&msg_ans);
-        let data_view = js_sys::Reflect::get(&msg_ans, &JsValue::from_str("data")).unwrap();
-        //js_debug(&data_view);
-        let array_buf = js_sys::Reflect::get(&data_view, &JsValue::from_str("buffer")).unwrap();
-        //js_debug(&array_buf);
-
-        let in_buf = js_sys::Uint8Array::new(&array_buf).to_vec();
-        //log::info!("{:x?}",in_buf);
+        let in_buf = transfer_data(&msg_ans)?;
+        if in_buf.len() > buf.len() {
+            return Err(Error::Protocol(format!("vis block of {} bytes", in_buf.len())));
+        }
         (&mut buf[.. in_buf.len()]).copy_from_slice(&in_buf);
     
         Ok(in_buf.len())
@@ -476,6 +636,30 @@ impl DeviceJs {
 
 }
 
+/// Bytes of a USBInTransferResult
+fn transfer_data(result: &JsValue) -> Result<Vec<u8>, Error> {
+    let data_view = js_sys::Reflect::get(result, &JsValue::from_str("data"))?;
+    if data_view.is_undefined() || data_view.is_null() {
+        return Err(Error::Protocol("transfer without data".into()));
+    }
+    let array_buf = js_sys::Reflect::get(&data_view, &JsValue::from_str("buffer"))?;
+    Ok(js_sys::Uint8Array::new(&array_buf).to_vec())
+}
+
+impl Error {
+    /// The device went away, as opposed to a single failed transfer
+    pub fn is_gone(&self) -> bool {
+        match self {
+            Error::NotConnected | Error::NotSelected => true,
+            Error::DomExp(e) => match e.name().as_str() {
+                "NetworkError" | "InvalidStateError" => true,
+                _ => false,
+            },
+            _ => false,
+        }
+    }
+}
+
 impl From<JsValue> for Error {
     fn from(e: JsValue) -> Self {
         if let Ok(domexp) = JsCast::dyn_into::<DomException>(e.clone()){
@@ -496,6 +680,17 @@ impl Default for Type {
     }
 }
 
+impl Desc {
+    pub fn serial(&self) -> &str {
+        &self.serialNumber
+    }
+
+    /// Same physical device, `interfaces` is unknown while it is closed or gone
+    pub fn same_unit(&self, other: &Desc) -> bool {
+        self.serialNumber == other.serialNumber && self.vid == other.vid && self.pid == other.pid
+    }
+}
+
 impl std::fmt::Display for Desc {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f, "{:?}", self)
@@ -0,0 +1,295 @@
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::convert::TryInto;
+
+use gloo_timers::future::TimeoutFuture;
+
+use ellocopo2::owned::Msg as DevMsg;
+use ellocopo2::ParseMsg;
+
+use super::{Desc, Error};
+
+const MAGIC: &[u8] = b"HCAP1\0";
+// Replayed vis gaps longer than this are cut
+const MAX_GAP_MS: u32 = 1000;
+
+/// Record types of a capture file
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[repr(u8)]
+enum Kind {
+    // JSON device descriptor, starts every device's part
+    Desc = 1,
+    CmdOut = 2,
+    CmdIn = 3,
+    // Transfer failed, payload is the error text
+    CmdErr = 4,
+    File = 5,
+    Vis = 6,
+}
+
+impl Kind {
+    fn from_u8(v: u8) -> Option<Self> {
+        Some(match v {
+            1 => Kind::Desc,
+            2 => Kind::CmdOut,
+            3 => Kind::CmdIn,
+            4 => Kind::CmdErr,
+            5 => Kind::File,
+            6 => Kind::Vis,
+            _ => return None,
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Record {
+    kind: Kind,
+    // ms from the capture start
+    t: u32,
+    data: Vec<u8>,
+}
+
+#[derive(Default)]
+struct Recorder {
+    start: f64,
+    buf: Vec<u8>,
+    // Serial the last descriptor record was written for
+    serial: Option<String>,
+}
+
+thread_local! {
+    static RECORDER: RefCell<Option<Recorder>> = RefCell::new(None);
+}
+
+pub fn start() {
+    RECORDER.with(|r| {
+        *r.borrow_mut() = Some(Recorder {
+            start: js_sys::Date::now(),
+            buf: MAGIC.to_vec(),
+            serial: None,
+        });
+    });
+    log::info!("Session capture started");
+}
+
+/// Ends the capture and returns the file contents
+pub fn stop() -> Option<Vec<u8>> {
+    let rec = RECORDER.with(|r| r.borrow_mut().take())?;
+    log::info!("Session capture finished, {} bytes", rec.buf.len());
+    Some(rec.buf)
+}
+
+pub fn is_active() -> bool {
+    RECORDER.with(|r| r.borrow().is_some())
+}
+
+fn push(desc: &Desc, kind: Kind, data: &[u8]) {
+    RECORDER.with(|r| {
+        let mut r = r.borrow_mut();
+        let rec = match r.as_mut() {
+            Some(rec) => rec,
+            None => return,
+        };
+        let t = (js_sys::Date::now() - rec.start) as u32;
+        if rec.serial.as_deref() != Some(desc.serial()) {
+            rec.serial = Some(desc.serial().to_string());
+            let json = serde_json::to_vec(desc).unwrap();
+            write_record(&mut rec.buf, Kind::Desc, t, &json);
+        }
+        write_record(&mut rec.buf, kind, t, data);
+    });
+}
+
+fn write_record(buf: &mut Vec<u8>, kind: Kind, t: u32, data: &[u8]) {
+    buf.push(kind as u8);
+    buf.extend_from_slice(&t.to_le_bytes());
+    buf.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    buf.extend_from_slice(data);
+}
+
+pub fn cmd(desc: &Desc, raw_out: &[u8], answer: Result<&[u8], String>) {
+    push(desc, Kind::CmdOut, raw_out);
+    match answer {
+        Ok(raw_in) => push(desc, Kind::CmdIn, raw_in),
+        Err(e) => push(desc, Kind::CmdErr, e.as_bytes()),
+    }
+}
+
+pub fn file(desc: &Desc, data: &[u8]) {
+    push(desc, Kind::File, data);
+}
+
+pub fn vis(desc: &Desc, data: &[u8]) {
+    push(desc, Kind::Vis, data);
+}
+
+/// Recorded session of one device served back in order
+pub struct Replay {
+    cmds: VecDeque<Record>,
+    files: VecDeque<Record>,
+    vis: VecDeque<Record>,
+    last_vis: Option<u32>,
+}
+
+impl Replay {
+    /// Descriptor and traffic of the first device in a capture
+    ///
+    /// Devices take turns when the user switches between them, every part
+    /// after a descriptor record belongs to that descriptor's device.
+    pub fn parse(buf: &[u8]) -> Result<(Desc, Self), Error> {
+        if !buf.starts_with(MAGIC) {
+            return Err(Error::BadCapture("not a capture file"));
+        }
+        let mut at = MAGIC.len();
+        let mut desc: Option<Desc> = None;
+        // Records belong to the first device
+        let mut ours = false;
+        let mut replay = Replay {
+            cmds: VecDeque::new(),
+            files: VecDeque::new(),
+            vis: VecDeque::new(),
+            last_vis: None,
+        };
+
+        while at < buf.len() {
+            let head = buf.get(at .. at + 9).ok_or(Error::BadCapture("truncated record"))?;
+            let kind = Kind::from_u8(head[0]).ok_or(Error::BadCapture("unknown record"))?;
+            let t = u32::from_le_bytes(head[1 .. 5].try_into().unwrap());
+            let len = u32::from_le_bytes(head[5 .. 9].try_into().unwrap()) as usize;
+            let end = (at + 9).checked_add(len).ok_or(Error::BadCapture("truncated record"))?;
+            let data = buf.get(at + 9 .. end).ok_or(Error::BadCapture("truncated record"))?;
+            at = end;
+
+            let record = Record { kind, t, data: data.to_vec() };
+            match kind {
+                Kind::Desc => {
+                    let part: Desc = serde_json::from_slice(data).map_err(|_| Error::BadCapture("bad descriptor"))?;
+                    ours = desc.as_ref().map_or(true, |d| d.serial() == part.serial());
+                    if desc.is_none() {
+                        desc = Some(part);
+                    }
+                }
+                _ if !ours => (),
+                Kind::CmdOut | Kind::CmdIn | Kind::CmdErr => replay.cmds.push_back(record),
+                Kind::File => replay.files.push_back(record),
+                Kind::Vis => replay.vis.push_back(record),
+            }
+        }
+
+        let desc = desc.ok_or(Error::BadCapture("no device in capture"))?;
+        log::info!(
+            "Replay {}: {} command records, {} file blocks, {} vis blocks",
+            desc, replay.cmds.len(), replay.files.len(), replay.vis.len(),
+        );
+        Ok((desc, replay))
+    }
+
+    /// Answer recorded for the next request, requests are expected in the same order
+    pub fn cmd(&mut self, msg: &DevMsg) -> Result<(DevMsg, Vec<u8>, Vec<u8>), Error> {
+        let out = self.next_cmd(Kind::CmdOut)?;
+        let answer = self.cmds.pop_front().ok_or(Error::ReplayEnd)?;
+
+        if let Ok(recorded) = ParseMsg::new().try_parse(&out.data) {
+            let recorded: DevMsg = recorded.into();
+            if recorded.1 != msg.1 {
+                log::warn!("Replay: recorded {} answers request for {}", recorded.1, msg.1);
+            }
+        }
+        if answer.kind != Kind::CmdIn {
+            return Err(Error::BadCapture("recorded transfer failed"));
+        }
+        let ans: DevMsg = ParseMsg::new()
+            .try_parse(&answer.data)
+            .map_err(|_| Error::BadCapture("bad recorded answer"))?
+            .into();
+        Ok((ans, out.data, answer.data))
+    }
+
+    fn next_cmd(&mut self, kind: Kind) -> Result<Record, Error> {
+        match self.cmds.pop_front() {
+            Some(r) if r.kind == kind => Ok(r),
+            Some(_) => Err(Error::BadCapture("command records out of order")),
+            None => Err(Error::ReplayEnd),
+        }
+    }
+
+    pub fn file(&mut self) -> Result<Vec<u8>, Error> {
+        self.files.pop_front().map(|r| r.data).ok_or(Error::ReplayEnd)
+    }
+
+    /// Next vis block and how long to wait to keep the recorded pace
+    pub fn vis(&mut self) -> Result<(Vec<u8>, u32), Error> {
+        let r = self.vis.pop_front().ok_or(Error::ReplayEnd)?;
+        let wait = self.last_vis.map_or(0, |last| r.t.saturating_sub(last).min(MAX_GAP_MS));
+        self.last_vis = Some(r.t);
+        Ok((r.data, wait))
+    }
+}
+
+pub async fn pace(ms: u32) {
+    if ms > 0 {
+        TimeoutFuture::new(ms).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn desc(serial: &str) -> Desc {
+        Desc { serialNumber: serial.into(), ..Desc::default() }
+    }
+
+    fn capture(records: &[(Option<&str>, Kind, &[u8])]) -> Vec<u8> {
+        let mut buf = MAGIC.to_vec();
+        for (t, &(dev, kind, data)) in records.iter().enumerate() {
+            match dev {
+                Some(serial) => {
+                    let json = serde_json::to_vec(&desc(serial)).unwrap();
+                    write_record(&mut buf, Kind::Desc, t as u32 * 10, &json);
+                }
+                None => write_record(&mut buf, kind, t as u32 * 10, data),
+            }
+        }
+        buf
+    }
+
+    #[test]
+    fn keeps_first_device_across_switches() {
+        let buf = capture(&[
+            (Some("A"), Kind::Desc, &[]),
+            (None, Kind::File, b"a1"),
+            (None, Kind::Vis, b"v1"),
+            (Some("B"), Kind::Desc, &[]),
+            (None, Kind::File, b"b1"),
+            (None, Kind::CmdOut, b"b"),
+            (Some("A"), Kind::Desc, &[]),
+            (None, Kind::File, b"a2"),
+            (None, Kind::Vis, b"v2"),
+        ]);
+        let (d, mut replay) = Replay::parse(&buf).unwrap();
+        assert_eq!(d.serial(), "A");
+        assert!(replay.cmds.is_empty());
+        assert_eq!(replay.file().unwrap(), b"a1".to_vec());
+        assert_eq!(replay.file().unwrap(), b"a2".to_vec());
+        assert!(matches!(replay.file(), Err(Error::ReplayEnd)));
+
+        assert_eq!(replay.vis().unwrap(), (b"v1".to_vec(), 0));
+        // Recorded 60 ms apart
+        assert_eq!(replay.vis().unwrap(), (b"v2".to_vec(), 60));
+    }
+
+    #[test]
+    fn rejects_broken_captures() {
+        assert!(matches!(Replay::parse(b"HCAP0"), Err(Error::BadCapture("not a capture file"))));
+        assert!(matches!(Replay::parse(MAGIC), Err(Error::BadCapture("no device in capture"))));
+
+        let mut buf = capture(&[(Some("A"), Kind::Desc, &[]), (None, Kind::File, b"abc")]);
+        buf.pop();
+        assert!(matches!(Replay::parse(&buf), Err(Error::BadCapture("truncated record"))));
+
+        let mut buf = capture(&[(Some("A"), Kind::Desc, &[])]);
+        buf.extend_from_slice(&[7, 0, 0, 0, 0, 0, 0, 0, 0]);
+        assert!(matches!(Replay::parse(&buf), Err(Error::BadCapture("unknown record"))));
+    }
+}
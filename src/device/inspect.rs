@@ -0,0 +1,142 @@
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::fmt::Write;
+
+use ellocopo2::owned::Msg as DevMsg;
+
+// Oldest exchanges are dropped past this
+pub const CAPACITY: usize = 1000;
+
+/// One request and its answer
+#[derive(Debug, Clone)]
+pub struct Exchange {
+    pub seq: u64,
+    pub serial: String,
+    // ms since the epoch
+    pub start: f64,
+    pub duration: f64,
+    pub request: DevMsg,
+    pub raw_out: Vec<u8>,
+    pub answer: Option<DevMsg>,
+    pub raw_in: Vec<u8>,
+    pub error: Option<String>,
+}
+
+impl Exchange {
+    pub fn new(serial: &str, request: &DevMsg) -> Self {
+        Self {
+            seq: 0,
+            serial: serial.to_string(),
+            start: js_sys::Date::now(),
+            duration: 0.,
+            request: request.clone(),
+            raw_out: Vec::new(),
+            answer: None,
+            raw_in: Vec::new(),
+            error: None,
+        }
+    }
+
+    pub fn path(&self) -> &str {
+        &self.request.1
+    }
+
+    pub fn is_ok(&self) -> bool {
+        self.error.is_none()
+    }
+}
+
+#[derive(Default)]
+pub struct Traffic {
+    seq: u64,
+    log: VecDeque<Exchange>,
+    paused: bool,
+}
+
+thread_local! {
+    // Shared by every device, the page runs on one thread
+    static TRAFFIC: RefCell<Traffic> = RefCell::new(Traffic::default());
+}
+
+/// Stores a finished exchange
+pub fn record(mut ex: Exchange) {
+    ex.duration = js_sys::Date::now() - ex.start;
+    TRAFFIC.with(|t| {
+        let mut t = t.borrow_mut();
+        if t.paused {
+            return;
+        }
+        t.seq += 1;
+        ex.seq = t.seq;
+        if t.log.len() == CAPACITY {
+            t.log.pop_front();
+        }
+        t.log.push_back(ex);
+    });
+}
+
+/// Exchanges whose path contains `filter`, newest last
+pub fn filtered(filter: &str) -> Vec<Exchange> {
+    TRAFFIC.with(|t| {
+        t.borrow()
+            .log
+            .iter()
+            .filter(|ex| ex.path().contains(filter))
+            .cloned()
+            .collect()
+    })
+}
+
+/// Newest `limit` exchanges whose path contains `filter`, newest first, and the match count
+///
+/// Only the returned rows are cloned, the view calls it on every render.
+pub fn latest(filter: &str, limit: usize) -> (Vec<Exchange>, usize) {
+    TRAFFIC.with(|t| {
+        let t = t.borrow();
+        let matches = || t.log.iter().rev().filter(|ex| ex.path().contains(filter));
+        (matches().take(limit).cloned().collect(), matches().count())
+    })
+}
+
+pub fn len() -> usize {
+    TRAFFIC.with(|t| t.borrow().log.len())
+}
+
+pub fn clear() {
+    TRAFFIC.with(|t| t.borrow_mut().log.clear());
+}
+
+pub fn set_paused(paused: bool) {
+    TRAFFIC.with(|t| t.borrow_mut().paused = paused);
+}
+
+pub fn is_paused() -> bool {
+    TRAFFIC.with(|t| t.borrow().paused)
+}
+
+/// Text dump, one exchange per block
+pub fn export(exchanges: &[Exchange]) -> String {
+    let mut out = String::new();
+    for ex in exchanges {
+        let _ = writeln!(
+            out,
+            "#{} {} {} {:.1} ms {}",
+            ex.seq,
+            js_sys::Date::new(&ex.start.into()).to_iso_string(),
+            ex.serial,
+            ex.duration,
+            ex.error.as_deref().unwrap_or("ok"),
+        );
+        let _ = writeln!(out, "  > {:?} {} {:?}", ex.request.0, ex.request.1, ex.request.2);
+        let _ = writeln!(out, "  > {}", hex(&ex.raw_out));
+        if let Some(DevMsg(code, path, value)) = &ex.answer {
+            let _ = writeln!(out, "  < {:?} {} {:?}", code, path, value);
+        }
+        let _ = writeln!(out, "  < {}", hex(&ex.raw_in));
+    }
+    out
+}
+
+pub fn hex(buf: &[u8]) -> String {
+    buf.iter().map(|b| format!("{:02x}", b)).collect::<Vec<_>>().join(" ")
+}
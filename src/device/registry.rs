@@ -0,0 +1,159 @@
+use std::rc::Rc;
+
+use serde::{Deserialize, Deserializer, Serialize};
+use seed::prelude::*;
+use wasm_bindgen::JsValue;
+
+use super::{Desc, Type};
+use crate::dfu::image::Layout;
+use crate::selftest::Limits;
+use crate::vis::panel::Rates;
+
+// Shipped with the page, new revisions only need an entry here
+const DEVICES_URL: &str = "public/devices.json";
+const BUILTIN: &str = include_str!("../../public/devices.json");
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Feature {
+    Tree,
+    File,
+    Vis,
+    Update,
+    Dfu,
+}
+
+/// Bulk IN endpoint numbers
+#[derive(Debug, Clone, Deserialize)]
+pub struct Endpoints {
+    pub file: u8,
+    pub vis: u8,
+}
+
+impl Default for Endpoints {
+    fn default() -> Self {
+        Self { file: 0x02, vis: 0x03 }
+    }
+}
+
+/// What the app knows about one kind of hardware
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct Profile {
+    pub name: String,
+    #[serde(deserialize_with = "hex_u16")]
+    pub vid: u16,
+    #[serde(deserialize_with = "hex_u16")]
+    pub pid: u16,
+    pub kind: Type,
+    // Interface count, tells revisions with the same ids apart
+    #[serde(default)]
+    pub interfaces: Option<u8>,
+    #[serde(default)]
+    pub scheme: Option<String>,
+    #[serde(default)]
+    pub endpoints: Endpoints,
+    #[serde(default)]
+    pub features: Vec<Feature>,
+    // `/dbg/flags` bit names, index is the bit number
+    #[serde(default)]
+    pub dbg_flags: Vec<String>,
+    // Firmware memory map, needed to check and flash images
+    #[serde(default)]
+    pub layout: Option<Layout>,
+    #[serde(default)]
+    pub selftest: Option<Limits>,
+    // Vis stream group rates
+    #[serde(default, deserialize_with = "positive_rates")]
+    pub rates: Option<Rates>,
+}
+
+impl Profile {
+    pub fn has(&self, feature: Feature) -> bool {
+        self.features.contains(&feature)
+    }
+
+    fn matches(&self, desc: &Desc) -> bool {
+        self.vid == desc.vid
+            && self.pid == desc.pid
+            && self.interfaces.map_or(true, |n| n == desc.interfaces)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Registry {
+    devices: Vec<Rc<Profile>>,
+}
+
+#[derive(Serialize)]
+#[allow(non_snake_case)]
+struct Filter {
+    vendorId: u16,
+    productId: u16,
+}
+
+impl Default for Registry {
+    fn default() -> Self {
+        serde_json::from_str(BUILTIN).expect("bad builtin devices.json")
+    }
+}
+
+impl Registry {
+    /// Fetches the served device list, the builtin one is used if it's unavailable
+    pub async fn load() -> Self {
+        match fetch_devices().await {
+            Ok(reg) => reg,
+            Err(e) => {
+                log::warn!("{} not loaded, using builtin list: {}", DEVICES_URL, e);
+                Self::default()
+            }
+        }
+    }
+
+    pub fn find(&self, desc: &Desc) -> Option<Rc<Profile>> {
+        // Entries with an interface count are more specific
+        self.devices
+            .iter()
+            .filter(|p| p.matches(desc))
+            .max_by_key(|p| p.interfaces.is_some())
+            .cloned()
+    }
+
+    /// `navigator.usb.requestDevice` filters
+    pub fn filters(&self) -> JsValue {
+        let filters = self.devices
+            .iter()
+            .map(|p| Filter { vendorId: p.vid, productId: p.pid })
+            .collect::<Vec<_>>();
+        JsValue::from_serde(&filters).unwrap()
+    }
+}
+
+async fn fetch_devices() -> Result<Registry, String> {
+    let response = fetch(DEVICES_URL)
+        .await
+        .map_err(|e| format!("{:?}", e))?;
+    let text = response
+        .check_status()
+        .map_err(|e| format!("{:?}", e))?
+        .text()
+        .await
+        .map_err(|e| format!("{:?}", e))?;
+    serde_json::from_str(&text).map_err(|e| e.to_string())
+}
+
+fn hex_u16<'de, D: Deserializer<'de>>(d: D) -> Result<u16, D::Error> {
+    let s = String::deserialize(d)?;
+    let digits = s.trim_start_matches("0x").trim_start_matches("0X");
+    u16::from_str_radix(digits, 16).map_err(serde::de::Error::custom)
+}
+
+// Every time axis and buffer size is derived from these
+fn positive_rates<'de, D: Deserializer<'de>>(d: D) -> Result<Option<Rates>, D::Error> {
+    let rates = Option::<Rates>::deserialize(d)?;
+    if let Some(r) = &rates {
+        if ![r.ecg, r.reo, r.acc_in].iter().all(|f| f.is_finite() && *f > 0.) {
+            return Err(serde::de::Error::custom(format!("rates must be positive: {:?}", r)));
+        }
+    }
+    Ok(rates)
+}
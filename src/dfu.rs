@@ -0,0 +1,593 @@
+use std::cell::Cell;
+use std::convert::TryFrom;
+use std::rc::Rc;
+
+use gloo_timers::future::TimeoutFuture;
+
+use holter_dfu::dfu::defs::*;
+
+use crate::device;
+
+pub mod image;
+pub mod container;
+
+use container::Firmware;
+
+// Bytes per DNLOAD/UPLOAD request when the loader doesn't say
+pub const TRANSFER_SIZE: usize = 64;
+// DFU functional descriptor
+const DFU_FUNCTIONAL_TYPE: u8 = 0x21;
+const DFU_FUNCTIONAL_LEN: usize = 9;
+// Upload stops here even if the loader keeps sending full blocks
+pub const MAX_UPLOAD_SIZE: usize = 0x10_0000;
+// Status polls of a single block before giving up
+const MAX_POLLS: u32 = 1000;
+// bwPollTimeout is 24 bit, a garbled reply must not park the transfer for hours
+const MAX_POLL_TIMEOUT_MS: u32 = 5_000;
+
+/// bState of DFU_GETSTATUS/DFU_GETSTATE, values from `defs::dfu_state`
+#[allow(non_camel_case_types)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    appIDLE,
+    appDETACH,
+    dfuIDLE,
+    dfuDNLOAD_SYNC,
+    dfuDNBUSY,
+    dfuDNLOAD_IDLE,
+    dfuMANIFEST_SYNC,
+    dfuMANIFEST,
+    dfuMANIFEST_WAIT_RESET,
+    dfuUPLOAD_IDLE,
+    dfuERROR,
+}
+
+/// bStatus of DFU_GETSTATUS, values from `defs::dfu_status`
+#[allow(non_camel_case_types)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    OK,
+    errTARGET,
+    errFILE,
+    errWRITE,
+    errERASE,
+    errCHECK_ERASED,
+    errPROG,
+    errVERIFY,
+    errADDRESS,
+    errNOTDONE,
+    errFIRMWARE,
+    errVENDOR,
+    errUSBR,
+    errPOR,
+    errUNKNOWN,
+    errSTALLEDPKT,
+}
+
+/// Parsed 6 byte DFU_GETSTATUS reply
+#[derive(Debug, Clone, Copy)]
+pub struct StatusReply {
+    pub status: Status,
+    // Minimum time before the next GETSTATUS, ms
+    pub poll_timeout: u32,
+    pub state: State,
+}
+
+#[derive(Debug, Clone)]
+pub enum Error {
+    Transport(device::Error),
+    ShortReply(usize),
+    UnknownStatus(u8),
+    UnknownState(u8),
+    // Loader reported a failure, the error state is already cleared
+    Failed { status: Status, state: State, block: Option<u16> },
+    UnexpectedState { state: State, block: Option<u16> },
+    TooManyPolls(u16),
+    TooLarge(usize),
+    Cancelled,
+    // Read back differs from the written image
+    Mismatch { offset: usize, expected: u8, actual: Option<u8> },
+    // Loader resets after manifestation, nothing to read back
+    NoReadback,
+    Unsupported(&'static str),
+}
+
+impl From<device::Error> for Error {
+    fn from(e: device::Error) -> Self {
+        Error::Transport(e)
+    }
+}
+
+impl TryFrom<u8> for State {
+    type Error = Error;
+    fn try_from(v: u8) -> Result<Self, Error> {
+        use State::*;
+        Ok(match v {
+            dfu_state::APP_IDLE                => appIDLE,
+            dfu_state::APP_DETACH              => appDETACH,
+            dfu_state::DFU_IDLE                => dfuIDLE,
+            dfu_state::DFU_DNLOAD_SYNC         => dfuDNLOAD_SYNC,
+            dfu_state::DFU_DNBUSY              => dfuDNBUSY,
+            dfu_state::DFU_DNLOAD_IDLE         => dfuDNLOAD_IDLE,
+            dfu_state::DFU_MANIFEST_SYNC       => dfuMANIFEST_SYNC,
+            dfu_state::DFU_MANIFEST            => dfuMANIFEST,
+            dfu_state::DFU_MANIFEST_WAIT_RESET => dfuMANIFEST_WAIT_RESET,
+            dfu_state::DFU_UPLOAD_IDLE         => dfuUPLOAD_IDLE,
+            dfu_state::DFU_ERROR               => dfuERROR,
+            v => return Err(Error::UnknownState(v)),
+        })
+    }
+}
+
+impl TryFrom<u8> for Status {
+    type Error = Error;
+    fn try_from(v: u8) -> Result<Self, Error> {
+        use Status::*;
+        Ok(match v {
+            dfu_status::OK                => OK,
+            dfu_status::ERR_TARGET        => errTARGET,
+            dfu_status::ERR_FILE          => errFILE,
+            dfu_status::ERR_WRITE         => errWRITE,
+            dfu_status::ERR_ERASE         => errERASE,
+            dfu_status::ERR_CHECK_ERASED  => errCHECK_ERASED,
+            dfu_status::ERR_PROG          => errPROG,
+            dfu_status::ERR_VERIFY        => errVERIFY,
+            dfu_status::ERR_ADDRESS       => errADDRESS,
+            dfu_status::ERR_NOTDONE       => errNOTDONE,
+            dfu_status::ERR_FIRMWARE      => errFIRMWARE,
+            dfu_status::ERR_VENDOR        => errVENDOR,
+            dfu_status::ERR_USBR          => errUSBR,
+            dfu_status::ERR_POR           => errPOR,
+            dfu_status::ERR_UNKNOWN       => errUNKNOWN,
+            dfu_status::ERR_STALLEDPKT    => errSTALLEDPKT,
+            v => return Err(Error::UnknownStatus(v)),
+        })
+    }
+}
+
+impl StatusReply {
+    pub fn parse(buf: &[u8]) -> Result<Self, Error> {
+        if buf.len() < 6 {
+            return Err(Error::ShortReply(buf.len()));
+        }
+        Ok(Self {
+            status: Status::try_from(buf[0])?,
+            poll_timeout: u32::from_le_bytes([buf[1], buf[2], buf[3], 0]),
+            state: State::try_from(buf[4])?,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Phase {
+    Download,
+    Manifest,
+    Verify,
+    // Reading the flash out, nothing is written
+    Upload,
+}
+
+/// Transfer position, `done` and `total` in bytes
+#[derive(Debug, Clone, Copy)]
+pub struct Progress {
+    pub phase: Phase,
+    pub done: usize,
+    pub total: usize,
+}
+
+/// How the loader finished manifestation
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Manifest {
+    // Still in dfuIDLE, flash can be read back
+    Idle,
+    // Loader resets or already dropped off the bus
+    Reset,
+}
+
+/// Loader capabilities from the DFU functional descriptor
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Functional {
+    pub can_download: bool,
+    pub can_upload: bool,
+    pub manifestation_tolerant: bool,
+    pub will_detach: bool,
+    // ms
+    pub detach_timeout: u16,
+    pub transfer_size: u16,
+}
+
+impl Default for Functional {
+    /// What the loader was assumed to do before the descriptor was read
+    fn default() -> Self {
+        Self {
+            can_download: true,
+            can_upload: true,
+            manifestation_tolerant: true,
+            will_detach: false,
+            detach_timeout: 0,
+            transfer_size: TRANSFER_SIZE as u16,
+        }
+    }
+}
+
+impl Functional {
+    /// Finds the descriptor among the configuration descriptors
+    pub fn parse(config: &[u8]) -> Option<Self> {
+        let mut at = 0;
+        while at + 2 <= config.len() {
+            let len = config[at] as usize;
+            if len < 2 {
+                break;
+            }
+            let desc = config.get(at .. at + len)?;
+            if desc[1] == DFU_FUNCTIONAL_TYPE && len >= DFU_FUNCTIONAL_LEN {
+                let attrs = desc[2];
+                return Some(Self {
+                    can_download: attrs & 0x01 != 0,
+                    can_upload: attrs & 0x02 != 0,
+                    manifestation_tolerant: attrs & 0x04 != 0,
+                    will_detach: attrs & 0x08 != 0,
+                    detach_timeout: u16::from_le_bytes([desc[3], desc[4]]),
+                    transfer_size: u16::from_le_bytes([desc[5], desc[6]]).max(1),
+                });
+            }
+            at += len;
+        }
+        None
+    }
+
+    /// Reads the descriptor, falls back to defaults if the loader has none
+    pub async fn read(dev: &device::Device) -> Result<Self, Error> {
+        let config = dev.config_descriptor().await?;
+        match Self::parse(&config) {
+            Some(f) => {
+                log::info!("DFU functional: {:?}", f);
+                Ok(f)
+            }
+            None => {
+                log::warn!("No DFU functional descriptor, assuming defaults");
+                Ok(Self::default())
+            }
+        }
+    }
+}
+
+/// DFU 1.1 host side state machine over a loader device
+pub struct Dfu<'a> {
+    dev: &'a device::Device,
+    caps: Functional,
+    progress: Option<Box<dyn Fn(Progress)>>,
+    cancel: Option<Rc<Cell<bool>>>,
+}
+
+impl<'a> Dfu<'a> {
+    pub fn new(dev: &'a device::Device) -> Self {
+        Self { dev, caps: Functional::default(), progress: None, cancel: None }
+    }
+
+    /// Transfer size and supported operations from the loader
+    pub fn functional(mut self, caps: Functional) -> Self {
+        self.caps = caps;
+        self
+    }
+
+    /// Bytes per DNLOAD/UPLOAD request, only the request size: blocks are
+    /// numbered as a sequence, so image offsets don't depend on it
+    fn transfer_size(&self) -> usize {
+        self.caps.transfer_size as usize
+    }
+
+    /// Called after every transferred block
+    pub fn on_progress(mut self, f: impl Fn(Progress) + 'static) -> Self {
+        self.progress = Some(Box::new(f));
+        self
+    }
+
+    /// Transfer stops with DFU_ABORT once the flag is set
+    pub fn cancel_flag(mut self, flag: Rc<Cell<bool>>) -> Self {
+        self.cancel = Some(flag);
+        self
+    }
+
+    fn report(&self, phase: Phase, done: usize, total: usize) {
+        if let Some(f) = &self.progress {
+            f(Progress { phase, done, total });
+        }
+    }
+
+    async fn check_cancel(&self) -> Result<(), Error> {
+        match &self.cancel {
+            Some(flag) if flag.get() => {
+                log::warn!("DFU transfer cancelled");
+                let _ = self.abort().await;
+                Err(Error::Cancelled)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    pub async fn get_status(&self) -> Result<StatusReply, Error> {
+        let buf = self.dev.dfu_control_in(dfu_request::DFU_GETSTATUS, 6, 0).await?;
+        StatusReply::parse(&buf)
+    }
+
+    pub async fn get_state(&self) -> Result<State, Error> {
+        let buf = self.dev.dfu_control_in(dfu_request::DFU_GETSTATE, 1, 0).await?;
+        match buf.first() {
+            Some(&s) => State::try_from(s),
+            None => Err(Error::ShortReply(0)),
+        }
+    }
+
+    pub async fn clear_status(&self) -> Result<(), Error> {
+        self.dev.dfu_control_out(dfu_request::DFU_CLRSTATUS, &[], 0).await?;
+        Ok(())
+    }
+
+    pub async fn abort(&self) -> Result<(), Error> {
+        self.dev.dfu_control_out(dfu_request::DFU_ABORT, &[], 0).await?;
+        Ok(())
+    }
+
+    /// Brings the loader to dfuIDLE from error or an unfinished transfer
+    pub async fn ensure_idle(&self) -> Result<(), Error> {
+        let st = self.get_status().await?;
+        match st.state {
+            State::dfuIDLE => return Ok(()),
+            State::dfuERROR => {
+                log::warn!("DFU in error state ({:?}), clearing", st.status);
+                self.clear_status().await?;
+            }
+            State::dfuDNLOAD_IDLE | State::dfuUPLOAD_IDLE => {
+                log::warn!("DFU transfer left unfinished ({:?}), aborting", st.state);
+                self.abort().await?;
+            }
+            state => return Err(Error::UnexpectedState { state, block: None }),
+        }
+        match self.get_status().await?.state {
+            State::dfuIDLE => Ok(()),
+            state => Err(Error::UnexpectedState { state, block: None }),
+        }
+    }
+
+    /// Polls GETSTATUS honoring bwPollTimeout until the loader leaves a busy state
+    async fn wait_while_busy(&self, block: Option<u16>) -> Result<StatusReply, Error> {
+        for _ in 0 .. MAX_POLLS {
+            let st = self.get_status().await?;
+            if st.status != Status::OK || st.state == State::dfuERROR {
+                // Leave the loader usable for a retry
+                let _ = self.clear_status().await;
+                return Err(Error::Failed { status: st.status, state: st.state, block });
+            }
+            match st.state {
+                State::dfuDNLOAD_SYNC | State::dfuDNBUSY
+                | State::dfuMANIFEST_SYNC | State::dfuMANIFEST => {
+                    TimeoutFuture::new(st.poll_timeout.min(MAX_POLL_TIMEOUT_MS)).await;
+                }
+                _ => return Ok(st),
+            }
+        }
+        Err(Error::TooManyPolls(block.unwrap_or(0)))
+    }
+
+    /// Writes the image from the application start and runs manifestation
+    ///
+    /// DFU 1.1 (6.1.1) treats wBlockNum as a running counter and leaves placement
+    /// to the loader, so the image goes as one flat stream with gaps erased.
+    pub async fn download(&self, fw: &Firmware) -> Result<Manifest, Error> {
+        if !self.caps.can_download {
+            return Err(Error::Unsupported("download"));
+        }
+        self.ensure_idle().await?;
+        let image = fw.flatten();
+        let total = image.len();
+        log::info!("DFU download, {} bytes from 0x{:08x}", total, fw.base);
+
+        let mut done = 0;
+        let mut next_block = 0;
+        for (block, chunk) in blocks(&image, self.transfer_size()) {
+            self.check_cancel().await?;
+            self.dev.dfu_control_out(dfu_request::DFU_DNLOAD, chunk, block as u32).await?;
+            let st = self.wait_while_busy(Some(block)).await?;
+            if st.state != State::dfuDNLOAD_IDLE {
+                let _ = self.abort().await;
+                return Err(Error::UnexpectedState { state: st.state, block: Some(block) });
+            }
+            done += chunk.len();
+            next_block = block.wrapping_add(1);
+            self.report(Phase::Download, done, total);
+        }
+
+        // Zero length block starts manifestation, no way back after it
+        self.report(Phase::Manifest, total, total);
+        self.dev.dfu_control_out(dfu_request::DFU_DNLOAD, &[], next_block as u32).await?;
+        match self.wait_while_busy(None).await {
+            Ok(StatusReply { state: State::dfuIDLE, .. }) => Ok(Manifest::Idle),
+            Ok(StatusReply { state: State::dfuMANIFEST_WAIT_RESET, .. }) => Ok(Manifest::Reset),
+            Ok(st) => Err(Error::UnexpectedState { state: st.state, block: None }),
+            // Loaders without manifestation tolerance reset right away
+            Err(Error::Transport(e)) => {
+                log::warn!("DFU device gone after manifestation: {:?}", e);
+                Ok(Manifest::Reset)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Writes the image and compares it with the flash contents
+    pub async fn download_verified(&self, fw: &Firmware) -> Result<(), Error> {
+        match self.download(fw).await? {
+            Manifest::Idle if self.caps.can_upload => self.verify(fw).await,
+            _ => Err(Error::NoReadback),
+        }
+    }
+
+    /// Reads back as much as was written and finds the first difference
+    pub async fn verify(&self, fw: &Firmware) -> Result<(), Error> {
+        let image = fw.flatten();
+        let flash = self.read(image.len(), Phase::Verify).await?;
+        if let Some(i) = image.iter().zip(flash.iter()).position(|(a, b)| a != b) {
+            return Err(Error::Mismatch { offset: i, expected: image[i], actual: Some(flash[i]) });
+        }
+        if flash.len() < image.len() {
+            let i = flash.len();
+            return Err(Error::Mismatch { offset: i, expected: image[i], actual: None });
+        }
+        log::info!("DFU verify ok, {} bytes", image.len());
+        Ok(())
+    }
+
+    /// Reads the image back until a short block or dfuIDLE
+    pub async fn upload(&self) -> Result<Vec<u8>, Error> {
+        let data = self.read(MAX_UPLOAD_SIZE, Phase::Upload).await?;
+        if data.len() >= MAX_UPLOAD_SIZE {
+            return Err(Error::TooLarge(data.len()));
+        }
+        log::info!("DFU upload, {} bytes", data.len());
+
+        Ok(data)
+    }
+
+    /// Upload stopping at `limit` bytes, the loader is aborted back to dfuIDLE then
+    async fn read(&self, limit: usize, phase: Phase) -> Result<Vec<u8>, Error> {
+        if !self.caps.can_upload {
+            return Err(Error::Unsupported("upload"));
+        }
+        self.ensure_idle().await?;
+        let block_size = self.transfer_size();
+
+        let mut data = Vec::new();
+        let mut block: u16 = 0;
+        loop {
+            self.check_cancel().await?;
+            let mut buf = self.dev
+                .dfu_control_in(dfu_request::DFU_UPLOAD, block_size as u16, block as u32)
+                .await?;
+            let short = buf.len() < block_size;
+            data.append(&mut buf);
+            self.report(phase, data.len().min(limit), limit);
+            if short {
+                break;
+            }
+            if data.len() >= limit {
+                data.truncate(limit);
+                self.abort().await?;
+                break;
+            }
+
+            match self.get_state().await? {
+                State::dfuUPLOAD_IDLE => (),
+                State::dfuIDLE => break,
+                State::dfuERROR => {
+                    let st = self.get_status().await?;
+                    let _ = self.clear_status().await;
+                    return Err(Error::Failed { status: st.status, state: st.state, block: Some(block) });
+                }
+                state => return Err(Error::UnexpectedState { state, block: Some(block) }),
+            }
+            block = block.wrapping_add(1);
+        }
+
+        Ok(data)
+    }
+}
+
+/// Numbered DNLOAD blocks of a flat image, the last one may be short
+fn blocks(image: &[u8], block_size: usize) -> impl Iterator<Item = (u16, &[u8])> {
+    image
+        .chunks(block_size)
+        .enumerate()
+        .map(|(i, chunk)| (i as u16, chunk))
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Transport(e) => write!(f, "USB transfer failed: {:?}", e),
+            Error::ShortReply(n) => write!(f, "Short DFU reply, {} bytes", n),
+            Error::UnknownStatus(v) => write!(f, "Unknown DFU status 0x{:02x}", v),
+            Error::UnknownState(v) => write!(f, "Unknown DFU state 0x{:02x}", v),
+            Error::Failed { status, state, block: Some(b) } => write!(f, "Loader failed at block {}: {:?} in {:?}", b, status, state),
+            Error::Failed { status, state, block: None } => write!(f, "Loader failed: {:?} in {:?}", status, state),
+            Error::UnexpectedState { state, block: Some(b) } => write!(f, "Unexpected loader state {:?} at block {}", state, b),
+            Error::UnexpectedState { state, block: None } => write!(f, "Unexpected loader state {:?}", state),
+            Error::TooManyPolls(b) => write!(f, "Loader stays busy at block {}", b),
+            Error::TooLarge(n) => write!(f, "Upload exceeds {} bytes", n),
+            Error::Cancelled => write!(f, "Cancelled"),
+            Error::Mismatch { offset, expected, actual: Some(a) } => write!(
+                f,
+                "Verify failed at image offset 0x{:x}: wrote 0x{:02x}, read 0x{:02x}",
+                offset, expected, a,
+            ),
+            Error::Mismatch { offset, .. } => write!(f, "Verify failed: flash ends at offset 0x{:x}", offset),
+            Error::NoReadback => write!(f, "Loader reset after writing, image not verified"),
+            Error::Unsupported(op) => write!(f, "Loader doesn't support {}", op),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use container::Segment;
+
+    fn firmware(segments: Vec<Segment>) -> Firmware {
+        Firmware { format: container::Format::Hex, base: 0x0800_0000, segments }
+    }
+
+    #[test]
+    fn blocks_are_numbered_from_zero() {
+        let image: Vec<u8> = (0 .. 10).collect();
+        let b: Vec<_> = blocks(&image, 4).collect();
+        assert_eq!(b, vec![(0, &image[0 .. 4]), (1, &image[4 .. 8]), (2, &image[8 .. 10])]);
+    }
+
+    #[test]
+    fn gaps_are_sent_erased() {
+        let fw = firmware(vec![
+            Segment { addr: 0x0800_0002, data: vec![1, 2] },
+            Segment { addr: 0x0800_0009, data: vec![3] },
+        ]);
+        let image = fw.flatten();
+        let b: Vec<_> = blocks(&image, 4).collect();
+        assert_eq!(b.len(), 3);
+        // Leading padding keeps the first segment at its address
+        assert_eq!(b[0], (0, &[0xFF, 0xFF, 1, 2][..]));
+        assert_eq!(b[1], (1, &[0xFF; 4][..]));
+        assert_eq!(b[2], (2, &[0xFF, 3][..]));
+    }
+
+    #[test]
+    fn offsets_do_not_depend_on_transfer_size() {
+        let image: Vec<u8> = (0 .. 200).map(|i| i as u8).collect();
+        for &size in &[64, 100, 4096] {
+            let stream: Vec<u8> = blocks(&image, size).flat_map(|(_, chunk)| chunk.iter().cloned()).collect();
+            assert_eq!(stream, image);
+        }
+    }
+
+    #[test]
+    fn functional_descriptor() {
+        // Interface descriptor followed by the DFU functional one
+        let config = [
+            9, 0x04, 0, 0, 0, 0xFE, 0x01, 0x02, 0,
+            9, 0x21, 0x0B, 0xFF, 0x00, 0x00, 0x08, 0x1A, 0x01,
+        ];
+        let f = Functional::parse(&config).unwrap();
+        assert!(f.can_download && f.can_upload && f.will_detach);
+        assert!(!f.manifestation_tolerant);
+        assert_eq!(f.detach_timeout, 0xFF);
+        assert_eq!(f.transfer_size, 0x800);
+
+        assert_eq!(Functional::parse(&config[.. 9]), None);
+        // Truncated descriptor
+        assert_eq!(Functional::parse(&config[.. 14]), None);
+    }
+
+    #[test]
+    fn block_numbers_wrap() {
+        let image = vec![0u8; 0x1_0001];
+        let last = blocks(&image, 1).last().unwrap();
+        assert_eq!(last.0, 0);
+    }
+}
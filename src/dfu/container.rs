@@ -0,0 +1,473 @@
+use std::convert::TryInto;
+
+use super::image::{self, Layout};
+
+const DFUSE_PREFIX: &[u8] = b"DfuSe";
+const DFUSE_TARGET: &[u8] = b"Target";
+const DFUSE_SUFFIX_LEN: usize = 16;
+const ELF_MAGIC: &[u8] = b"\x7fELF";
+const PT_LOAD: u32 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    Bin,
+    Hex,
+    Elf,
+    DfuSe,
+}
+
+/// Continuous piece of flash contents
+#[derive(Debug, Clone)]
+pub struct Segment {
+    pub addr: u32,
+    pub data: Vec<u8>,
+}
+
+impl Segment {
+    pub fn end(&self) -> u32 {
+        self.addr + self.data.len() as u32
+    }
+}
+
+/// Firmware file split into segments sorted by address
+#[derive(Debug, Clone)]
+pub struct Firmware {
+    pub format: Format,
+    // Application start of the target, flat image offset 0
+    pub base: u32,
+    pub segments: Vec<Segment>,
+}
+
+#[derive(Debug, Clone)]
+pub enum Error {
+    Empty,
+    Hex { line: usize, reason: &'static str },
+    Elf(&'static str),
+    DfuSe(&'static str),
+    OutOfRange { addr: u32, len: usize, base: u32, end: u64 },
+    Overlap(u32),
+}
+
+impl Firmware {
+    /// Recognizes the container by its contents, anything unknown is a flat binary
+    pub fn parse(data: &[u8], layout: &Layout) -> Result<Self, Error> {
+        let (format, segments) = if data.starts_with(DFUSE_PREFIX) {
+            (Format::DfuSe, parse_dfuse(data)?)
+        } else if data.starts_with(ELF_MAGIC) {
+            (Format::Elf, parse_elf(data)?)
+        } else if data.first() == Some(&b':') && data.iter().all(|b| b.is_ascii()) {
+            (Format::Hex, parse_hex(data)?)
+        } else {
+            (Format::Bin, vec![Segment { addr: layout.app_base, data: data.to_vec() }])
+        };
+
+        let segments = check(segments, layout)?;
+        Ok(Self { format, base: layout.app_base, segments })
+    }
+
+    pub fn size(&self) -> usize {
+        self.segments.iter().map(|s| s.data.len()).sum()
+    }
+
+    /// Image as it lies in flash from `base`, gaps erased
+    pub fn flatten(&self) -> Vec<u8> {
+        let end = self.segments.last().map_or(self.base, |s| s.end());
+        let mut flat = vec![0xFF; (end - self.base) as usize];
+        for s in &self.segments {
+            let at = (s.addr - self.base) as usize;
+            flat[at .. at + s.data.len()].copy_from_slice(&s.data);
+        }
+        flat
+    }
+}
+
+/// Sorts, merges adjacent pieces and rejects anything outside the application area
+fn check(mut segments: Vec<Segment>, layout: &Layout) -> Result<Vec<Segment>, Error> {
+    segments.retain(|s| !s.data.is_empty());
+    if segments.is_empty() {
+        return Err(Error::Empty);
+    }
+    segments.sort_by_key(|s| s.addr);
+
+    let mut merged: Vec<Segment> = Vec::with_capacity(segments.len());
+    for s in segments {
+        let end = s.addr as u64 + s.data.len() as u64;
+        if s.addr < layout.app_base || end > layout.app_end() {
+            let (base, end) = (layout.app_base, layout.app_end());
+            return Err(Error::OutOfRange { addr: s.addr, len: s.data.len(), base, end });
+        }
+        match merged.last_mut() {
+            Some(last) if s.addr < last.end() => return Err(Error::Overlap(s.addr)),
+            Some(last) if s.addr == last.end() => last.data.extend_from_slice(&s.data),
+            _ => merged.push(s),
+        }
+    }
+    Ok(merged)
+}
+
+fn u16_at(data: &[u8], at: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(data.get(at .. at.checked_add(2)?)?.try_into().unwrap()))
+}
+
+fn u32_at(data: &[u8], at: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(data.get(at .. at.checked_add(4)?)?.try_into().unwrap()))
+}
+
+fn parse_hex(data: &[u8]) -> Result<Vec<Segment>, Error> {
+    let text = String::from_utf8_lossy(data);
+    let mut segments: Vec<Segment> = Vec::new();
+    let mut base: u32 = 0;
+
+    for (i, line) in text.lines().enumerate() {
+        let line_no = i + 1;
+        let err = |reason| Error::Hex { line: line_no, reason };
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        if !line.starts_with(':') || line.len() < 11 || line.len() % 2 == 0 {
+            return Err(err("malformed record"));
+        }
+        let bytes = (1 .. line.len())
+            .step_by(2)
+            .map(|j| u8::from_str_radix(&line[j .. j + 2], 16))
+            .collect::<Result<Vec<u8>, _>>()
+            .map_err(|_| err("not a hex digit"))?;
+
+        let len = bytes[0] as usize;
+        if bytes.len() != len + 5 {
+            return Err(err("length mismatch"));
+        }
+        if bytes.iter().fold(0u8, |acc, &b| acc.wrapping_add(b)) != 0 {
+            return Err(err("bad checksum"));
+        }
+        let offset = u16::from_be_bytes([bytes[1], bytes[2]]) as u32;
+        let payload = &bytes[4 .. 4 + len];
+
+        match bytes[3] {
+            0x00 => {
+                let addr = base.checked_add(offset).ok_or_else(|| err("address overflow"))?;
+                match segments.last_mut() {
+                    Some(last) if last.addr as u64 + last.data.len() as u64 == addr as u64 => {
+                        last.data.extend_from_slice(payload)
+                    }
+                    _ => segments.push(Segment { addr, data: payload.to_vec() }),
+                }
+            }
+            0x01 => return Ok(segments),
+            0x02 if len == 2 => base = (u16::from_be_bytes([payload[0], payload[1]]) as u32) << 4,
+            0x04 if len == 2 => base = (u16::from_be_bytes([payload[0], payload[1]]) as u32) << 16,
+            // Start addresses, the loader jumps through the vector table anyway
+            0x03 | 0x05 => (),
+            _ => return Err(err("unsupported record")),
+        }
+    }
+    Err(Error::Hex { line: 0, reason: "no end of file record" })
+}
+
+fn parse_elf(data: &[u8]) -> Result<Vec<Segment>, Error> {
+    const CLASS_32: u8 = 1;
+    const DATA_LE: u8 = 1;
+    if data.len() < 52 || data[4] != CLASS_32 || data[5] != DATA_LE {
+        return Err(Error::Elf("only 32 bit little endian files"));
+    }
+    let phoff = u32_at(data, 28).unwrap() as usize;
+    let phentsize = u16_at(data, 42).unwrap() as usize;
+    let phnum = u16_at(data, 44).unwrap() as usize;
+    if phnum == 0 || phentsize < 32 {
+        return Err(Error::Elf("no program headers"));
+    }
+
+    let mut segments = Vec::new();
+    for i in 0 .. phnum {
+        let ph = i
+            .checked_mul(phentsize)
+            .and_then(|off| off.checked_add(phoff))
+            .ok_or(Error::Elf("program header out of range"))?;
+        let field = |at| {
+            ph.checked_add(at)
+                .and_then(|at| u32_at(data, at))
+                .ok_or(Error::Elf("truncated program header"))
+        };
+        if field(0)? != PT_LOAD {
+            continue;
+        }
+        let offset = field(4)? as usize;
+        // Load address, .data initializers sit in flash while running in RAM
+        let paddr = field(12)?;
+        let filesz = field(16)? as usize;
+        if filesz == 0 {
+            continue;
+        }
+        let bytes = offset
+            .checked_add(filesz)
+            .and_then(|end| data.get(offset .. end))
+            .ok_or(Error::Elf("segment beyond end of file"))?;
+        segments.push(Segment { addr: paddr, data: bytes.to_vec() });
+    }
+    Ok(segments)
+}
+
+fn parse_dfuse(data: &[u8]) -> Result<Vec<Segment>, Error> {
+    if data.len() < 11 + DFUSE_SUFFIX_LEN {
+        return Err(Error::DfuSe("file too short"));
+    }
+    let (body, suffix) = data.split_at(data.len() - DFUSE_SUFFIX_LEN);
+    if &suffix[8 .. 11] != b"UFD" || suffix[11] as usize != DFUSE_SUFFIX_LEN {
+        return Err(Error::DfuSe("no DFU suffix"));
+    }
+    // Suffix CRC has no final inversion
+    let crc = u32_at(suffix, 12).unwrap();
+    if crc != !image::crc32(&data[.. data.len() - 4]) {
+        return Err(Error::DfuSe("suffix CRC mismatch"));
+    }
+
+    let targets = body[10];
+    let mut at = 11;
+    let mut segments = Vec::new();
+    for _ in 0 .. targets {
+        if body.get(at .. at + 6) != Some(DFUSE_TARGET) {
+            return Err(Error::DfuSe("bad target prefix"));
+        }
+        let alt = *body.get(at + 6).ok_or(Error::DfuSe("truncated target"))?;
+        let elements = u32_at(body, at + 270).ok_or(Error::DfuSe("truncated target"))?;
+        at += 274;
+        for _ in 0 .. elements {
+            let truncated = Error::DfuSe("truncated element");
+            let addr = u32_at(body, at).ok_or(truncated.clone())?;
+            let size = u32_at(body, at + 4).ok_or(truncated.clone())? as usize;
+            let end = (at + 8).checked_add(size).ok_or(truncated.clone())?;
+            let bytes = body.get(at + 8 .. end).ok_or(truncated)?;
+            at = end;
+            // Other alternate settings address option bytes or OTP
+            if alt == 0 {
+                segments.push(Segment { addr, data: bytes.to_vec() });
+            } else {
+                log::warn!("DfuSe: skipped element at 0x{:08x} for alt {}", addr, alt);
+            }
+        }
+    }
+    Ok(segments)
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Empty => write!(f, "File has no data"),
+            Error::Hex { line, reason } => write!(f, "Intel HEX line {}: {}", line, reason),
+            Error::Elf(reason) => write!(f, "ELF: {}", reason),
+            Error::DfuSe(reason) => write!(f, "DfuSe: {}", reason),
+            Error::OutOfRange { addr, len, base, end } => write!(
+                f,
+                "Segment 0x{:08x}..0x{:08x} is outside the writable area 0x{:08x}..0x{:08x}",
+                addr, *addr as u64 + *len as u64, base, end,
+            ),
+            Error::Overlap(addr) => write!(f, "Segments overlap at 0x{:08x}", addr),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BASE: u32 = 0x0800_4000;
+
+    fn layout() -> Layout {
+        Layout {
+            app_base: BASE,
+            app_size: 0x1000,
+            ram_base: 0x2000_0000,
+            ram_end: 0x2000_8000,
+            ..Layout::default()
+        }
+    }
+
+    fn seg(addr: u32, data: &[u8]) -> Segment {
+        Segment { addr, data: data.to_vec() }
+    }
+
+    fn hex_record(ty: u8, offset: u16, data: &[u8]) -> String {
+        let mut bytes = vec![data.len() as u8, (offset >> 8) as u8, offset as u8, ty];
+        bytes.extend_from_slice(data);
+        let sum = bytes.iter().fold(0u8, |acc, &b| acc.wrapping_add(b));
+        bytes.push(0u8.wrapping_sub(sum));
+        let digits: String = bytes.iter().map(|b| format!("{:02X}", b)).collect();
+        format!(":{}\n", digits)
+    }
+
+    fn hex(records: &[(u8, u16, &[u8])]) -> Vec<u8> {
+        records.iter().map(|&(ty, off, data)| hex_record(ty, off, data)).collect::<String>().into_bytes()
+    }
+
+    fn elf(phoff: u32, phnum: u16, loads: &[(u32, &[u8])]) -> Vec<u8> {
+        let mut data = vec![0u8; 52];
+        data[.. 4].copy_from_slice(ELF_MAGIC);
+        data[4] = 1;
+        data[5] = 1;
+        data[28 .. 32].copy_from_slice(&phoff.to_le_bytes());
+        data[42 .. 44].copy_from_slice(&32u16.to_le_bytes());
+        data[44 .. 46].copy_from_slice(&phnum.to_le_bytes());
+        let mut offset = 52 + 32 * loads.len() as u32;
+        let mut contents = Vec::new();
+        for (paddr, bytes) in loads {
+            let mut ph = [0u8; 32];
+            ph[0 .. 4].copy_from_slice(&PT_LOAD.to_le_bytes());
+            ph[4 .. 8].copy_from_slice(&offset.to_le_bytes());
+            // Run address differs from the load address for .data
+            ph[8 .. 12].copy_from_slice(&0x2000_0000u32.to_le_bytes());
+            ph[12 .. 16].copy_from_slice(&paddr.to_le_bytes());
+            ph[16 .. 20].copy_from_slice(&(bytes.len() as u32).to_le_bytes());
+            data.extend_from_slice(&ph);
+            contents.extend_from_slice(bytes);
+            offset += bytes.len() as u32;
+        }
+        data.extend_from_slice(&contents);
+        data
+    }
+
+    fn dfuse(elements: &[(u8, u32, &[u8])]) -> Vec<u8> {
+        let mut data = DFUSE_PREFIX.to_vec();
+        data.push(0x01);
+        data.extend_from_slice(&[0; 4]);
+        data.push(elements.len() as u8);
+        for &(alt, addr, bytes) in elements {
+            data.extend_from_slice(DFUSE_TARGET);
+            data.push(alt);
+            data.extend_from_slice(&[0; 4 + 255 + 4]);
+            data.extend_from_slice(&1u32.to_le_bytes());
+            data.extend_from_slice(&addr.to_le_bytes());
+            data.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+            data.extend_from_slice(bytes);
+        }
+        data.extend_from_slice(&[0xFF, 0xFF, 0xDA, 0xDE, 0x83, 0x04, 0x1A, 0x01]);
+        data.extend_from_slice(b"UFD");
+        data.push(DFUSE_SUFFIX_LEN as u8);
+        let crc = !image::crc32(&data);
+        data.extend_from_slice(&crc.to_le_bytes());
+        data
+    }
+
+    #[test]
+    fn raw_binary_starts_at_base() {
+        let fw = Firmware::parse(&[1, 2, 3], &layout()).unwrap();
+        assert_eq!(fw.format, Format::Bin);
+        assert_eq!(fw.base, BASE);
+        assert_eq!(fw.segments.len(), 1);
+        assert_eq!((fw.segments[0].addr, fw.size()), (BASE, 3));
+    }
+
+    #[test]
+    fn hex_with_extended_address() {
+        let data = hex(&[
+            (0x04, 0, &[0x08, 0x00]),
+            (0x00, 0x4000, &[1, 2]),
+            (0x00, 0x4002, &[3]),
+            (0x00, 0x4010, &[4]),
+            (0x05, 0, &[0x08, 0x00, 0x40, 0x41]),
+            (0x01, 0, &[]),
+        ]);
+        let fw = Firmware::parse(&data, &layout()).unwrap();
+        assert_eq!(fw.format, Format::Hex);
+        assert_eq!(fw.segments.len(), 2);
+        assert_eq!((fw.segments[0].addr, fw.segments[0].data.clone()), (BASE, vec![1, 2, 3]));
+        assert_eq!(fw.segments[1].addr, BASE + 0x10);
+    }
+
+    #[test]
+    fn hex_errors() {
+        let mut data = hex(&[(0x04, 0, &[0x08, 0x00]), (0x00, 0x4000, &[1]), (0x01, 0, &[])]);
+        // Last checksum digit of the data record
+        let at = data.iter().enumerate().filter(|(_, &b)| b == b'\n').nth(1).unwrap().0 - 1;
+        data[at] = if data[at] == b'0' { b'1' } else { b'0' };
+        assert!(matches!(Firmware::parse(&data, &layout()), Err(Error::Hex { line: 2, reason: "bad checksum" })));
+
+        // Truncated, no end of file record
+        let data = hex(&[(0x04, 0, &[0x08, 0x00]), (0x00, 0x4000, &[1])]);
+        assert!(matches!(Firmware::parse(&data, &layout()), Err(Error::Hex { line: 0, .. })));
+
+        let data = b":0100".to_vec();
+        assert!(matches!(Firmware::parse(&data, &layout()), Err(Error::Hex { line: 1, .. })));
+
+        let data = hex(&[(0x04, 0, &[0xFF, 0xFF]), (0x00, 0xFFFF, &[1, 2]), (0x01, 0, &[])]);
+        assert!(matches!(Firmware::parse(&data, &layout()), Err(Error::OutOfRange { .. })));
+    }
+
+    #[test]
+    fn elf_load_addresses() {
+        let data = elf(52, 2, &[(BASE, &[1, 2, 3, 4]), (BASE + 4, &[5])]);
+        let fw = Firmware::parse(&data, &layout()).unwrap();
+        assert_eq!(fw.format, Format::Elf);
+        assert_eq!(fw.segments.len(), 1);
+        assert_eq!(fw.flatten(), vec![1, 2, 3, 4, 5]);
+    }
+
+    #[test]
+    fn elf_errors() {
+        // Program header table past the end of the file
+        let data = elf(52, 3, &[(BASE, &[1])]);
+        assert!(matches!(Firmware::parse(&data, &layout()), Err(Error::Elf(_))));
+
+        let data = elf(u32::MAX - 8, 1, &[(BASE, &[1])]);
+        assert!(matches!(Firmware::parse(&data, &layout()), Err(Error::Elf(_))));
+
+        let mut data = elf(52, 1, &[(BASE, &[1])]);
+        data[52 + 16 .. 52 + 20].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(matches!(Firmware::parse(&data, &layout()), Err(Error::Elf("segment beyond end of file"))));
+
+        let mut data = elf(52, 1, &[(BASE, &[1])]);
+        data[4] = 2;
+        assert!(matches!(Firmware::parse(&data, &layout()), Err(Error::Elf(_))));
+    }
+
+    #[test]
+    fn dfuse_elements() {
+        let data = dfuse(&[(0, BASE, &[1, 2]), (1, 0x1FFF_7800, &[0xAA])]);
+        let fw = Firmware::parse(&data, &layout()).unwrap();
+        assert_eq!(fw.format, Format::DfuSe);
+        // Option bytes of alt 1 are not flashed
+        assert_eq!(fw.segments.len(), 1);
+        assert_eq!(fw.flatten(), vec![1, 2]);
+    }
+
+    #[test]
+    fn dfuse_errors() {
+        let mut data = dfuse(&[(0, BASE, &[1, 2])]);
+        data[20] ^= 1;
+        assert!(matches!(Firmware::parse(&data, &layout()), Err(Error::DfuSe("suffix CRC mismatch"))));
+
+        // Element size past the end, CRC fixed up so parsing gets there
+        let mut data = dfuse(&[(0, BASE, &[1, 2])]);
+        let size_at = 11 + 274 + 4;
+        data[size_at .. size_at + 4].copy_from_slice(&u32::MAX.to_le_bytes());
+        let crc_at = data.len() - 4;
+        let crc = !image::crc32(&data[.. crc_at]);
+        data[crc_at ..].copy_from_slice(&crc.to_le_bytes());
+        assert!(matches!(Firmware::parse(&data, &layout()), Err(Error::DfuSe("truncated element"))));
+
+        let data = DFUSE_PREFIX.to_vec();
+        assert!(matches!(Firmware::parse(&data, &layout()), Err(Error::DfuSe("file too short"))));
+    }
+
+    #[test]
+    fn check_merges_and_rejects() {
+        let merged = check(vec![seg(BASE + 2, &[3]), seg(BASE, &[1, 2])], &layout()).unwrap();
+        assert_eq!(merged.len(), 1);
+        assert_eq!(merged[0].data, vec![1, 2, 3]);
+
+        assert!(matches!(check(vec![seg(BASE, &[1, 2]), seg(BASE + 1, &[3])], &layout()), Err(Error::Overlap(a)) if a == BASE + 1));
+        assert!(matches!(check(vec![seg(BASE - 1, &[1])], &layout()), Err(Error::OutOfRange { .. })));
+        assert!(matches!(check(vec![seg(BASE + 0xFFF, &[1, 2])], &layout()), Err(Error::OutOfRange { .. })));
+        assert!(matches!(check(vec![seg(BASE, &[])], &layout()), Err(Error::Empty)));
+    }
+
+    #[test]
+    fn flatten_erases_gaps() {
+        let fw = Firmware {
+            format: Format::Hex,
+            base: BASE,
+            segments: vec![seg(BASE + 1, &[1]), seg(BASE + 4, &[2])],
+        };
+        assert_eq!(fw.flatten(), vec![0xFF, 1, 0xFF, 0xFF, 2]);
+    }
+}
@@ -0,0 +1,282 @@
+use std::collections::BTreeMap;
+use std::convert::TryInto;
+
+use serde::{Deserialize, Deserializer};
+
+// Initial stack pointer plus the architectural exceptions
+const MIN_SIZE: usize = 16 * 4;
+
+/// Memory map and image records of a target, `layout` of its device profile
+///
+/// Must follow the firmware linker script, nothing here is guessed from the image.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct Layout {
+    // Application area the loader writes, image offset 0 lies here
+    #[serde(deserialize_with = "hex_u32")]
+    pub app_base: u32,
+    #[serde(deserialize_with = "hex_u32")]
+    pub app_size: u32,
+    #[serde(deserialize_with = "hex_u32")]
+    pub ram_base: u32,
+    #[serde(deserialize_with = "hex_u32")]
+    pub ram_end: u32,
+    // Build info record: magic, then NUL terminated "key=value" strings, then an empty string
+    #[serde(default)]
+    pub build_magic: Option<String>,
+    // Trailer: magic, then CRC-32 of everything before it, LE
+    #[serde(default)]
+    pub crc_magic: Option<String>,
+}
+
+impl Layout {
+    pub fn app_end(&self) -> u64 {
+        self.app_base as u64 + self.app_size as u64
+    }
+}
+
+/// Fields reported by `/build/*`
+pub const BUILD_FIELDS: &[&str] = &[
+    "version", "compiler", "git", "timestamp", "target", "host", "profile", "opt_lvl", "debug",
+];
+
+#[derive(Debug, Clone)]
+pub enum Error {
+    Empty,
+    TooSmall(usize),
+    TooLarge(usize),
+    BadStack(u32),
+    BadReset(u32),
+    BadCrc { expected: u32, actual: u32 },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Crc {
+    Ok(u32),
+    // Image has no trailer or the target doesn't use one, CRC-32 of the whole image
+    Missing(u32),
+}
+
+/// What is known about an image before it is written
+#[derive(Debug, Clone)]
+pub struct Info {
+    pub size: usize,
+    pub capacity: usize,
+    pub stack: u32,
+    pub reset: u32,
+    pub crc: Crc,
+    pub build: BTreeMap<String, String>,
+}
+
+impl Info {
+    pub fn version(&self) -> Option<&str> {
+        self.build.get("version").map(|s| s.as_str())
+    }
+}
+
+/// Checks a flat application image against the target layout
+pub fn inspect(data: &[u8], layout: &Layout) -> Result<Info, Error> {
+    if data.is_empty() {
+        return Err(Error::Empty);
+    }
+    if data.len() < MIN_SIZE {
+        return Err(Error::TooSmall(data.len()));
+    }
+    if data.len() > layout.app_size as usize {
+        return Err(Error::TooLarge(data.len()));
+    }
+
+    // Cortex-M vector table: stack top in RAM, thumb reset handler inside the image
+    let stack = word(data, 0);
+    if stack < layout.ram_base || stack > layout.ram_end || stack & 0x3 != 0 {
+        return Err(Error::BadStack(stack));
+    }
+    let reset = word(data, 4);
+    let image_end = layout.app_base as u64 + data.len() as u64;
+    if reset & 1 == 0 || reset & !1 < layout.app_base || (reset & !1) as u64 >= image_end {
+        return Err(Error::BadReset(reset));
+    }
+
+    let trailer = layout.crc_magic.as_ref().map(|m| m.as_bytes());
+    let crc = match (trailer, data.len().checked_sub(8)) {
+        (Some(magic), Some(at)) if magic.len() == 4 && &data[at .. at + 4] == magic => {
+            let expected = word(data, at + 4);
+            let actual = crc32(&data[.. at]);
+            if expected != actual {
+                return Err(Error::BadCrc { expected, actual });
+            }
+            Crc::Ok(actual)
+        }
+        _ => Crc::Missing(crc32(data)),
+    };
+
+    let build = match &layout.build_magic {
+        Some(magic) => build_info(data, magic.as_bytes()),
+        None => BTreeMap::new(),
+    };
+
+    Ok(Info {
+        size: data.len(),
+        capacity: layout.app_size as usize,
+        stack,
+        reset,
+        crc,
+        build,
+    })
+}
+
+fn word(data: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes(data[at .. at + 4].try_into().unwrap())
+}
+
+fn build_info(data: &[u8], magic: &[u8]) -> BTreeMap<String, String> {
+    let mut info = BTreeMap::new();
+    if magic.is_empty() {
+        return info;
+    }
+    let start = match data.windows(magic.len()).position(|w| w == magic) {
+        Some(pos) => pos + magic.len(),
+        None => return info,
+    };
+    for entry in data[start ..].split(|&b| b == 0) {
+        if entry.is_empty() {
+            break;
+        }
+        let entry = String::from_utf8_lossy(entry);
+        let mut kv = entry.splitn(2, '=');
+        if let (Some(k), Some(v)) = (kv.next(), kv.next()) {
+            if BUILD_FIELDS.contains(&k) {
+                info.insert(k.to_string(), v.to_string());
+            }
+        }
+    }
+    info
+}
+
+/// CRC-32/ISO-HDLC, also used by the DFU file suffix
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &b in data {
+        crc ^= b as u32;
+        for _ in 0 .. 8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Empty => write!(f, "Image is empty"),
+            Error::TooSmall(n) => write!(f, "Image is too small for a vector table: {} bytes", n),
+            Error::TooLarge(n) => write!(f, "Image of {} bytes exceeds the application area", n),
+            Error::BadStack(v) => write!(f, "Initial stack pointer 0x{:08x} is outside RAM, not an image for this target", v),
+            Error::BadReset(v) => write!(f, "Reset vector 0x{:08x} is outside the image, not an image for this target", v),
+            Error::BadCrc { expected, actual } => write!(f, "CRC mismatch: image says 0x{:08x}, computed 0x{:08x}", expected, actual),
+        }
+    }
+}
+
+fn hex_u32<'de, D: Deserializer<'de>>(d: D) -> Result<u32, D::Error> {
+    let s = String::deserialize(d)?;
+    let digits = s.trim_start_matches("0x").trim_start_matches("0X");
+    u32::from_str_radix(digits, 16).map_err(serde::de::Error::custom)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn layout() -> Layout {
+        Layout {
+            app_base: 0x0800_4000,
+            app_size: 0x1000,
+            ram_base: 0x2000_0000,
+            ram_end: 0x2000_8000,
+            build_magic: Some("BUILD\0".into()),
+            crc_magic: Some("CRC3".into()),
+        }
+    }
+
+    fn image(len: usize) -> Vec<u8> {
+        let mut data = vec![0u8; len];
+        data[0 .. 4].copy_from_slice(&0x2000_8000u32.to_le_bytes());
+        data[4 .. 8].copy_from_slice(&0x0800_4041u32.to_le_bytes());
+        data
+    }
+
+    fn with_crc(mut data: Vec<u8>) -> Vec<u8> {
+        let crc = crc32(&data);
+        data.extend_from_slice(b"CRC3");
+        data.extend_from_slice(&crc.to_le_bytes());
+        data
+    }
+
+    #[test]
+    fn crc32_check_value() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+    }
+
+    #[test]
+    fn accepts_vector_table() {
+        let info = inspect(&image(0x100), &layout()).unwrap();
+        assert_eq!(info.size, 0x100);
+        assert_eq!(info.capacity, 0x1000);
+        assert_eq!(info.stack, 0x2000_8000);
+        assert_eq!(info.reset, 0x0800_4041);
+        assert_eq!(info.crc, Crc::Missing(crc32(&image(0x100))));
+        assert!(info.build.is_empty());
+    }
+
+    #[test]
+    fn rejects_size() {
+        assert!(matches!(inspect(&[], &layout()), Err(Error::Empty)));
+        assert!(matches!(inspect(&image(MIN_SIZE - 4), &layout()), Err(Error::TooSmall(_))));
+        assert!(matches!(inspect(&image(0x1004), &layout()), Err(Error::TooLarge(0x1004))));
+    }
+
+    #[test]
+    fn rejects_vectors() {
+        let mut data = image(0x100);
+        data[0 .. 4].copy_from_slice(&0x0800_0000u32.to_le_bytes());
+        assert!(matches!(inspect(&data, &layout()), Err(Error::BadStack(0x0800_0000))));
+
+        let mut data = image(0x100);
+        // Even address is not a thumb entry
+        data[4 .. 8].copy_from_slice(&0x0800_4040u32.to_le_bytes());
+        assert!(matches!(inspect(&data, &layout()), Err(Error::BadReset(_))));
+
+        let mut data = image(0x100);
+        data[4 .. 8].copy_from_slice(&0x0800_4101u32.to_le_bytes());
+        assert!(matches!(inspect(&data, &layout()), Err(Error::BadReset(_))));
+    }
+
+    #[test]
+    fn checks_crc_trailer() {
+        let data = with_crc(image(0x100));
+        assert_eq!(inspect(&data, &layout()).unwrap().crc, Crc::Ok(crc32(&image(0x100))));
+
+        let mut bad = data.clone();
+        bad[0x10] ^= 1;
+        assert!(matches!(inspect(&bad, &layout()), Err(Error::BadCrc { .. })));
+
+        // Trailer means nothing to a target without one
+        let plain = Layout { crc_magic: None, ..layout() };
+        assert_eq!(inspect(&bad, &plain).unwrap().crc, Crc::Missing(crc32(&bad)));
+    }
+
+    #[test]
+    fn reads_build_info() {
+        let mut data = image(0x100);
+        let record = b"BUILD\0version=1.2.3\0git=abc\0unknown=x\0\0";
+        data[0x40 .. 0x40 + record.len()].copy_from_slice(record);
+
+        let info = inspect(&data, &layout()).unwrap();
+        assert_eq!(info.version(), Some("1.2.3"));
+        assert_eq!(info.build.get("git").map(|s| s.as_str()), Some("abc"));
+        assert_eq!(info.build.len(), 2);
+
+        let plain = Layout { build_magic: None, ..layout() };
+        assert!(inspect(&data, &plain).unwrap().build.is_empty());
+    }
+}
@@ -1,4 +1,5 @@
 
+use std::cell::RefCell;
 use std::rc::Rc;
 use wasm_bindgen::prelude::*;
 
@@ -7,6 +8,7 @@ use ellocopo2::AnswerCode;
 
 use crate::device;
 use crate::cmd;
+use crate::vis::Group;
 
 #[wasm_bindgen(module = "/public/js/StreamSaver.js")]
 extern "C" {
@@ -45,74 +47,234 @@ pub async fn download_file(filename: String, content: Vec<u8>) -> Result<(),JsVa
     Ok(())
 }
 
-pub async fn download_file_from_device(device: Rc<device::Device>, filename: &str, _cancel: &bool) -> Result<(),()> {
+pub const BLOCK_SIZE: u32 = 0x800;
+const TRANS_SIZE: u32 = 0x100_000;
+const BLOCKS_PER_TRANS: u32 = TRANS_SIZE / BLOCK_SIZE;
+
+/// Device file transfer that survives a disconnect
+pub struct FileDownload {
+    filename: String,
+    // Opened once the length is known
+    writer: Option<FileWriter>,
+    // Blocks
+    total: u32,
+    done: u32,
+    finished: bool,
+    // A transfer is feeding the writer right now
+    running: bool,
+}
 
-    async fn trans_start_cmds(device: Rc<device::Device>) -> Result<u32,()> {
-        let _ = cmd(&device, DevMsg(AnswerCode::OK_WRITE, String::from("/io/file/pos"), Value::U32(0)))
-            .await?;
+impl FileDownload {
+    pub fn new(filename: &str) -> Self {
+        Self {
+            filename: filename.to_string(),
+            writer: None,
+            total: 0,
+            done: 0,
+            finished: false,
+            running: false,
+        }
+    }
+
+    pub fn is_started(&self) -> bool {
+        self.writer.is_some() || self.finished
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.finished
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.running
+    }
+
+    /// Blocks received and expected
+    pub fn progress(&self) -> (u32, u32) {
+        (self.done, self.total)
+    }
+}
+
+/// Downloads the device file, continues from the last block if `dl` was interrupted
+///
+/// Only one transfer feeds a download at a time, a second call fails right away.
+pub async fn download_file_from_device(device: Rc<device::Device>, dl: Rc<RefCell<FileDownload>>) -> Result<(),()> {
+    if dl.borrow().running {
+        log::warn!("Download of {} already running", dl.borrow().filename);
+        return Err(());
+    }
+    dl.borrow_mut().running = true;
+    let r = transfer(device, Rc::clone(&dl)).await;
+    dl.borrow_mut().running = false;
+    r
+}
 
-        let len = cmd(&device, DevMsg(AnswerCode::OK_READ, String::from("/io/file/len"), Value::UNIT(())))
+async fn transfer(device: Rc<device::Device>, dl: Rc<RefCell<FileDownload>>) -> Result<(),()> {
+
+    async fn trans_start_cmds(device: Rc<device::Device>, pos: u32, len: Option<u32>) -> Result<u32,()> {
+        let _ = cmd(&device, DevMsg(AnswerCode::OK_WRITE, String::from("/io/file/pos"), Value::U32(pos)))
             .await?;
 
-        let block_cnt = if let Value::U32(_block_cnt) = len {
-            20_000
-        } else { unimplemented!() };
+        let block_cnt = match len {
+            Some(len) => len,
+            None => {
+                let len = cmd(&device, DevMsg(AnswerCode::OK_READ, String::from("/io/file/len"), Value::UNIT(())))
+                    .await?;
+
+                if let Value::U32(_block_cnt) = len {
+                    20_000
+                } else {
+                    log::error!("Unexpected /io/file/len answer {:?}", len);
+                    return Err(());
+                }
+            }
+        };
 
         let _ = cmd(&device, DevMsg(AnswerCode::OK_WRITE, String::from("/io/file/len"), Value::U32(block_cnt)))
-            .await
-            .unwrap();
+            .await?;
     
         let _ = cmd(&device, DevMsg(AnswerCode::OK_WRITE, String::from("/io/file/start"), Value::UNIT(())))
-            .await
-            .unwrap();
+            .await?;
 
         Ok(block_cnt)
     }
 
-    const TRANS_SIZE: u32 = 0x100_000;
-    const BLOCKS_PER_TRANS: u32 = TRANS_SIZE / 0x800;
-
-    log::info!("Performing download");
-
-    let block_cnt = trans_start_cmds(device.clone())
-        .await?;
-    let file_writer = FileWriter::new(filename, Some(block_cnt * 0x800));
-    let (trans_cnt, rem_blck_cnt) = (block_cnt / BLOCKS_PER_TRANS, block_cnt % BLOCKS_PER_TRANS);
+    let (started, done, total) = {
+        let dl = dl.borrow();
+        (dl.is_started(), dl.done, dl.total)
+    };
+    if started {
+        log::info!("Resuming download at block {} of {}", done, total);
+        trans_start_cmds(device.clone(), done, Some(total - done))
+            .await?;
+    } else {
+        log::info!("Performing download");
+        let block_cnt = trans_start_cmds(device.clone(), 0, None)
+            .await?;
+        let mut dl = dl.borrow_mut();
+        dl.writer = Some(FileWriter::new(&dl.filename, Some(block_cnt * BLOCK_SIZE)));
+        dl.total = block_cnt;
+    }
 
-    for i in 0 .. trans_cnt {
-        let buf = device.recv_file_block(TRANS_SIZE).await.unwrap();
-        log::info!("recv_file_block res {:?}", buf.len());
-        log::info!("{:x?}", &buf[.. delta::defs::HEADER_SZ]);
-        let mut parser = delta::block::parse::BlockParser::new();
-        let r = parser.try_open_block(&buf[..]);
-        log::info!("try_open_block res: {:?}", r);
-        log::info!("Blk {} header: {:#?}", i * TRANS_SIZE, parser.header());
+    loop {
+        let (done, total) = dl.borrow().progress();
+        if done >= total {
+            break;
+        }
+        let (size, blocks) = if total - done >= BLOCKS_PER_TRANS {
+            (TRANS_SIZE, BLOCKS_PER_TRANS)
+        } else {
+            (BLOCK_SIZE, 1)
+        };
+
+        // Kept for resuming, the device may be back soon
+        let buf = device.recv_file_block(size).await.map_err(|e| {
+            log::error!("Download interrupted at block {}: {:?}", done, e);
+        })?;
+        if buf.len() != size as usize {
+            log::error!("Wrong buf len {}", buf.len());
+        }
+        if blocks > 1 {
+            let mut parser = delta::block::parse::BlockParser::new();
+            let r = parser.try_open_block(&buf[..]);
+            log::info!("Blk {} open: {:?}, header: {:#?}", done, r, parser.header());
+        }
 
-        let promise = file_writer.write(&buf);
+        let promise = {
+            let mut dl = dl.borrow_mut();
+            dl.done += blocks;
+            dl.writer.as_ref().ok_or(())?.write(&buf)
+        };
         let _ = wasm_bindgen_futures::JsFuture::from(promise)
             .await
             .map_err(|_| ())?;
     }
 
-    if rem_blck_cnt != 0 {
-        for _ in 0 .. rem_blck_cnt {
-            let buf = device.recv_file_block(0x800).await.unwrap();
+    let mut dl = dl.borrow_mut();
+    if let Some(writer) = dl.writer.take() {
+        writer.close();
+    }
+    dl.finished = true;
 
-            if buf.len() != 0x800 {
-                log::error!("Wrong buf len {}", buf.len());
-            }
-    
-            let promise = file_writer.write(&buf);
-            let _ = wasm_bindgen_futures::JsFuture::from(promise)
-                .await
-                .map_err(|_| ())?;
+    log::info!("End::Performing download");
+
+    Ok(())
+}
+
+/// Writes the live vis stream to disk while it is displayed
+pub struct LiveRecorder {
+    raw: FileWriter,
+    csv: Option<FileWriter>,
+    // Decoded rows of the current block
+    rows: String,
+    // Per group sample counters, ECG, REO, ACC_IN
+    cnt: [u64; 3],
+}
+
+impl LiveRecorder {
+    pub fn start(decode: bool) -> Self {
+        let stamp = js_sys::Date::now() as u64;
+        let csv = if decode {
+            Some(FileWriter::new(&format!("vis-{}.csv", stamp), None))
+        } else { None };
+
+        log::info!("Live recording started");
+        Self {
+            raw: FileWriter::new(&format!("vis-{}.bin", stamp), None),
+            csv,
+            // Header goes out with the first flush
+            rows: String::from("group,index,time,values\n"),
+            cnt: [0; 3],
         }
     }
 
+    /// Raw block exactly as received from the device
+    pub async fn write_block(&mut self, blk: &[u8]) -> Result<(), JsValue> {
+        let promise = self.raw.write(blk);
+        wasm_bindgen_futures::JsFuture::from(promise).await?;
+        Ok(())
+    }
 
-    file_writer.close();
+    pub fn push_sample(&mut self, group: Group, sample: &[i32]) {
+        use std::fmt::Write;
 
-    log::info!("End::Performing download");
+        if self.csv.is_none() {
+            return;
+        }
+        // Time from the profile rate, the raw file keeps the exact stream
+        let cnt = &mut self.cnt[group as usize];
+        let values: Vec<String> = sample.iter().map(|v| v.to_string()).collect();
+        let _ = writeln!(
+            self.rows,
+            "{:?},{},{:.4},{}",
+            group, cnt, *cnt as f32 / group.sample_rate(), values.join(";"),
+        );
+        *cnt += 1;
+    }
 
-    Ok(())
-}
\ No newline at end of file
+    /// Writes decoded rows collected since the last flush
+    pub async fn flush(&mut self) -> Result<(), JsValue> {
+        if let Some(csv) = &self.csv {
+            if !self.rows.is_empty() {
+                let promise = csv.write(self.rows.as_bytes());
+                self.rows.clear();
+                wasm_bindgen_futures::JsFuture::from(promise).await?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn finish(self) {
+        self.raw.close();
+        if let Some(csv) = self.csv {
+            csv.close();
+        }
+        log::info!("Live recording finished");
+    }
+
+    pub fn abort(self) {
+        self.raw.abort();
+        if let Some(csv) = self.csv {
+            csv.abort();
+        }
+    }
+}
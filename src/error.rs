@@ -0,0 +1,174 @@
+use std::collections::VecDeque;
+
+use crate::dbg;
+use crate::device;
+use crate::dfu;
+
+// Oldest notifications are dropped past this
+const MAX_NOTIFICATIONS: usize = 50;
+
+/// Anything that goes wrong and is worth telling the user about
+#[derive(Debug, Clone)]
+pub enum Error {
+    Device(device::Error),
+    // Device answered with an error code
+    Protocol { path: String, code: u32 },
+    // Answer the scheme does not explain
+    Answer { path: String, reason: String },
+    Scheme(String),
+    // Value typed by the user does not fit the register
+    Input(String),
+    Dfu(dfu::Error),
+    Firmware(String),
+    File(String),
+    Config(String),
+    // Trace canvas could not be set up or stopped taking frames
+    Render(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Severity {
+    Info,
+    Warning,
+    Error,
+}
+
+impl Error {
+    pub fn severity(&self) -> Severity {
+        match self {
+            Error::Device(device::Error::NotSelected) | Error::Dfu(dfu::Error::Cancelled) => Severity::Info,
+            Error::Device(device::Error::Security)
+            | Error::Device(device::Error::ReplayEnd)
+            | Error::Dfu(dfu::Error::NoReadback)
+            | Error::Input(_) => Severity::Warning,
+            _ => Severity::Error,
+        }
+    }
+
+    /// Short text for the notification list
+    pub fn message(&self) -> String {
+        match self {
+            Error::Device(e) => match e {
+                device::Error::NotConnected => "Device is not connected".into(),
+                device::Error::NotSelected => "No device selected".into(),
+                device::Error::Security => "The browser denied access to the device".into(),
+                device::Error::DevTypeApi => "Not available in this device mode".into(),
+                device::Error::EpStall => "The device rejected the transfer".into(),
+                device::Error::UnknownDevice { vid, pid } => {
+                    format!("Unknown device {:04x}:{:04x}, add it to devices.json", vid, pid)
+                }
+                device::Error::BadCapture(reason) => format!("Session capture is damaged: {}", reason),
+                device::Error::ReplayEnd => "Session capture ended".into(),
+                device::Error::Protocol(_) => "The device answer breaks the protocol".into(),
+                device::Error::Timeout => "The device did not answer in time".into(),
+                device::Error::DomExp(_) | device::Error::RawJs(_) => "Device communication error".into(),
+            },
+            Error::Protocol { path, code } => format!("{}: device error {}", path, dbg::decode(*code)),
+            Error::Answer { path, .. } if path.is_empty() => "Unexpected device answer".into(),
+            Error::Answer { path, .. } => format!("{}: unexpected device answer", path),
+            Error::Scheme(_) => "Failed to load the register scheme".into(),
+            Error::Input(e) => format!("Invalid value: {}", e),
+            Error::Dfu(dfu::Error::NoReadback) => "Firmware written, the loader restarted before the check".into(),
+            Error::Dfu(e) => format!("DFU error: {}", e),
+            Error::Firmware(e) => format!("Firmware file rejected: {}", e),
+            Error::File(e) => format!("File error: {}", e),
+            Error::Config(e) => format!("Config profile rejected: {}", e),
+            Error::Render(e) => format!("Trace view unavailable: {}", e),
+        }
+    }
+
+    /// Everything known, for support
+    pub fn details(&self) -> String {
+        match self {
+            Error::Answer { reason, .. } => reason.clone(),
+            Error::Scheme(e) | Error::Firmware(e) | Error::File(e) | Error::Config(e) | Error::Render(e) => e.clone(),
+            _ => format!("{:?}", self),
+        }
+    }
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.message())
+    }
+}
+
+impl From<device::Error> for Error {
+    fn from(e: device::Error) -> Self {
+        Error::Device(e)
+    }
+}
+
+impl From<dfu::Error> for Error {
+    fn from(e: dfu::Error) -> Self {
+        Error::Dfu(e)
+    }
+}
+
+impl From<dfu::container::Error> for Error {
+    fn from(e: dfu::container::Error) -> Self {
+        Error::Firmware(e.to_string())
+    }
+}
+
+impl From<dfu::image::Error> for Error {
+    fn from(e: dfu::image::Error) -> Self {
+        Error::Firmware(e.to_string())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Notification {
+    pub id: u32,
+    pub t: f64,
+    pub severity: Severity,
+    pub message: String,
+    pub details: Option<String>,
+}
+
+/// Non-blocking list shown on top of the page, newest last
+#[derive(Default)]
+pub struct Notifications {
+    list: VecDeque<Notification>,
+    next_id: u32,
+}
+
+impl Notifications {
+    pub fn error(&mut self, e: &Error) {
+        match e.severity() {
+            Severity::Error => log::error!("{:?}", e),
+            _ => log::warn!("{:?}", e),
+        }
+        self.push(e.severity(), e.message(), Some(e.details()));
+    }
+
+    pub fn push(&mut self, severity: Severity, message: String, details: Option<String>) {
+        if self.list.len() >= MAX_NOTIFICATIONS {
+            self.list.pop_front();
+        }
+        self.next_id += 1;
+        self.list.push_back(Notification {
+            id: self.next_id,
+            t: js_sys::Date::now(),
+            severity,
+            message,
+            details,
+        });
+    }
+
+    pub fn dismiss(&mut self, id: u32) {
+        self.list.retain(|n| n.id != id);
+    }
+
+    pub fn clear(&mut self) {
+        self.list.clear();
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Notification> {
+        self.list.iter()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.list.is_empty()
+    }
+}
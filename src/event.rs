@@ -0,0 +1,66 @@
+use ellocopo2::owned::{Msg as DevMsg, Value};
+use ellocopo2::AnswerCode;
+
+pub const EVENT_PATH: &'static str = "/ctrl/event";
+// Protocol payload limit, see `@com5` of the scheme
+pub const MAX_EVENT_LEN: usize = 256;
+
+/// `/ctrl/event` payload, the firmware defines no layout so it's kept as is
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Event {
+    pub data: Vec<u8>,
+}
+
+impl Event {
+    /// Marker with the note typed by the technician, cut to the payload limit
+    pub fn note(text: &str) -> Self {
+        let mut end = text.len().min(MAX_EVENT_LEN);
+        while !text.is_char_boundary(end) {
+            end -= 1;
+        }
+        Self {
+            data: text[.. end].as_bytes().to_vec(),
+        }
+    }
+
+    pub fn decode(buf: &[u8]) -> Self {
+        Self { data: buf.to_vec() }
+    }
+
+    /// Payload as text if it reads as one, hex otherwise
+    pub fn describe(&self) -> String {
+        match std::str::from_utf8(&self.data) {
+            Ok(s) if !s.chars().any(char::is_control) => s.to_string(),
+            _ => format!("{:02x?}", self.data),
+        }
+    }
+
+    /// Write request for `/ctrl/event`
+    pub fn request(&self) -> DevMsg {
+        DevMsg(AnswerCode::OK_WRITE, String::from(EVENT_PATH), Value::BYTES(self.data.clone()))
+    }
+}
+
+/// Event seen in the vis stream
+#[derive(Debug, Clone)]
+pub struct Record {
+    pub event: Event,
+    // Wall clock time of arrival, ms since epoch
+    pub time: f64,
+    // Position in the stream, seconds since vis start
+    pub offset: f32,
+}
+
+impl Record {
+    pub fn new(event: Event, offset: f32) -> Self {
+        Self {
+            event,
+            time: js_sys::Date::now(),
+            offset,
+        }
+    }
+
+    pub fn time_str(&self) -> String {
+        crate::local_time(self.time)
+    }
+}
@@ -0,0 +1,103 @@
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::rc::Rc;
+
+use serde_json::Value as JsonValue;
+
+use ellocopo2::owned::{Msg as DevMsg, Value};
+use ellocopo2::AnswerCode;
+
+use crate::dbg;
+use crate::device;
+use crate::download::FileDownload;
+use crate::tree;
+
+/// One connected recorder
+pub struct Slot {
+    pub device: Rc<device::Device>,
+    // Parked register tree, the active device's tree lives in the app model
+    pub tree: Option<tree::Model>,
+    pub selected: bool,
+    pub busy: bool,
+    pub status: String,
+    pub download: Option<Rc<RefCell<FileDownload>>>,
+}
+
+impl Slot {
+    pub fn new(device: Rc<device::Device>) -> Self {
+        Self {
+            device,
+            tree: Some(tree::Model::default()),
+            selected: false,
+            busy: false,
+            status: String::new(),
+            download: None,
+        }
+    }
+
+    pub fn download_interrupted(&self) -> bool {
+        self.download.as_ref().map_or(false, |dl| !dl.borrow().is_complete())
+    }
+}
+
+/// Devices keyed by serial number
+pub type Fleet = BTreeMap<String, Slot>;
+
+pub fn selected(fleet: &Fleet) -> impl Iterator<Item = (&String, &Slot)> {
+    fleet.iter().filter(|(_, s)| s.selected && s.device.is_connected())
+}
+
+/// `/time` is seconds since the epoch
+pub async fn sync_time(device: Rc<device::Device>) -> Result<String, String> {
+    let now = (js_sys::Date::now() / 1000.) as u32;
+    write(&device, "/time", Value::U32(now)).await?;
+    Ok(format!("Time {}", now))
+}
+
+/// Writes every value of a config profile, stops at the first failure
+pub async fn apply_config(device: Rc<device::Device>, writes: Vec<(String, Value)>) -> Result<String, String> {
+    for (path, value) in &writes {
+        write(&device, path, value.clone()).await?;
+    }
+    Ok(format!("{} values written", writes.len()))
+}
+
+/// Writes one register, any answer but OK_WRITE is a failure
+async fn write(device: &Rc<device::Device>, path: &str, value: Value) -> Result<(), String> {
+    let ans = device
+        .send_recv_cmd(DevMsg(AnswerCode::OK_WRITE, path.to_string(), value))
+        .await
+        .map_err(|e| format!("{} not written: {:?}", path, e))?;
+    match ans {
+        DevMsg(AnswerCode::OK_WRITE, _, _) => Ok(()),
+        DevMsg(AnswerCode::ERR_CUSTOM, _, Value::U32(code)) => Err(format!("{} not written: {}", path, dbg::decode(code))),
+        DevMsg(code, _, _) => Err(format!("{} not written: answer {:?}", path, code)),
+    }
+}
+
+/// Flattens `{"conf": {"time": 1}}` or `{"/conf/time": 1}` into typed writes
+pub fn config_writes(profile: &JsonValue, tree: &tree::Model) -> Result<Vec<(String, Value)>, String> {
+    fn walk(prefix: &str, v: &JsonValue, out: &mut Vec<(String, String)>) {
+        match v {
+            JsonValue::Object(map) => {
+                for (k, v) in map {
+                    // Scheme style comments and attributes
+                    if k.starts_with('@') {
+                        continue;
+                    }
+                    let path = format!("{}/{}", prefix, k.trim_start_matches('/'));
+                    walk(&path, v, out);
+                }
+            }
+            JsonValue::String(s) => out.push((prefix.to_string(), s.clone())),
+            v => out.push((prefix.to_string(), v.to_string())),
+        }
+    }
+
+    let mut texts = Vec::new();
+    walk("", profile, &mut texts);
+    texts
+        .into_iter()
+        .map(|(path, text)| tree.parse_value(&path, &text).map(|v| (path, v)))
+        .collect()
+}
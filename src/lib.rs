@@ -1,6 +1,6 @@
 
 use std::rc::Rc;
-use std::cell::Cell;
+use std::cell::{Cell, RefCell};
 use std::sync::atomic::{AtomicBool, Ordering};
 
 //#[macro_use]
@@ -16,6 +16,18 @@ mod cfg;
 mod tree;
 mod vis;
 mod download;
+mod event;
+mod analysis;
+mod dfu;
+mod update;
+mod fleet;
+mod selftest;
+mod dbg;
+mod error;
+mod link;
+
+use device::registry::{Feature, Registry};
+use device::{capture, inspect};
 
 #[derive(Default)]
 struct Model {
@@ -23,7 +35,67 @@ struct Model {
     device: Rc<device::Device>,
     vis: Rc<AtomicBool>,
     vis_group: Rc<Cell<VisSelectedGroup>>,
-    upload_data: Option<Vec<u8>>,
+    vis_quality: Rc<RefCell<Vec<vis::quality::ChannelQuality>>>,
+    vis_events: Rc<RefCell<Vec<event::Record>>>,
+    vis_ctl: Rc<RefCell<vis::Control>>,
+    vis_history: Rc<RefCell<vis::history::Store>>,
+    vis_stats: Rc<RefCell<vis::stats::LinkStats>>,
+    vis_record: Rc<Cell<VisRecordMode>>,
+    // Text of the next event marker
+    event_note: String,
+    vis_acc: Rc<RefCell<analysis::acc::State>>,
+    acc_timeline: Vec<analysis::Segment<(analysis::acc::Position, analysis::acc::Activity)>>,
+    vis_reo: Rc<RefCell<analysis::reo::State>>,
+    reo_report: Option<analysis::reo::Report>,
+    upload_data: Option<dfu::container::Firmware>,
+    upload_info: Option<dfu::image::Info>,
+    dfu_progress: Option<dfu::Progress>,
+    dfu_cancel: Rc<Cell<bool>>,
+    dfu_caps: Option<dfu::Functional>,
+    update: Option<update::Update>,
+    registry: Rc<Registry>,
+    // Serial of the active device, re-attached when it shows up again
+    last_serial: Option<String>,
+    fleet: fleet::Fleet,
+    inspector: bool,
+    inspector_filter: String,
+    selftest_running: bool,
+    selftest: Option<selftest::Report>,
+    dbg: dbg::Panel,
+    notes: error::Notifications,
+    link: link::Link,
+    heartbeat: bool,
+}
+
+/// Shared state handed to the vis stream task
+struct VisCtx {
+    run: Rc<AtomicBool>,
+    group: Rc<Cell<VisSelectedGroup>>,
+    quality: Rc<RefCell<Vec<vis::quality::ChannelQuality>>>,
+    events: Rc<RefCell<Vec<event::Record>>>,
+    ctl: Rc<RefCell<vis::Control>>,
+    history: Rc<RefCell<vis::history::Store>>,
+    stats: Rc<RefCell<vis::stats::LinkStats>>,
+    record: Rc<Cell<VisRecordMode>>,
+    acc: Rc<RefCell<analysis::acc::State>>,
+    reo: Rc<RefCell<analysis::reo::State>>,
+}
+
+impl Model {
+    fn vis_ctx(&self) -> VisCtx {
+        VisCtx {
+            run: Rc::clone(&self.vis),
+            group: Rc::clone(&self.vis_group),
+            quality: Rc::clone(&self.vis_quality),
+            events: Rc::clone(&self.vis_events),
+            ctl: Rc::clone(&self.vis_ctl),
+            history: Rc::clone(&self.vis_history),
+            stats: Rc::clone(&self.vis_stats),
+            record: Rc::clone(&self.vis_record),
+            acc: Rc::clone(&self.vis_acc),
+            reo: Rc::clone(&self.vis_reo),
+        }
+    }
 }
 
 #[derive(Clone)]
@@ -33,14 +105,70 @@ enum Msg {
     AutoConnect,
     DevConnected(Rc<device::Device>),
     //NewDevice(Rc<HolterDevice>),
-    CfgLoaded(String),
+    CfgLoaded(Result<String, error::Error>),
     DownloadFile,
     VisStart,
     VisUpdate,
+    VisTick,
     VisSelectedGroup(VisSelectedGroup),
+    VisRecordMode(VisRecordMode),
+    EventNote(String),
+    MarkEvent,
+    ExportStats,
+    VisPause,
+    VisScroll(f32),
+    VisCursor(f32, f32),
+    VisClearCursors,
+    OpenRecording(web_sys::Event),
+    RecordingLoaded(Rc<Recording>),
     DfuUploadFirmware(web_sys::Event),
     DfuDownloadFirmware,
     UploadFileCompleted(Vec<u8>),
+    DfuConfirm,
+    DfuCancel,
+    DfuProgress(dfu::Progress),
+    DfuAbort,
+    DfuFinished(Result<(), error::Error>),
+    DfuCaps(Option<dfu::Functional>),
+    RegistryLoaded(Rc<Registry>),
+    UsbAttached(device::Desc),
+    UsbDetached(device::Desc),
+    DownloadOn(String),
+    DownloadStopped(String),
+    DevReattached(Rc<device::Device>),
+    DeviceActivate(String),
+    DeviceSelect(String),
+    BulkSyncTime,
+    BulkDownload,
+    BulkConfig(web_sys::Event),
+    BulkConfigLoaded(Vec<u8>),
+    BulkStatus(String, Result<String, String>),
+    InspectorToggle,
+    InspectorFilter(String),
+    InspectorPause,
+    InspectorClear,
+    InspectorExport,
+    Failed(error::Error),
+    HeartbeatTick,
+    HeartbeatDone(Result<f64, device::Error>),
+    Dismiss(u32),
+    DismissAll,
+    DbgRead,
+    DbgLoaded(Result<dbg::Snapshot, String>),
+    DbgClear,
+    SelfTestRun,
+    SelfTestDone(selftest::Report),
+    SelfTestExport,
+    CaptureToggle,
+    OpenCapture(web_sys::Event),
+    CaptureLoaded(Vec<u8>),
+    UpdateWait,
+    UpdateStep(update::Step),
+    UpdateFound(Rc<device::Device>),
+    UpdateNotFound,
+    UpdateSelect,
+    UpdateFlashed(Result<(), String>),
+    UpdateDone(Result<String, String>),
 }
 
 // dev: wasm-pack build --target web --out-name package --dev
@@ -60,34 +188,43 @@ fn update(msg: Msg, model: &mut Model, orders: &mut impl Orders<Msg>) {
                         Some(Msg::Tree(tree::Msg::GAnswerUpdate(Ok(msg))))
                     },
                     Err(e) => {
-                        log::error!("{:?}", e);
                         log::info!("End::Performing cmd");
-                        Some(Msg::Connect)
+                        // The heartbeat tells a lost device from one failed request
+                        Some(Msg::Failed(e.into()))
                     }
                 }
             });
         }
+        Msg::Tree(tree::Msg::Failed(e)) => {
+            model.notes.error(&e);
+        }
         Msg::Tree(msg) => { 
+            if let tree::Msg::GAnswerUpdate(Ok(DevMsg(AnswerCode::ERR_CUSTOM, path, Value::U32(code)))) = &msg {
+                let serial = model.last_serial.clone().unwrap_or_default();
+                model.dbg.answer_error(js_sys::Date::now(), &serial, path.clone(), *code);
+                orders.send_msg(Msg::DbgRead);
+            }
             tree::update(msg, &mut model.treee, &mut orders.proxy(Msg::Tree));
         }
         Msg::Connect => {
             log!("Connect pressed");
+            model.link.connecting();
+            let registry = Rc::clone(&model.registry);
             orders
-                .perform_cmd(async {
-                    let r = device::Device::request_device()
+                .perform_cmd(async move {
+                    let r = device::Device::request_device(&registry)
                         .await;
                     use device::Error;
                     match r {
                         Ok(dev) => Some(Msg::DevConnected(Rc::new(dev))),
 
+                        Err(e @ Error::UnknownDevice { .. }) => Some(Msg::Failed(e.into())),
+
                         Err(e @ Error::NotSelected) => { 
                             log::info!("{:?}", e);
                             None
                         }
-                        Err(e @ Error::Security) => { 
-                            log::error!("{:?}", e);
-                            None
-                        }
+                        Err(e @ Error::Security) => Some(Msg::Failed(e.into())),
                         Err(e) => {
                             log::error!("{:?}", e);
                             Some(Msg::AutoConnect)
@@ -96,38 +233,411 @@ fn update(msg: Msg, model: &mut Model, orders: &mut impl Orders<Msg>) {
                 });
         }
         Msg::AutoConnect => {
-            orders.perform_cmd(async {auto_connect().await});
+            let registry = Rc::clone(&model.registry);
+            let serial = model.last_serial.clone();
+            orders.perform_cmd(auto_connect(registry, serial));
+        }
+        Msg::UsbAttached(desc) => {
+            log::info!("USB attached: {}", desc);
+            let busy = model.update.as_ref().map_or(false, |u| u.step.is_running());
+            let serial = desc.serial().to_string();
+            let background = model.last_serial.as_ref() != Some(&serial)
+                && model.fleet.get(&serial).map_or(false, |s| !s.device.is_connected());
+            if background {
+                let registry = Rc::clone(&model.registry);
+                orders.perform_cmd(async move {
+                    match device::Device::find_permitted(&registry, |_, d| d.serial() == serial).await {
+                        Ok(Some(dev)) => Some(Msg::DevReattached(Rc::new(dev))),
+                        _ => None,
+                    }
+                });
+            } else if !model.device.is_connected() && !busy {
+                orders.send_msg(Msg::AutoConnect);
+            }
+        }
+        Msg::UsbDetached(desc) => {
+            for slot in model.fleet.values_mut() {
+                if slot.device.descriptor().map_or(false, |d| d.same_unit(&desc)) {
+                    log::warn!("Device {} unplugged, waiting for it to come back", desc.serial());
+                    slot.device.detach();
+                    slot.busy = false;
+                }
+            }
+            if model.device.descriptor().map_or(false, |d| d.same_unit(&desc)) {
+                model.device.detach();
+            }
         }
         Msg::DevConnected(dev) => {
+            let serial = dev.descriptor().map(|d| d.serial().to_string()).unwrap_or_default();
 
             if model.device.is_reconnecting(&dev) {
                 log::info!("Device reconnected");
+                if dev.has(Feature::Tree) {
+                    orders.send_msg(Msg::Tree(tree::Msg::Refresh));
+                }
             } else {
                 log::info!("New device connected");
-                let desc = dev.descriptor().unwrap().clone();
-                orders.perform_cmd(async {
-                    let scheme = cfg::load(desc).await;
-                    Msg::CfgLoaded(scheme)
-                });
+                park_tree(model);
+                let parked = model.fleet.get_mut(&serial).and_then(|s| s.tree.take());
+                match parked {
+                    // Known recorder, its tree is still there
+                    Some(tree) if dev.has(Feature::Tree) && !tree.is_empty() => {
+                        model.treee = tree;
+                        orders.send_msg(Msg::Tree(tree::Msg::Refresh));
+                    }
+                    _ => {
+                        model.treee = tree::Model::default();
+                        if let Some(url) = dev.profile().scheme.clone() {
+                            orders.perform_cmd(async move {
+                                let scheme = cfg::load(url).await;
+                                Msg::CfgLoaded(scheme)
+                            });
+                        }
+                    }
+                }
             };
+
+            let slot = model.fleet
+                .entry(serial.clone())
+                .or_insert_with(|| fleet::Slot::new(Rc::clone(&dev)));
+            slot.device = Rc::clone(&dev);
+            slot.tree = None;
+            if slot.download_interrupted() && dev.has(Feature::File) {
+                orders.send_msg(Msg::DownloadOn(serial.clone()));
+            }
+
+            model.dfu_caps = None;
+            if dev.has(Feature::Dfu) {
+                orders.perform_cmd(read_dfu_caps(Rc::clone(&dev)));
+            }
+            model.dbg.snapshot = None;
+            model.link.connected();
+            model.last_serial = Some(serial);
             model.device = dev;
         }
-        Msg::CfgLoaded(scheme) => {
+        Msg::DevReattached(dev) => {
+            let serial = dev.descriptor().map(|d| d.serial().to_string()).unwrap_or_default();
+            if let Some(slot) = model.fleet.get_mut(&serial) {
+                log::info!("Device {} reconnected in background", serial);
+                slot.device = dev;
+                slot.status = "Reconnected".into();
+                if slot.download_interrupted() {
+                    orders.send_msg(Msg::DownloadOn(serial));
+                }
+            }
+        }
+        Msg::DeviceActivate(serial) => {
+            if model.last_serial.as_ref() == Some(&serial) {
+                return;
+            }
+            park_tree(model);
+            let slot = match model.fleet.get_mut(&serial) {
+                Some(slot) => slot,
+                None => return,
+            };
+            model.treee = slot.tree.take().unwrap_or_default();
+            model.device = Rc::clone(&slot.device);
+            model.dfu_caps = None;
+            if model.device.has(Feature::Dfu) {
+                orders.perform_cmd(read_dfu_caps(Rc::clone(&model.device)));
+            }
+            model.dbg.snapshot = None;
+            model.link = link::Link::default();
+            model.link.refresh(&model.device);
+            model.last_serial = Some(serial);
+        }
+        Msg::DeviceSelect(serial) => {
+            if let Some(slot) = model.fleet.get_mut(&serial) {
+                slot.selected = !slot.selected;
+            }
+        }
+        Msg::BulkSyncTime => {
+            let mut skipped = Vec::new();
+            for (serial, slot) in model.fleet.iter_mut().filter(|(_, s)| s.selected && s.device.has(Feature::Tree)) {
+                if slot.busy {
+                    skipped.push(serial.clone());
+                    continue;
+                }
+                slot.busy = true;
+                let (serial, device) = (serial.clone(), Rc::clone(&slot.device));
+                orders.perform_cmd(async move {
+                    Msg::BulkStatus(serial, fleet::sync_time(device).await)
+                });
+            }
+            report_skipped(model, "Time sync", &skipped);
+        }
+        Msg::BulkDownload => {
+            let serials = fleet::selected(&model.fleet)
+                .filter(|(_, s)| s.device.has(Feature::File))
+                .map(|(serial, _)| serial.clone())
+                .collect::<Vec<_>>();
+            for serial in serials {
+                orders.send_msg(Msg::DownloadOn(serial));
+            }
+        }
+        Msg::BulkConfig(e) => {
+            let picked = match picked_file(&e) {
+                Ok(picked) => picked,
+                Err(e) => {
+                    model.notes.error(&e);
+                    return;
+                }
+            };
+            if let Some(file) = picked {
+                orders.perform_cmd(async move {
+                    match read_file(file).await {
+                        Ok(bytes) => Msg::BulkConfigLoaded(bytes),
+                        Err(e) => Msg::Failed(e),
+                    }
+                });
+            }
+        }
+        Msg::BulkConfigLoaded(bytes) => {
+            let writes = serde_json::from_slice(&bytes)
+                .map_err(|e| e.to_string())
+                .and_then(|profile| fleet::config_writes(&profile, &model.treee));
+            let writes = match writes {
+                Ok(writes) => writes,
+                Err(e) => {
+                    model.notes.error(&error::Error::Config(e));
+                    return;
+                }
+            };
+            let mut skipped = Vec::new();
+            for (serial, slot) in model.fleet.iter_mut().filter(|(_, s)| s.selected && s.device.has(Feature::Tree)) {
+                if slot.busy {
+                    skipped.push(serial.clone());
+                    continue;
+                }
+                slot.busy = true;
+                let (serial, device, writes) = (serial.clone(), Rc::clone(&slot.device), writes.clone());
+                orders.perform_cmd(async move {
+                    Msg::BulkStatus(serial, fleet::apply_config(device, writes).await)
+                });
+            }
+            report_skipped(model, "Config", &skipped);
+        }
+        Msg::InspectorToggle => {
+            model.inspector = !model.inspector;
+        }
+        Msg::InspectorFilter(filter) => {
+            model.inspector_filter = filter;
+        }
+        Msg::InspectorPause => {
+            inspect::set_paused(!inspect::is_paused());
+        }
+        Msg::InspectorClear => {
+            inspect::clear();
+        }
+        Msg::InspectorExport => {
+            let text = inspect::export(&inspect::filtered(&model.inspector_filter));
+            let name = format!("protocol-{}.txt", js_sys::Date::now() as u64);
+            orders.perform_cmd(save_file(name, text.into_bytes()));
+        }
+        Msg::DbgRead => {
+            if model.dbg.reading || !model.device.has(Feature::Tree) {
+                return;
+            }
+            model.dbg.reading = true;
+            let device = Rc::clone(&model.device);
+            orders.perform_cmd(async move {
+                Msg::DbgLoaded(dbg::read(device).await)
+            });
+        }
+        Msg::DbgLoaded(r) => {
+            model.dbg.reading = false;
+            match r {
+                Ok(snapshot) => {
+                    let serial = model.last_serial.clone().unwrap_or_default();
+                    model.dbg.update(js_sys::Date::now(), &serial, snapshot);
+                }
+                Err(e) => model.notes.push(error::Severity::Warning, "Failed to read /dbg".into(), Some(e)),
+            }
+        }
+        Msg::DbgClear => {
+            model.dbg.history.clear();
+        }
+        Msg::SelfTestRun => {
+            if model.selftest_running || !model.device.has(Feature::Tree) {
+                return;
+            }
+            // The file reads rewrite /io/file/* and take blocks off a running download or stream
+            if model.vis.load(Ordering::SeqCst) {
+                log::warn!("Vis is running, self-test not started");
+                return;
+            }
+            let serial = model.device.descriptor().map(|d| d.serial().to_string()).unwrap_or_default();
+            if let Some(slot) = model.fleet.get_mut(&serial) {
+                let downloading = slot.download.as_ref().map_or(false, |dl| dl.borrow().is_running());
+                if slot.busy || downloading {
+                    log::warn!("{} is busy, self-test not started", serial);
+                    return;
+                }
+                slot.busy = true;
+            }
+            model.selftest_running = true;
+            model.selftest = None;
+            let device = Rc::clone(&model.device);
+            orders.perform_cmd(async move {
+                Msg::SelfTestDone(selftest::run(device).await)
+            });
+        }
+        Msg::SelfTestDone(report) => {
+            model.selftest_running = false;
+            if let Some(slot) = model.fleet.get_mut(&report.serial) {
+                slot.busy = false;
+            }
+            model.selftest = Some(report);
+        }
+        Msg::SelfTestExport => {
+            if let Some(report) = &model.selftest {
+                let name = format!("selftest-{}-{}.txt", report.serial, js_sys::Date::now() as u64);
+                orders.perform_cmd(save_file(name, report.to_text().into_bytes()));
+            }
+        }
+        Msg::CaptureToggle => {
+            if !capture::is_active() {
+                capture::start();
+                return;
+            }
+            if let Some(data) = capture::stop() {
+                let name = format!("session-{}.hcap", js_sys::Date::now() as u64);
+                orders.perform_cmd(save_file(name, data));
+            }
+        }
+        Msg::OpenCapture(e) => {
+            let file = match picked_file(&e) {
+                Ok(Some(file)) => file,
+                Ok(None) => return,
+                Err(e) => {
+                    model.notes.error(&e);
+                    return;
+                }
+            };
+            log::info!("Capture file name: {}", file.name());
+
+            orders.perform_cmd(async move {
+                match read_file(file).await {
+                    Ok(bytes) => Msg::CaptureLoaded(bytes),
+                    Err(e) => Msg::Failed(e),
+                }
+            });
+        }
+        Msg::CaptureLoaded(data) => {
+            match device::Device::replay(&data, &model.registry) {
+                Ok(dev) => {
+                    log::info!("Replaying capture of {:?}", dev.descriptor().map(|d| d.serial()));
+                    orders.send_msg(Msg::DevConnected(Rc::new(dev)));
+                }
+                Err(e) => model.notes.error(&e.into()),
+            }
+        }
+        Msg::BulkStatus(serial, r) => {
+            if let Some(slot) = model.fleet.get_mut(&serial) {
+                slot.busy = false;
+                slot.status = match r {
+                    Ok(status) => status,
+                    Err(e) => {
+                        log::error!("{}: {}", serial, e);
+                        format!("Error: {}", e)
+                    }
+                };
+            }
+        }
+        Msg::CfgLoaded(Ok(scheme)) => {
             orders.send_msg(Msg::Tree(tree::Msg::SetScheme(scheme)));
         }
+        Msg::CfgLoaded(Err(e)) => {
+            model.notes.error(&e);
+        }
+        Msg::Failed(e) => {
+            model.notes.error(&e);
+        }
+        Msg::HeartbeatTick => {
+            orders.perform_cmd(heartbeat_tick());
+            let was_lost = model.link.is_lost();
+            model.link.refresh(&model.device);
+            if !was_lost && model.link.is_lost() {
+                link_lost(model, orders);
+            }
+            let device = &model.device;
+            // Recent traffic is proof enough, and a beat must not slip between a running flow's requests
+            // unless the flow stalled
+            let idle = !model.heartbeat
+                && !model.selftest_running
+                && !link::is_active(device)
+                && device.last_seen().map_or(true, |t| js_sys::Date::now() - t > link::HEARTBEAT_MS as f64)
+                && model.update.as_ref().map_or(true, |u| !u.step.is_running())
+                && model.last_serial.as_ref().and_then(|s| model.fleet.get(s)).map_or(true, |s| !s.busy || link::is_stalled(device));
+            if device.has(Feature::Tree) && !device.is_replay() && idle {
+                model.heartbeat = true;
+                let device = Rc::clone(device);
+                orders.perform_cmd(async move {
+                    Msg::HeartbeatDone(link::heartbeat(device).await)
+                });
+            }
+        }
+        Msg::HeartbeatDone(r) => {
+            model.heartbeat = false;
+            let was_lost = model.link.is_lost();
+            if model.link.beat(r) {
+                if let link::State::Lost(why) = &model.link.state {
+                    model.device.lose(why.clone());
+                }
+            }
+            if !was_lost && model.link.is_lost() {
+                link_lost(model, orders);
+            }
+        }
+        Msg::Dismiss(id) => {
+            model.notes.dismiss(id);
+        }
+        Msg::DismissAll => {
+            model.notes.clear();
+        }
         Msg::DownloadFile => {
-            let device = Rc::clone(&model.device);
+            if let Some(serial) = model.last_serial.clone() {
+                orders.send_msg(Msg::DownloadOn(serial));
+            }
+        }
+        Msg::DownloadOn(serial) => {
+            let slot = match model.fleet.get_mut(&serial) {
+                Some(slot) => slot,
+                None => return,
+            };
+            if slot.busy {
+                log::warn!("{} is busy, download not started", serial);
+                return;
+            }
+            // Continues an interrupted one, otherwise starts over
+            let dl = match &slot.download {
+                Some(dl) if dl.borrow().is_running() => {
+                    log::warn!("Download from {} already running", serial);
+                    return;
+                }
+                Some(dl) if !dl.borrow().is_complete() => Rc::clone(dl),
+                _ => Rc::new(RefCell::new(download::FileDownload::new(&format!("data-{}.bin", serial)))),
+            };
+            slot.download = Some(Rc::clone(&dl));
+            slot.busy = true;
+            let device = Rc::clone(&slot.device);
             orders.perform_cmd( async move {
-                let cancel = false;
-                download::download_file_from_device(
-                    device,
-                    "data.bin",
-                    &cancel,
-                ).await.unwrap();
-
-                Option::<Msg>::None
+                if download::download_file_from_device(device, dl).await.is_err() {
+                    log::warn!("Download from {} stopped, resumes on reconnect", serial);
+                }
+                Msg::DownloadStopped(serial)
             });
         }
+        Msg::DownloadStopped(serial) => {
+            if let Some(slot) = model.fleet.get_mut(&serial) {
+                slot.busy = false;
+                let complete = slot.download.as_ref().map_or(false, |dl| dl.borrow().is_complete());
+                slot.status = if complete { "File downloaded".into() } else { "Download interrupted".into() };
+                if complete {
+                    slot.download = None;
+                }
+            }
+        }
         Msg::VisStart => {
             // FIXME: proper vis stop sequence
             if model.vis.load(Ordering::SeqCst) {
@@ -141,42 +651,412 @@ fn update(msg: Msg, model: &mut Model, orders: &mut impl Orders<Msg>) {
         }
         Msg::VisUpdate => {
             let device = Rc::clone(&model.device);
-            let vis = Rc::clone(&model.vis);
-            let vis_group = Rc::clone(&model.vis_group);
-            orders.perform_cmd(vis_update(device, vis, vis_group));
+            set_profile_rates(&device);
+            model.vis_events.borrow_mut().clear();
+            {
+                let mut ctl = model.vis_ctl.borrow_mut();
+                ctl.resume();
+                ctl.set_spans(Vec::new());
+            }
+            model.vis_history.borrow_mut().clear();
+            *model.vis_stats.borrow_mut() = vis::stats::LinkStats::new();
+            *model.vis_acc.borrow_mut() = Default::default();
+            *model.vis_reo.borrow_mut() = Default::default();
+            orders.perform_cmd(vis_update(device, model.vis_ctx()));
+            orders.send_msg(Msg::VisTick);
+        }
+        Msg::VisTick => {
+            // Periodic redraw of vis indicators, stops with the stream
+            if model.vis.load(Ordering::SeqCst) {
+                orders.perform_cmd(vis_tick());
+            } else {
+                model.vis_quality.borrow_mut().clear();
+            }
         }
         Msg::VisSelectedGroup(group) => {
             log::info!("Selected vis group: {:?}", group);
             model.vis_group.set(group);
         }
+        Msg::VisRecordMode(mode) => {
+            log::info!("Vis record mode: {:?}", mode);
+            model.vis_record.set(mode);
+        }
+        Msg::EventNote(text) => {
+            model.event_note = text;
+        }
+        Msg::MarkEvent => {
+            let device = Rc::clone(&model.device);
+            let ev = event::Event::note(&model.event_note);
+            orders.perform_cmd(async move {
+                let req = ev.request();
+                if cmd(&device, req).await.is_err() {
+                    log::error!("Failed to write event marker");
+                }
+            });
+        }
+        Msg::ExportStats => {
+            let snapshot = model.vis_stats.borrow().snapshot(js_sys::Date::now());
+            let content = serde_json::to_vec_pretty(&snapshot).unwrap();
+            orders.perform_cmd(async move {
+                if let Err(e) = download::download_file("vis-stats.json".to_string(), content).await {
+                    log::error!("Stats export failed: {:?}", e);
+                }
+            });
+        }
+        Msg::VisPause => {
+            let mut ctl = model.vis_ctl.borrow_mut();
+            if ctl.is_paused() {
+                ctl.resume();
+            } else {
+                ctl.pause(&model.vis_history.borrow());
+            }
+        }
+        Msg::VisScroll(sec) => {
+            model.vis_ctl.borrow_mut().scroll(sec);
+        }
+        Msg::VisCursor(x, y) => {
+            model.vis_ctl.borrow_mut().pick(&model.vis_history.borrow(), x, y);
+        }
+        Msg::VisClearCursors => {
+            model.vis_ctl.borrow_mut().clear_cursors();
+        }
+        Msg::OpenRecording(e) => {
+            let file = match picked_file(&e) {
+                Ok(Some(file)) => file,
+                Ok(None) => return,
+                Err(e) => {
+                    model.notes.error(&e);
+                    return;
+                }
+            };
+            log::info!("Recording file name: {}", file.name());
+
+            set_profile_rates(&model.device);
+            orders.perform_cmd(open_recording(file));
+        }
+        Msg::RecordingLoaded(rec) => {
+            // Live stream and recording share the renderer
+            model.vis.store(false, Ordering::SeqCst);
+            let Recording { store, acc_timeline, reo_report } = match Rc::try_unwrap(rec) {
+                Ok(rec) => rec,
+                Err(_) => return,
+            };
+            model.acc_timeline = acc_timeline;
+            model.reo_report = reo_report;
+            let groups = VisSelectedGroup::ALL.groups();
+            // Apnea-like pauses are marked over the REO trace
+            let rate = vis::Group::REO.sample_rate();
+            let apneas = model.reo_report.iter().flat_map(|r| r.apneas.iter()).map(|a| {
+                (vis::Group::REO, (a.start * rate) as u64, (a.end * rate) as u64)
+            }).collect();
+            {
+                let mut ctl = model.vis_ctl.borrow_mut();
+                ctl.freeze(groups.clone(), &store);
+                ctl.set_spans(apneas);
+            }
+            *model.vis_history.borrow_mut() = store;
+
+            let (tx, rx) = std::sync::mpsc::channel();
+            // Receiver is still in scope, the layout cannot be lost here
+            let _ = tx.send(vis::Frame::Layout(groups));
+            if let Err(e) = vis::vis_run(rx, Rc::clone(&model.vis_ctl), Rc::clone(&model.vis_history)) {
+                model.notes.error(&error::Error::Render(format!("{:?}", e)));
+            }
+        }
         Msg::DfuUploadFirmware(e) => {
-            let event = e.dyn_into::<JsValue>().unwrap();
-            let target  = js_sys::Reflect::get(&event, &JsValue::from_str("target")).unwrap();
-            let files = js_sys::Reflect::get(&target, &JsValue::from_str("files")).unwrap();
-            let files: web_sys::FileList = files.dyn_into().unwrap();
-            let file = files.item(0).expect_throw("No file selected");
+            let file = match picked_file(&e) {
+                Ok(Some(file)) => file,
+                Ok(None) => return,
+                Err(e) => {
+                    model.notes.error(&e);
+                    return;
+                }
+            };
             log::info!("Upload file name: {}", file.name());
 
             orders.perform_cmd(upload_file(file));
         }
         Msg::UploadFileCompleted(data) => {
-            // model.upload_data = Some(data);
-            // log::info!("Upload file done, size: 0x{:x} bytes", model.upload_data.as_ref().unwrap().len());
+            let layout = match &model.device.profile().layout {
+                Some(layout) => layout.clone(),
+                None => {
+                    let e = format!("no memory layout for {} in devices.json", model.device.profile().name);
+                    model.notes.error(&error::Error::Firmware(e));
+                    return;
+                }
+            };
+            let fw = match dfu::container::Firmware::parse(&data, &layout) {
+                Ok(fw) => fw,
+                Err(e) => {
+                    model.notes.error(&e.into());
+                    return;
+                }
+            };
+            log::info!("Firmware {:?}, {} segments", fw.format, fw.segments.len());
+            match dfu::image::inspect(&fw.flatten(), &layout) {
+                Ok(info) => {
+                    log::info!("Firmware image: {:?}", info);
+                    model.upload_data = Some(fw);
+                    model.upload_info = Some(info);
+                }
+                Err(e) => model.notes.error(&e.into()),
+            }
+        }
+        Msg::DfuConfirm => {
+            let data = match model.upload_data.take() {
+                Some(data) => data,
+                None => return,
+            };
+            let info = model.upload_info.take();
+            if !model.device.is_dfu_mode() {
+                model.update = Some(update::Update {
+                    step: update::Step::EnterLoader,
+                    serial: model.device.descriptor().map(|d| d.serial().to_string()).unwrap_or_default(),
+                    image: Rc::new(data),
+                    expected: info.and_then(|i| i.version().map(String::from)),
+                });
+                let device =  Rc::clone(&model.device);
+                orders.perform_cmd( async move {
+                    update::enter_loader(device).await;
+                    Msg::UpdateWait
+                });
+                return;
+            }
+            model.dfu_cancel.set(false);
             let device =  Rc::clone(&model.device);
+            let cancel = Rc::clone(&model.dfu_cancel);
+            let caps = model.dfu_caps.unwrap_or_default();
+            let sender = orders.msg_sender();
             orders.perform_cmd( async move {
-            let _dev_ans = device.send_recv_dfu(data).await;
+                let dfu = dfu::Dfu::new(&device)
+                    .functional(caps)
+                    .cancel_flag(cancel)
+                    .on_progress(move |p| sender(Some(Msg::DfuProgress(p))));
+                let r = dfu.download_verified(&data).await;
+                if r.is_ok() {
+                    log::info!("Firmware written and verified");
+                }
+                Msg::DfuFinished(r.map_err(error::Error::from))
             });
         }
+        Msg::DfuCancel => {
+            model.upload_data = None;
+            model.upload_info = None;
+        }
         Msg::DfuDownloadFirmware => {
+            model.dfu_cancel.set(false);
             let device =  Rc::clone(&model.device);
+            let cancel = Rc::clone(&model.dfu_cancel);
+            let caps = model.dfu_caps.unwrap_or_default();
+            let sender = orders.msg_sender();
             orders.perform_cmd( async move {
-            let dev_ans = device.dfu_upload().await;
-            download::download_file("holter-firmware.bin".to_string(), dev_ans.unwrap()).await.unwrap();
+                let dfu = dfu::Dfu::new(&device)
+                    .functional(caps)
+                    .cancel_flag(cancel)
+                    .on_progress(move |p| sender(Some(Msg::DfuProgress(p))));
+                let r = match dfu.upload().await {
+                    Ok(image) => download::download_file("holter-firmware.bin".to_string(), image)
+                        .await
+                        .map_err(|e| error::Error::File(format!("{:?}", e))),
+                    Err(e) => Err(e.into()),
+                };
+                Msg::DfuFinished(r)
             });
         }
+        Msg::DfuProgress(p) => {
+            model.dfu_progress = Some(p);
+        }
+        Msg::DfuAbort => {
+            model.dfu_cancel.set(true);
+        }
+        Msg::DfuFinished(r) => {
+            model.dfu_progress = None;
+            if let Err(e) = r {
+                model.notes.error(&e);
+            }
+        }
+        Msg::DfuCaps(caps) => {
+            model.dfu_caps = caps;
+        }
+        Msg::RegistryLoaded(registry) => {
+            model.registry = registry;
+            if !model.device.is_connected() {
+                orders.send_msg(Msg::AutoConnect);
+            }
+        }
+        Msg::UpdateWait => {
+            let up = match &mut model.update {
+                Some(up) => up,
+                None => return,
+            };
+            up.step = match up.step {
+                update::Step::EnterLoader => update::Step::WaitLoader,
+                update::Step::Flash => update::Step::WaitApp,
+                _ => return,
+            };
+            let ty = up.step.awaits().unwrap();
+            let serial = up.serial.clone();
+            let held: Vec<String> = model.fleet
+                .iter()
+                .filter(|(_, slot)| slot.device.is_connected())
+                .map(|(serial, _)| serial.clone())
+                .collect();
+            let registry = Rc::clone(&model.registry);
+            orders.perform_cmd( async move {
+                match update::wait_device(&registry, ty, &serial, &held).await {
+                    Some(dev) => Msg::UpdateFound(Rc::new(dev)),
+                    None => Msg::UpdateNotFound,
+                }
+            });
+        }
+        Msg::UpdateStep(step) => {
+            if let Some(up) = &mut model.update {
+                up.step = step;
+            }
+        }
+        Msg::UpdateNotFound => {
+            if let Some(up) = &mut model.update {
+                up.step = match up.step {
+                    update::Step::WaitLoader => update::Step::SelectLoader,
+                    update::Step::WaitApp => update::Step::SelectApp,
+                    ref step => step.clone(),
+                };
+            }
+        }
+        Msg::UpdateSelect => {
+            let registry = Rc::clone(&model.registry);
+            orders.perform_cmd(async move {
+                match device::Device::request_device(&registry).await {
+                    Ok(dev) => Msg::UpdateFound(Rc::new(dev)),
+                    Err(e) => {
+                        log::info!("{:?}", e);
+                        Msg::UpdateNotFound
+                    }
+                }
+            });
+        }
+        Msg::UpdateFound(dev) => {
+            let up = match &mut model.update {
+                Some(up) => up,
+                None => return,
+            };
+            let loader = match up.step.awaits() {
+                Some(device::Type::Loader) => true,
+                Some(device::Type::Holter) => false,
+                None => return,
+            };
+            // A loader picked by hand may report its own serial, the application must be the same unit
+            let other = !loader && dev.descriptor().map_or(true, |d| d.serial() != up.serial);
+            if dev.is_dfu_mode() != loader || other {
+                log::warn!("Update: unexpected device {:?}", dev.descriptor());
+                orders.send_msg(Msg::UpdateNotFound);
+                return;
+            }
+            orders.send_msg(Msg::DevConnected(Rc::clone(&dev)));
+
+            if loader {
+                up.step = update::Step::Backup;
+                model.dfu_cancel.set(false);
+                let image = Rc::clone(&up.image);
+                let cancel = Rc::clone(&model.dfu_cancel);
+                let sender = orders.msg_sender();
+                orders.perform_cmd( async move {
+                    let caps = match dfu::Functional::read(&dev).await {
+                        Ok(caps) => caps,
+                        Err(e) => return Msg::UpdateFlashed(Err(e.to_string())),
+                    };
+                    let progress = Rc::clone(&sender);
+                    let dfu = dfu::Dfu::new(&dev)
+                        .functional(caps)
+                        .cancel_flag(cancel)
+                        .on_progress(move |p| progress(Some(Msg::DfuProgress(p))));
+                    if !caps.can_upload {
+                        log::warn!("Update: loader can't upload, no backup");
+                    } else if let Err(e) = update::backup(&dev, &dfu).await {
+                        sender(Some(Msg::DfuFinished(Ok(()))));
+                        return Msg::UpdateFlashed(Err(e));
+                    }
+                    sender(Some(Msg::UpdateStep(update::Step::Flash)));
+                    let r = match dfu.download_verified(&image).await {
+                        Ok(()) => Ok(()),
+                        Err(dfu::Error::NoReadback) => {
+                            log::warn!("Update: loader reset before verify, relying on version check");
+                            Ok(())
+                        }
+                        Err(e) => Err(e.to_string()),
+                    };
+                    sender(Some(Msg::DfuFinished(Ok(()))));
+                    if r.is_ok() {
+                        if let Err(e) = dev.usb_reset().await {
+                            log::debug!("Update: reset {:?}", e);
+                        }
+                    }
+                    Msg::UpdateFlashed(r)
+                });
+            } else {
+                up.step = update::Step::CheckVersion;
+                orders.perform_cmd( async move {
+                    Msg::UpdateDone(update::read_version(dev).await)
+                });
+            }
+        }
+        Msg::UpdateFlashed(r) => {
+            match r {
+                Ok(()) => {
+                    orders.send_msg(Msg::UpdateStep(update::Step::Flash));
+                    orders.send_msg(Msg::UpdateWait);
+                }
+                Err(e) => {
+                    log::error!("Update: {}", e);
+                    orders.send_msg(Msg::UpdateStep(update::Step::Failed(e)));
+                }
+            }
+        }
+        Msg::UpdateDone(r) => {
+            let up = match &mut model.update {
+                Some(up) => up,
+                None => return,
+            };
+            up.step = match (r, &up.expected) {
+                (Ok(v), Some(expected)) if &v != expected => {
+                    update::Step::Failed(format!("running {} instead of {}", v, expected))
+                }
+                (Ok(v), None) => {
+                    log::warn!("Update: image has no build info, running {} not checked", v);
+                    update::Step::Unchecked(v)
+                }
+                (Ok(v), Some(_)) => update::Step::Done(v),
+                (Err(e), _) => update::Step::Failed(e),
+            };
+            log::info!("Update: {:?}", up.step);
+        }
     }
 }
 
+/// Moves the active device's tree back into its slot
+fn park_tree(model: &mut Model) {
+    if let Some(slot) = model.last_serial.as_ref().and_then(|s| model.fleet.get_mut(s)) {
+        slot.tree = Some(std::mem::take(&mut model.treee));
+    }
+}
+
+/// Bulk actions leave busy devices alone, the user is told which ones
+fn report_skipped(model: &mut Model, action: &str, skipped: &[String]) {
+    if skipped.is_empty() {
+        return;
+    }
+    log::warn!("{} skipped, busy: {:?}", action, skipped);
+    model.notes.push(
+        error::Severity::Warning,
+        format!("{} skipped on busy devices: {}", action, skipped.join(", ")),
+        None,
+    );
+}
+
+fn active_slot(model: &Model) -> Option<&fleet::Slot> {
+    model.fleet.get(model.last_serial.as_ref()?)
+}
+
 // ------ ------
 // Orders
 // ------ ------
@@ -186,14 +1066,131 @@ fn update(msg: Msg, model: &mut Model, orders: &mut impl Orders<Msg>) {
 //    Msg::Increment
 //}
 
-async fn auto_connect() -> Msg {
+async fn read_dfu_caps(device: Rc<device::Device>) -> Msg {
+    match dfu::Functional::read(&device).await {
+        Ok(caps) => Msg::DfuCaps(Some(caps)),
+        Err(e) => {
+            log::error!("{:?}", e);
+            Msg::DfuCaps(None)
+        }
+    }
+}
+
+/// Re-attaches to a permitted device without the picker, the known serial first
+async fn auto_connect(registry: Rc<Registry>, serial: Option<String>) -> Option<Msg> {
     TimeoutFuture::new(100).await;
-    Msg::Connect
+    let found = device::Device::find_permitted(&registry, |p, d| match &serial {
+        Some(serial) => d.serial() == serial.as_str(),
+        None => p.kind == device::Type::Holter,
+    }).await;
+    match found {
+        Ok(Some(dev)) => Some(Msg::DevConnected(Rc::new(dev))),
+        Ok(None) => {
+            log::info!("No permitted device, waiting for it to be plugged in");
+            None
+        }
+        Err(e) => {
+            log::error!("{:?}", e);
+            None
+        }
+    }
 }
 
-async fn upload_file(file: web_sys::File) -> Msg {
+async fn heartbeat_tick() -> Msg {
+    TimeoutFuture::new(link::HEARTBEAT_MS).await;
+    Msg::HeartbeatTick
+}
+
+/// Tells the user once and looks for the device among permitted ones, no picker
+fn link_lost(model: &mut Model, orders: &mut impl Orders<Msg>) {
+    model.notes.push(error::Severity::Warning, model.link.state.describe(), None);
+    let busy = model.update.as_ref().map_or(false, |u| u.step.is_running());
+    if !busy {
+        orders.send_msg(Msg::AutoConnect);
+    }
+}
+
+async fn vis_tick() -> Msg {
+    TimeoutFuture::new(500).await;
+    Msg::VisTick
+}
+
+async fn save_file(name: String, data: Vec<u8>) -> Option<Msg> {
+    download::download_file(name, data)
+        .await
+        .err()
+        .map(|e| Msg::Failed(error::Error::File(format!("{:?}", e))))
+}
+
+/// File chosen in an `<input type="file">` change event
+fn picked_file(e: &web_sys::Event) -> Result<Option<web_sys::File>, error::Error> {
+    let bad = |what: &str| error::Error::File(format!("file input: {}", what));
+    let target = js_sys::Reflect::get(e, &JsValue::from_str("target")).map_err(|_| bad("no target"))?;
+    let files = js_sys::Reflect::get(&target, &JsValue::from_str("files")).map_err(|_| bad("no file list"))?;
+    let files: web_sys::FileList = files.dyn_into().map_err(|_| bad("no file list"))?;
+    Ok(files.item(0))
+}
+
+async fn read_file(file: web_sys::File) -> Result<Vec<u8>, error::Error> {
+    let name = file.name();
     let file: gloo_file::File = file.into();
-    let bytes = gloo_file::futures::read_as_bytes(&file).await.unwrap();
+    gloo_file::futures::read_as_bytes(&file)
+        .await
+        .map_err(|e| error::Error::File(format!("{}: {:?}", name, e)))
+}
+
+// Vis group rates come from the device profile
+fn set_profile_rates(device: &device::Device) {
+    let rates = match device.profile().rates {
+        Some(rates) => rates,
+        None => {
+            log::warn!("No vis rates for {} in devices.json, using defaults", device.profile().name);
+            vis::panel::Rates::default()
+        }
+    };
+    vis::panel::set_rates(rates);
+}
+
+async fn open_recording(file: web_sys::File) -> Msg {
+    let bytes = match read_file(file).await {
+        Ok(bytes) => bytes,
+        Err(e) => return Msg::Failed(e),
+    };
+    log::info!("Recording size: 0x{:x} bytes", bytes.len());
+
+    let store = vis::history::Store::decode(&bytes).await;
+    let acc_timeline = store
+        .get(vis::Group::ACC_IN)
+        .map(analysis::acc::timeline)
+        .unwrap_or_default();
+    let reo_report = store.get(vis::Group::REO).map(|reo| {
+        let r_peaks: Vec<f32> = store
+            .get(vis::Group::ECG)
+            .map(|ecg| {
+                analysis::rpeak::detect(ecg, RPEAK_CH)
+                    .into_iter()
+                    .map(|idx| ecg.time(idx))
+                    .collect()
+            })
+            .unwrap_or_default();
+        analysis::reo::report(reo, &r_peaks)
+    });
+
+    Msg::RecordingLoaded(Rc::new(Recording { store, acc_timeline, reo_report }))
+}
+
+/// Decoded recording with its analysis
+struct Recording {
+    store: vis::history::Store,
+    acc_timeline: Vec<analysis::Segment<(analysis::acc::Position, analysis::acc::Activity)>>,
+    reo_report: Option<analysis::reo::Report>,
+}
+
+async fn upload_file(file: web_sys::File) -> Msg {
+    let bytes = match read_file(file).await {
+        Ok(bytes) => bytes,
+        Err(e) => return Msg::Failed(e),
+    };
     log::info!("Upload file data chunk:\n{:x?}", &bytes[.. if bytes.len() > 0x40 { 0x40 } else { bytes.len() }]);
 
     Msg::UploadFileCompleted(bytes)
@@ -216,80 +1213,186 @@ async fn cmd(device: &Rc<device::Device>, msg: DevMsg) -> Result<Value, ()> {
 
 async fn vis_start(device: Rc<device::Device>, vis: Rc<AtomicBool>) -> Option<Msg> {
     log::info!("Vis started");
-    let _ = cmd(&device, DevMsg (AnswerCode::OK_WRITE, String::from("/ctrl/vis"), Value::BOOL(true)))
-        .await
-        .unwrap();
-    
+    if let Err(e) = device.send_recv_cmd(DevMsg(AnswerCode::OK_WRITE, String::from("/ctrl/vis"), Value::BOOL(true))).await {
+        return Some(Msg::Failed(e.into()));
+    }
+
     vis.store(true, Ordering::SeqCst);
     Some(Msg::VisUpdate)
 }
 
-async fn vis_update(
-    device: Rc<device::Device>,
-    vis: Rc<AtomicBool>,
-    vis_group: Rc<Cell<VisSelectedGroup>>,
-) 
-    -> Option<Msg> 
-{
+// ECG channel used for R-peak detection
+const RPEAK_CH: usize = 0;
+
+const RENDERER_GONE: &str = "the renderer stopped taking frames";
+
+// Ends a vis session the page can no longer show: the recording is kept,
+// the device is asked to stop streaming and the failure is reported
+async fn vis_abort(
+    device: &device::Device,
+    ctx: &VisCtx,
+    recorder: Option<download::LiveRecorder>,
+    reason: String,
+) -> Option<Msg> {
+    log::error!("Vis aborted: {}", reason);
+    ctx.run.store(false, Ordering::SeqCst);
+    if let Some(r) = recorder {
+        r.finish();
+    }
+    if let Err(e) = device.send_recv_cmd(DevMsg(AnswerCode::OK_WRITE, String::from("/ctrl/vis"), Value::BOOL(false))).await {
+        log::warn!("Vis stop not confirmed: {:?}", e);
+    }
+    Some(Msg::Failed(error::Error::Render(reason)))
+}
+
+async fn vis_update(device: Rc<device::Device>, ctx: VisCtx) -> Option<Msg> {
     use std::sync::mpsc;
     use delta::block::parse::{PntResult, Point};
     use delta::point::decode::PointDesc;
     use delta::error::DecodingError;
     use delta::defs::GroupId;
-    use VisSelectedGroup::*;
     
     let (tx, rx) = mpsc::channel();
-    vis::vis_run(rx).unwrap();
+    if let Err(e) = vis::vis_run(rx, Rc::clone(&ctx.ctl), Rc::clone(&ctx.history)) {
+        return vis_abort(&device, &ctx, None, format!("{:?}", e)).await;
+    }
 
     let mut buf = [0u8;0x800];
-    let mut cnt = 0usize;
+    let mut ecg_cnt = 0usize;
+    let mut reo_cnt = 0usize;
+    // REO state is refreshed on breaths and about once a second
+    let reo_refresh = (vis::Group::REO.sample_rate() as usize).max(1);
+    let mut estimator = vis::quality::Estimator::new(8, vis::Group::ECG.sample_rate());
+    let mut acc = analysis::acc::Analyzer::new(vis::Group::ACC_IN.sample_rate());
+    let mut rpeak = analysis::rpeak::Detector::new(vis::Group::ECG.sample_rate());
+    let mut reo = analysis::reo::Analyzer::new(vis::Group::REO.sample_rate());
+    let mut shown = None;
+    let mut recorder: Option<download::LiveRecorder> = None;
+    let mut recording = VisRecordMode::Off;
     loop {
-        let sz = device.recv_vis(&mut buf).await.unwrap();
-        if !vis.load(Ordering::SeqCst) {
+        let sz = match device.recv_vis(&mut buf).await {
+            Ok(sz) => sz,
+            Err(e) => {
+                ctx.run.store(false, Ordering::SeqCst);
+                if let Some(r) = recorder.take() {
+                    r.finish();
+                }
+                return Some(Msg::Failed(e.into()));
+            }
+        };
+        if !ctx.run.load(Ordering::SeqCst) {
             log::info!("Vis stopped");
+            if let Some(r) = recorder.take() {
+                r.finish();
+            }
             return None;
         }
+
+        let mode = ctx.record.get();
+        if mode != recording {
+            if let Some(r) = recorder.take() {
+                r.finish();
+            }
+            recorder = match mode {
+                VisRecordMode::Off => None,
+                VisRecordMode::Raw => Some(download::LiveRecorder::start(false)),
+                VisRecordMode::RawCsv => Some(download::LiveRecorder::start(true)),
+            };
+            recording = mode;
+        }
+        if let Some(r) = recorder.as_mut() {
+            if let Err(e) = r.write_block(&buf[.. sz]).await {
+                log::error!("Live recording failed: {:?}", e);
+                recorder.take().unwrap().abort();
+                ctx.record.set(VisRecordMode::Off);
+                recording = VisRecordMode::Off;
+            }
+        }
+
+        let now = js_sys::Date::now();
+        ctx.stats.borrow_mut().block(now, sz);
+        let selected = ctx.group.get();
+        if shown != Some(selected) {
+            if tx.send(vis::Frame::Layout(selected.groups())).is_err() {
+                return vis_abort(&device, &ctx, recorder.take(), RENDERER_GONE.into()).await;
+            }
+            shown = Some(selected);
+        }
         //log::info!("vis: {:x?}", &buf[.. sz]);
         let mut parser = delta::block::parse::BlockParser::new();
         let r = parser.try_open_block(&buf[.. sz]);
         //log::info!("try_open_block res: {:?}", r);
         //log::info!("Blk header: {:#?}", parser.header());
         if let DecodingError::Ok = r {
+            ctx.stats.borrow_mut().sequence(parser.header().seq_num);
             while let PntResult::Ok(p) = parser.iter_point() {
                 match p {
-                    Point::PointV(PointDesc{group_id: GroupId::ECG, ch_cnt: 8}, sample) if vis_group.get() == ECG => {
-                        let sample = Vec::from(sample)
-                            .into_iter()
-                            .map(|e| e / 100)
-                            .collect();
-                        if cnt % 8 == 0 {
-                            tx.send(sample).unwrap();
+                    Point::PointV(PointDesc{group_id: GroupId::ECG, ch_cnt: 8}, sample) => {
+                        let sample = Vec::from(sample);
+                        ecg_cnt += 1;
+                        ctx.history.borrow_mut().push(vis::Group::ECG, &sample);
+                        if let Some(r) = recorder.as_mut() { r.push_sample(vis::Group::ECG, &sample) }
+                        estimator.push(&sample);
+                        if let Some(r) = rpeak.push(sample[RPEAK_CH]) {
+                            reo.r_peak(r as f32 / vis::Group::ECG.sample_rate());
+                        }
+                        if ecg_cnt % 250 == 0 && estimator.is_ready() {
+                            *ctx.quality.borrow_mut() = estimator.estimate();
+                        }
+                        if selected.shows(vis::Group::ECG) {
+                            if tx.send(vis::Frame::Sample(vis::Group::ECG, sample)).is_err() {
+                                return vis_abort(&device, &ctx, recorder.take(), RENDERER_GONE.into()).await;
+                            }
                         }
-                        cnt = cnt.wrapping_add(1);
                     }
-                    Point::PointV(PointDesc{group_id: GroupId::REO, ch_cnt: 1}, sample) if vis_group.get() == REO => {
-                        let sample = Vec::from(sample)
-                            .into_iter()
-                            .map(|e| e / 50)
-                            .collect();
-                            tx.send(sample).unwrap();
+                    Point::PointV(PointDesc{group_id: GroupId::REO, ch_cnt: 1}, sample) => {
+                        let sample = Vec::from(sample);
+                        ctx.history.borrow_mut().push(vis::Group::REO, &sample);
+                        if let Some(r) = recorder.as_mut() { r.push_sample(vis::Group::REO, &sample) }
+                        reo_cnt += 1;
+                        if reo.push(sample[0]) || reo_cnt % reo_refresh == 0 {
+                            *ctx.reo.borrow_mut() = reo.state();
+                        }
+                        if selected.shows(vis::Group::REO) {
+                            if tx.send(vis::Frame::Sample(vis::Group::REO, sample)).is_err() {
+                                return vis_abort(&device, &ctx, recorder.take(), RENDERER_GONE.into()).await;
+                            }
+                        }
                     }
-                    Point::PointV(PointDesc{group_id: GroupId::ACC_IN, ch_cnt: 3}, sample) if vis_group.get() == ACC_IN => {
-                        let sample = Vec::from(sample)
-                            .into_iter()
-                            .map(|e| e / 8)
-                            .collect();
-                        tx.send(sample).unwrap();
+                    Point::PointV(PointDesc{group_id: GroupId::ACC_IN, ch_cnt: 3}, sample) => {
+                        let sample = Vec::from(sample);
+                        ctx.history.borrow_mut().push(vis::Group::ACC_IN, &sample);
+                        if let Some(r) = recorder.as_mut() { r.push_sample(vis::Group::ACC_IN, &sample) }
+                        if let Some(state) = acc.push(&sample) {
+                            *ctx.acc.borrow_mut() = state;
+                        }
+                        if selected.shows(vis::Group::ACC_IN) {
+                            if tx.send(vis::Frame::Sample(vis::Group::ACC_IN, sample)).is_err() {
+                                return vis_abort(&device, &ctx, recorder.take(), RENDERER_GONE.into()).await;
+                            }
+                        }
                     }
                     Point::PointV(_, _) => (),
                     Point::EventV(buf) => {
-                        log::error!("EVENT: {:x?}", buf);
+                        log::info!("EVENT: {:x?}", buf);
+                        let ev = event::Event::decode(&buf[..]);
+                        let offset = ecg_cnt as f32 / vis::Group::ECG.sample_rate();
+                        ctx.events.borrow_mut().push(event::Record::new(ev, offset));
+                        if tx.send(vis::Frame::Marker).is_err() {
+                            return vis_abort(&device, &ctx, recorder.take(), RENDERER_GONE.into()).await;
+                        }
                     }
                 }
             }
 
+            if let Some(r) = recorder.as_mut() {
+                if let Err(e) = r.flush().await {
+                    log::error!("Live recording failed: {:?}", e);
+                }
+            }
         } else {
             log::error!("Failed to parse blk: {:?}", r);
+            ctx.stats.borrow_mut().decode_error(format!("{:?}", r));
         }
     }
 }
@@ -301,21 +1404,47 @@ async fn vis_update(
 
 fn view(model: &Model) -> Vec<Node<Msg>> {
     vec![
+        view_notifications(&model.notes),
         div![
             C!["row"],
             button![
                 C!["two columns"],
                 simple_ev(Ev::Click, Msg::Connect),
-                "Connect",
-                if model.device.is_connected() {
-                    attrs!{
-                        At::Disabled => true
-                    }
-                } else {
-                    attrs!{}
-                }
+                if model.fleet.is_empty() { "Connect" } else { "Add device" },
+            ],
+            button![
+                C!["two columns"],
+                simple_ev(Ev::Click, Msg::CaptureToggle),
+                if capture::is_active() { "Stop capture" } else { "Capture" },
+            ],
+            button![
+                C!["two columns"],
+                "Replay",
+                ev(Ev::Click, |_| {
+                    let elem: web_sys::HtmlElement = web_sys::window()
+                        .unwrap()
+                        .document()
+                        .unwrap()
+                        .get_element_by_id("open-capture")
+                        .unwrap()
+                        .dyn_into().unwrap();
+                    elem.click();
+                    ()
+                }),
+            ],
+            input![
+                id!["open-capture"],
+                attrs![
+                    At::Type => "file",
+                    At::Accept => ".hcap",
+                ],
+                style![
+                    St::Display => "none",
+                ],
+                ev(Ev::Input, |e| Msg::OpenCapture(e)),
             ],
         ],
+        view_fleet(model),
         div![
             C!["container"],
             if let Some(desc) = model.device.descriptor() {
@@ -324,9 +1453,24 @@ fn view(model: &Model) -> Vec<Node<Msg>> {
               "No connected devices!".into()
             }
         ],
+        view_link(model),
+        if let (true, Some(caps)) = (model.device.is_dfu_mode(), model.dfu_caps) {
+            div![
+                C!["container"],
+                format!(
+                    "DFU: block {} bytes, download {}, upload {}, detach timeout {} ms",
+                    caps.transfer_size,
+                    if caps.can_download { "yes" } else { "no" },
+                    if caps.can_upload { "yes" } else { "no" },
+                    caps.detach_timeout,
+                ),
+            ]
+        } else {
+            empty![]
+        },
         div![
             C!["container"],
-            if !model.device.is_dfu_mode() {
+            if model.device.has(Feature::Tree) {
                 tree::view(&model.treee).map_msg(Msg::Tree)
             }
             else {
@@ -338,7 +1482,7 @@ fn view(model: &Model) -> Vec<Node<Msg>> {
             button![
                 simple_ev(Ev::Click, Msg::DownloadFile),
                 "Download file",
-                if model.device.is_dfu_mode() {
+                if !model.device.has(Feature::File) {
                     attrs!{
                         At::Disabled => true
                     };
@@ -352,25 +1496,55 @@ fn view(model: &Model) -> Vec<Node<Msg>> {
             ],
             progress![
                 C!["ten columns"],
-                if model.device.is_dfu_mode() {
+                if let Some(p) = model.dfu_progress {
                     attrs!{
-                        At::Disabled => true
-                    };
+                        At::Max => p.total.max(1),
+                        At::Value => p.done,
+                    }
+                } else if let Some(dl) = active_slot(model).and_then(|s| s.download.as_ref()) {
+                    let (done, total) = dl.borrow().progress();
+                    attrs!{
+                        At::Max => total.max(1),
+                        At::Value => done,
+                    }
+                } else {
+                    attrs!{}
+                },
+                if model.device.is_dfu_mode() && model.dfu_progress.is_none() {
                     style![
                         St::Display => "none",
                         ]
-                } else { 
-                    attrs!{
-                        At::Max => 100,
-                        At::Value => 50,
-                    };
+                } else {
                     style![]
                 }
-            ]
+            ],
+            if let Some(p) = model.dfu_progress {
+                div![
+                    format!("{}: {} / {} bytes", match p.phase {
+                        dfu::Phase::Download => "Transfer",
+                        dfu::Phase::Manifest => "Manifest",
+                        dfu::Phase::Verify => "Verify",
+                        dfu::Phase::Upload => "Read back",
+                    }, p.done, p.total),
+                    button![
+                        simple_ev(Ev::Click, Msg::DfuAbort),
+                        "Abort",
+                        if p.phase == dfu::Phase::Manifest {
+                            attrs!{
+                                At::Disabled => true
+                            }
+                        } else {
+                            attrs!{}
+                        }
+                    ],
+                ]
+            } else {
+                empty![]
+            }
         ],
         div![
             button![
-                "Upload file",
+                if model.device.is_dfu_mode() { "Upload file" } else { "Update firmware" },
                 ev(Ev::Click, |_| {
                     let elem: web_sys::HtmlElement = web_sys::window()
                         .unwrap()
@@ -382,7 +1556,9 @@ fn view(model: &Model) -> Vec<Node<Msg>> {
                     elem.click();
                     ()
                 }),
-                if model.device.is_dfu_mode() {
+                if (model.device.has(Feature::Dfu) || model.device.has(Feature::Update))
+                    && !model.update.as_ref().map_or(false, |u| u.step.is_running())
+                {
                     attrs!{};
                     style![]
                 } else {
@@ -392,6 +1568,14 @@ fn view(model: &Model) -> Vec<Node<Msg>> {
                     style![
                         St::Display => "none",
                         ]
+                },
+                if model.device.is_dfu_mode() && model.dfu_caps.map_or(false, |c| !c.can_download) {
+                    attrs!{
+                        At::Disabled => true,
+                        At::Title => "The loader does not support download",
+                    }
+                } else {
+                    attrs!{}
                 }
             ],
             input![
@@ -405,6 +1589,8 @@ fn view(model: &Model) -> Vec<Node<Msg>> {
                 ev(Ev::Input, |e| Msg::DfuUploadFirmware(e)),
             ],
         ],
+        view_firmware(model),
+        view_update(model),
         div![
             C!["row"],
             button![
@@ -421,6 +1607,14 @@ fn view(model: &Model) -> Vec<Node<Msg>> {
                     style![
                         St::Display => "none",
                         ]
+                },
+                if model.dfu_caps.map_or(false, |c| !c.can_upload) {
+                    attrs!{
+                        At::Disabled => true,
+                        At::Title => "The loader does not support upload",
+                    }
+                } else {
+                    attrs!{}
                 }
             ],
         ],
@@ -429,7 +1623,7 @@ fn view(model: &Model) -> Vec<Node<Msg>> {
             button![
                 simple_ev(Ev::Click, Msg::VisStart),
                 "Vis",
-                if !model.device.is_connected() || model.device.is_dfu_mode() {
+                if !model.device.has(Feature::Vis) {
                     attrs!{
                         At::Disabled => true
                     };
@@ -441,12 +1635,50 @@ fn view(model: &Model) -> Vec<Node<Msg>> {
                     style![]
                 }
             ],
+            input![
+                attrs!{
+                    At::Type => "text",
+                    At::Placeholder => "Event note",
+                    At::MaxLength => event::MAX_EVENT_LEN,
+                    At::Value => model.event_note,
+                },
+                input_ev(Ev::Input, Msg::EventNote),
+            ],
+            button![
+                simple_ev(Ev::Click, Msg::MarkEvent),
+                "Mark event",
+                if !model.vis.load(Ordering::SeqCst) {
+                    attrs!{
+                        At::Disabled => true
+                    }
+                } else {
+                    attrs!{}
+                }
+            ],
             select![
                 input_ev(Ev::Change, |v| Msg::VisSelectedGroup(v.into())),
                 option![ "ECG" ],
                 option![ "REO" ],
                 option![ "ACC_IN" ],
-                if !model.device.is_connected() || model.device.is_dfu_mode() {
+                option![ "ALL" ],
+                if !model.device.has(Feature::Vis) {
+                    attrs!{
+                        At::Disabled => true
+                    };
+                    style![
+                        St::Display => "none",
+                        ]
+                } else {
+                    attrs!{};
+                    style![]
+                }
+            ],
+            select![
+                input_ev(Ev::Change, |v| Msg::VisRecordMode(v.into())),
+                option![ attrs!{ At::Value => "Off" }, "No recording" ],
+                option![ attrs!{ At::Value => "Raw" }, "Record raw" ],
+                option![ attrs!{ At::Value => "RawCsv" }, "Record raw + CSV" ],
+                if !model.device.has(Feature::Vis) {
                     attrs!{
                         At::Disabled => true
                     };
@@ -459,11 +1691,641 @@ fn view(model: &Model) -> Vec<Node<Msg>> {
                 }
             ]
         ],
+        view_history(model),
+        div![
+            style![
+                St::Display => "flex",
+            ],
+            view_quality(&model.vis_quality.borrow(), model.vis_group.get(), model.vis_ctl.borrow().height()),
+            div![
+                style![
+                    St::FlexGrow => "1",
+                ],
+                canvas![
+                    id!("canvas"),
+                    mouse_ev(Ev::Click, |e| Msg::VisCursor(e.offset_x() as f32, e.offset_y() as f32)),
+                ]
+            ],
+        ],
+        view_events(&model.vis_events.borrow()),
+        view_stats(&model.vis_stats.borrow()),
+        view_acc(model),
+        view_reo(model),
+        view_dbg(model),
+        view_selftest(model),
+        view_inspector(model),
+    ]
+}
+
+/// Wall clock time in the browser's locale
+// "default" is a valid language tag that resolves to the user's locale
+pub(crate) fn local_time(t: f64) -> String {
+    String::from(js_sys::Date::new(&JsValue::from_f64(t)).to_locale_time_string("default"))
+}
+
+fn view_link(model: &Model) -> Node<Msg> {
+    let link = &model.link;
+    if link.state == link::State::Disconnected {
+        return empty![];
+    }
+    let color = match link.state {
+        link::State::Ready | link::State::Busy => "green",
+        link::State::Connecting(_) => "gray",
+        link::State::Degraded(_) => "darkorange",
+        link::State::Lost(_) | link::State::Disconnected => "crimson",
+    };
+    div![
+        C!["container"],
+        span![style![St::Color => color, St::FontWeight => "bold"], link.state.describe()],
+        if let Some(t) = model.device.last_seen() {
+            span![format!(", last answer {:.0} s ago", (js_sys::Date::now() - t) / 1000.)]
+        } else {
+            empty![]
+        },
+        if let Some(ms) = link.latency {
+            span![format!(", latency {:.0} ms", ms)]
+        } else {
+            empty![]
+        },
+    ]
+}
+
+fn view_notifications(notes: &error::Notifications) -> Node<Msg> {
+    if notes.is_empty() {
+        return empty![];
+    }
+    div![
+        C!["container"],
+        notes.iter().rev().map(|n| {
+            let color = match n.severity {
+                error::Severity::Info => "steelblue",
+                error::Severity::Warning => "darkorange",
+                error::Severity::Error => "crimson",
+            };
+            let id = n.id;
+            div![
+                style![
+                    St::BorderLeft => format!("4px solid {}", color),
+                    St::PaddingLeft => "8px",
+                    St::MarginBottom => "4px",
+                ],
+                span![local_time(n.t)],
+                " ",
+                span![&n.message],
+                button![
+                    style![St::Float => "right"],
+                    ev(Ev::Click, move |_| Msg::Dismiss(id)),
+                    "×",
+                ],
+                if let Some(details) = &n.details {
+                    details![summary!["Details"], pre![details]]
+                } else {
+                    empty![]
+                },
+            ]
+        }),
+        button![simple_ev(Ev::Click, Msg::DismissAll), "Clear all"],
+    ]
+}
+
+fn view_dbg(model: &Model) -> Node<Msg> {
+    if !model.device.has(Feature::Tree) || !model.device.is_connected() {
+        return empty![];
+    }
+    let panel = &model.dbg;
+    div![
+        C!["container"],
+        button![
+            simple_ev(Ev::Click, Msg::DbgRead),
+            "Read /dbg",
+            if panel.reading { attrs!{At::Disabled => true} } else { attrs!{} },
+        ],
+        if let Some(s) = &panel.snapshot {
+            div![
+                div![format!("Last error: {} (0x{:08x})", dbg::decode(s.last_code), s.last_code)],
+                div![format!("Location: {}", if s.last.is_empty() { "-" } else { s.last.as_str() })],
+                div![
+                    format!("Flags 0x{:08x}: ", s.flags),
+                    dbg::flags(s.flags, &model.device.profile().dbg_flags).into_iter().map(|(_, name, set)| {
+                        span![
+                            style![
+                                St::Margin => "0 4px",
+                                St::Color => if set { "black" } else { "lightgray" },
+                                St::FontWeight => if set { "bold" } else { "normal" },
+                            ],
+                            name,
+                        ]
+                    }),
+                ],
+            ]
+        } else {
+            empty![]
+        },
+        if panel.history.is_empty() {
+            empty![]
+        } else {
+            div![
+                span![format!("Errors this session: {} ", panel.history.len())],
+                button![simple_ev(Ev::Click, Msg::DbgClear), "Clear"],
+                table![
+                    panel.history.iter().rev().map(|e| {
+                        tr![
+                            td![local_time(e.t)],
+                            td![&e.serial],
+                            td![match &e.source {
+                                dbg::Source::Dbg => "/dbg/last_code".to_string(),
+                                dbg::Source::Answer(path) => path.clone(),
+                            }],
+                            td![format!("{}", e.code)],
+                            td![&e.text],
+                        ]
+                    }),
+                ],
+            ]
+        },
+    ]
+}
+
+fn view_selftest(model: &Model) -> Node<Msg> {
+    if !model.device.has(Feature::Tree) || !model.device.is_connected() {
+        return empty![];
+    }
+    div![
+        C!["container"],
+        button![
+            simple_ev(Ev::Click, Msg::SelfTestRun),
+            if model.selftest_running { "Self-test…" } else { "Self-test" },
+            if model.selftest_running { attrs!{At::Disabled => true} } else { attrs!{} },
+        ],
+        if let Some(report) = &model.selftest {
+            div![
+                span![
+                    style![
+                        St::Color => if report.passed() { "green" } else { "red" },
+                        St::FontWeight => "bold",
+                    ],
+                    if report.passed() { "PASS" } else { "FAIL" },
+                ],
+                button![simple_ev(Ev::Click, Msg::SelfTestExport), "Export"],
+                pre![report.to_text()],
+            ]
+        } else if model.selftest_running {
+            div![format!("Echo test, up to {} bytes per request…", selftest::MAX_PAYLOAD)]
+        } else {
+            empty![]
+        },
+    ]
+}
+
+// Rows shown, the export has the whole buffer
+const INSPECTOR_ROWS: usize = 100;
+
+fn view_inspector(model: &Model) -> Node<Msg> {
+    let toggle = button![
+        simple_ev(Ev::Click, Msg::InspectorToggle),
+        if model.inspector { "Hide protocol" } else { "Protocol" },
+    ];
+    if !model.inspector {
+        return div![C!["container"], toggle];
+    }
+    let (exchanges, matched) = inspect::latest(&model.inspector_filter, INSPECTOR_ROWS);
+    let t0 = exchanges.last().map_or(0., |ex| ex.start);
+    div![
+        C!["container"],
+        toggle,
+        input![
+            attrs!{
+                At::Placeholder => "Filter by path",
+                At::Value => &model.inspector_filter,
+            },
+            input_ev(Ev::Input, Msg::InspectorFilter),
+        ],
+        button![
+            simple_ev(Ev::Click, Msg::InspectorPause),
+            if inspect::is_paused() { "Resume" } else { "Pause" },
+        ],
+        button![simple_ev(Ev::Click, Msg::InspectorClear), "Clear"],
+        button![simple_ev(Ev::Click, Msg::InspectorExport), "Export"],
+        span![format!(" {} / {} / {}", matched, inspect::len(), inspect::CAPACITY)],
+        table![
+            exchanges.iter().map(|ex| {
+                tr![
+                    if ex.is_ok() { style![] } else { style![St::Color => "#ff4136"] },
+                    attrs!{
+                        At::Title => format!("> {}\n< {}", inspect::hex(&ex.raw_out), inspect::hex(&ex.raw_in)),
+                    },
+                    td![ex.seq.to_string()],
+                    td![format!("{:.3} s", (ex.start - t0) / 1000.)],
+                    td![ex.serial.as_str()],
+                    td![format!("{:?}", ex.request.0)],
+                    td![ex.path()],
+                    td![format!("{:?}", ex.request.2)],
+                    td![
+                        match (&ex.answer, &ex.error) {
+                            (Some(ans), _) => format!("{:?} {:?}", ans.0, ans.2),
+                            (None, Some(e)) => e.clone(),
+                            (None, None) => String::new(),
+                        }
+                    ],
+                    td![format!("{:.1} ms", ex.duration)],
+                ]
+            }).collect::<Vec<_>>()
+        ],
+    ]
+}
+
+fn view_fleet(model: &Model) -> Node<Msg> {
+    if model.fleet.len() < 2 {
+        return empty![];
+    }
+    let any_selected = fleet::selected(&model.fleet).next().is_some();
+    div![
+        C!["container"],
+        table![
+            model.fleet.iter().map(|(serial, slot)| {
+                let active = model.last_serial.as_ref() == Some(serial);
+                let (s1, s2) = (serial.clone(), serial.clone());
+                tr![
+                    td![
+                        input![
+                            attrs!{
+                                At::Type => "checkbox",
+                                At::Checked => slot.selected.as_at_value(),
+                            },
+                            ev(Ev::Change, move |_| Msg::DeviceSelect(s1)),
+                        ],
+                    ],
+                    td![
+                        if active { b![serial.as_str()] } else { span![serial.as_str()] },
+                    ],
+                    td![slot.device.profile().name.clone()],
+                    td![
+                        if !slot.device.is_connected() {
+                            "Disconnected".to_string()
+                        } else if slot.busy {
+                            "Running...".to_string()
+                        } else {
+                            slot.status.clone()
+                        }
+                    ],
+                    td![
+                        if let Some(dl) = &slot.download {
+                            let (done, total) = dl.borrow().progress();
+                            progress![attrs!{ At::Max => total.max(1), At::Value => done }]
+                        } else {
+                            empty![]
+                        }
+                    ],
+                    td![
+                        button![
+                            ev(Ev::Click, move |_| Msg::DeviceActivate(s2)),
+                            "Open",
+                            if active { attrs!{ At::Disabled => true } } else { attrs!{} },
+                        ],
+                    ],
+                ]
+            }).collect::<Vec<_>>()
+        ],
+        div![
+            button![
+                simple_ev(Ev::Click, Msg::BulkSyncTime),
+                "Sync time",
+                if any_selected { attrs!{} } else { attrs!{ At::Disabled => true } },
+            ],
+            button![
+                simple_ev(Ev::Click, Msg::BulkDownload),
+                "Download files",
+                if any_selected { attrs!{} } else { attrs!{ At::Disabled => true } },
+            ],
+            button![
+                "Apply config",
+                ev(Ev::Click, |_| {
+                    let elem: web_sys::HtmlElement = web_sys::window()
+                        .unwrap()
+                        .document()
+                        .unwrap()
+                        .get_element_by_id("config-file")
+                        .unwrap()
+                        .dyn_into().unwrap();
+                    elem.click();
+                    ()
+                }),
+                if any_selected && !model.treee.is_empty() { attrs!{} } else { attrs!{ At::Disabled => true } },
+            ],
+            input![
+                id!["config-file"],
+                attrs![
+                    At::Type => "file",
+                    At::Accept => ".json",
+                ],
+                style![
+                    St::Display => "none",
+                ],
+                ev(Ev::Input, |e| Msg::BulkConfig(e)),
+            ],
+        ],
+    ]
+}
+
+fn view_firmware(model: &Model) -> Node<Msg> {
+    let info = match &model.upload_info {
+        Some(info) => info,
+        None => return empty![],
+    };
+    let layout = model.device.profile().layout.as_ref();
+    div![
+        C!["container"],
+        if let Some(fw) = &model.upload_data {
+            div![
+                format!("File {:?}, {} bytes", fw.format, fw.size()),
+                ul![
+                    fw.segments.iter().map(|s| {
+                        li![format!("0x{:08x}..0x{:08x}", s.addr, s.end())]
+                    }).collect::<Vec<_>>()
+                ],
+            ]
+        } else {
+            empty![]
+        },
+        div![format!("Firmware: {} of {} bytes", info.size, info.capacity)],
+        div![format!("Stack 0x{:08x}, reset 0x{:08x}", info.stack, info.reset)],
         div![
-            canvas![
-                id!("canvas"),
+            match (info.crc, layout.and_then(|l| l.crc_magic.as_ref())) {
+                (dfu::image::Crc::Ok(crc), _) => format!("CRC 0x{:08x} matches", crc),
+                (dfu::image::Crc::Missing(crc), Some(_)) => format!("CRC 0x{:08x}, not stored in the image, integrity not checked", crc),
+                (dfu::image::Crc::Missing(crc), None) => format!("CRC 0x{:08x}, integrity check unavailable: no CRC magic in the device profile", crc),
+            }
+        ],
+        if !info.build.is_empty() {
+            ul![
+                info.build.iter().map(|(k, v)| li![format!("{}: {}", k, v)]).collect::<Vec<_>>()
+            ]
+        } else if layout.map_or(false, |l| l.build_magic.is_some()) {
+            div!["No build info found, the version after the update is not checked"]
+        } else {
+            div!["Build info check unavailable: no build magic in the device profile, the version after the update is not checked"]
+        },
+        button![
+            simple_ev(Ev::Click, Msg::DfuConfirm),
+            "Flash",
+        ],
+        button![
+            simple_ev(Ev::Click, Msg::DfuCancel),
+            "Cancel",
+        ],
+    ]
+}
+
+fn view_update(model: &Model) -> Node<Msg> {
+    let up = match &model.update {
+        Some(up) => up,
+        None => return empty![],
+    };
+    div![
+        C!["container"],
+        span![up.step.describe()],
+        match up.step {
+            update::Step::SelectLoader | update::Step::SelectApp => {
+                button![
+                    simple_ev(Ev::Click, Msg::UpdateSelect),
+                    "Select",
+                ]
+            }
+            _ => empty![],
+        },
+    ]
+}
+
+fn view_reo(model: &Model) -> Node<Msg> {
+    let live = *model.vis_reo.borrow();
+    div![
+        C!["container"],
+        if let Some(rate) = live.breath_rate {
+            div![
+                format!("Breathing: {:.0}/min", rate),
+                if let Some(dz) = live.cardiac_dz {
+                    format!(", ΔZ: {:.0}", dz)
+                } else { String::new() },
+                if live.apnea {
+                    span![
+                        style![St::Color => "#ff4136"],
+                        " — breathing pause",
+                    ]
+                } else { empty![] },
+            ]
+        } else {
+            empty![]
+        },
+        if let Some(report) = &model.reo_report {
+            div![
+                format!(
+                    "Recording: mean breathing rate {}, pauses: {}, ΔZ cycles: {}",
+                    report.mean_breath_rate.map_or("-".into(), |r| format!("{:.1}/min", r)),
+                    report.apneas.len(),
+                    report.cardiac.len(),
+                ),
+                ul![
+                    report.apneas.iter().map(|a| {
+                        li![format!("Pause {:.0}-{:.0} s ({:.0} s)", a.start, a.end, a.end - a.start)]
+                    }).collect::<Vec<_>>()
+                ],
+            ]
+        } else {
+            empty![]
+        },
+    ]
+}
+
+fn view_acc(model: &Model) -> Node<Msg> {
+    use analysis::acc::{Activity, Position};
+
+    fn color(a: Activity) -> &'static str {
+        match a {
+            Activity::Rest     => "#c0c0c0",
+            Activity::Light    => "#2ecc40",
+            Activity::Moderate => "#ffdc00",
+            Activity::Vigorous => "#ff4136",
+        }
+    }
+
+    let live = *model.vis_acc.borrow();
+    let total = model.acc_timeline.last().map_or(0., |s| s.end);
+    div![
+        C!["container"],
+        if live.position != Position::Unknown {
+            div![
+                format!(
+                    "Position: {:?}, activity: {:?} ({:.2} g), steps: {:.0}/min",
+                    live.position, live.activity, live.intensity, live.steps_per_min,
+                )
+            ]
+        } else {
+            empty![]
+        },
+        if total > 0. {
+            div![
+                style![
+                    St::Display => "flex",
+                    St::Height => px(20),
+                ],
+                model.acc_timeline.iter().map(|seg| {
+                    let (position, activity) = seg.value;
+                    div![
+                        attrs!{
+                            At::Title => format!("{:.0}-{:.0} s: {:?}, {:?}", seg.start, seg.end, position, activity),
+                        },
+                        style![
+                            St::Width => format!("{}%", (seg.end - seg.start) / total * 100.),
+                            St::BackgroundColor => color(activity),
+                        ],
+                    ]
+                }).collect::<Vec<_>>()
             ]
+        } else {
+            empty![]
+        },
+    ]
+}
+
+fn view_stats(stats: &vis::stats::LinkStats) -> Node<Msg> {
+    let s = stats.snapshot(js_sys::Date::now());
+    if s.blocks == 0 {
+        return empty![];
+    }
+    div![
+        C!["container"],
+        span!["Stream statistics:"],
+        ul![
+            li![format!("Time: {:.0} s", s.duration_sec)],
+            li![format!("Blocks: {} ({:.1} /s)", s.blocks, s.blocks_per_sec)],
+            li![format!("Data: {} B ({:.1} kB/s)", s.bytes, s.bytes_per_sec / 1024.)],
+            li![format!("Decode errors: {}", s.decode_errors)],
+            li![format!("Gaps: {} ({} blocks lost)", s.gaps, s.lost_blocks)],
+            if let Some(e) = &s.last_error {
+                li![format!("Last error: {}", e)]
+            } else {
+                empty![]
+            },
+        ],
+        button![
+            simple_ev(Ev::Click, Msg::ExportStats),
+            "Export",
+        ],
+    ]
+}
+
+fn view_history(model: &Model) -> Node<Msg> {
+    let ctl = model.vis_ctl.borrow();
+    let paused = ctl.is_paused();
+    let frozen_only = if !paused {
+        attrs!{
+            At::Disabled => true
+        }
+    } else {
+        attrs!{}
+    };
+    div![
+        C!["container"],
+        button![
+            simple_ev(Ev::Click, Msg::VisPause),
+            if paused { "Resume" } else { "Pause" },
+        ],
+        button![
+            simple_ev(Ev::Click, Msg::VisScroll(5.)),
+            "<< 5 s",
+            frozen_only.clone(),
+        ],
+        button![
+            simple_ev(Ev::Click, Msg::VisScroll(-5.)),
+            "5 s >>",
+            frozen_only.clone(),
+        ],
+        button![
+            simple_ev(Ev::Click, Msg::VisClearCursors),
+            "Clear cursors",
+            frozen_only,
+        ],
+        button![
+            "Open recording",
+            ev(Ev::Click, |_| {
+                let elem: web_sys::HtmlElement = web_sys::window()
+                    .unwrap()
+                    .document()
+                    .unwrap()
+                    .get_element_by_id("open-recording")
+                    .unwrap()
+                    .dyn_into().unwrap();
+                elem.click();
+                ()
+            }),
+        ],
+        input![
+            id!["open-recording"],
+            attrs![
+                At::Type => "file",
+            ],
+            style![
+                St::Display => "none",
+            ],
+            ev(Ev::Input, |e| Msg::OpenRecording(e)),
+        ],
+        if let Some(m) = ctl.measurement(&model.vis_history.borrow()) {
+            span![m.to_string()]
+        } else {
+            empty![]
+        },
+    ]
+}
+
+fn view_events(events: &[event::Record]) -> Node<Msg> {
+    if events.is_empty() {
+        return empty![];
+    }
+    div![
+        C!["container"],
+        span!["Events:"],
+        ul![
+            events.iter().map(|r| {
+                li![
+                    format!("{} [{:.1} s] {}", r.time_str(), r.offset, r.event.describe())
+                ]
+            }).collect::<Vec<_>>()
+        ]
+    ]
+}
+
+fn view_quality(quality: &[vis::quality::ChannelQuality], selected: VisSelectedGroup, height: u32) -> Node<Msg> {
+    // Indicators are aligned with ECG channel lanes of the canvas layout
+    let panels = vis::panel::layout(&selected.groups(), 0, height as f32);
+    let ecg = match panels.iter().find(|p| p.group == vis::Group::ECG) {
+        Some(ecg) => ecg,
+        None => return empty![],
+    };
+    div![
+        style![
+            St::PaddingTop => px(ecg.top),
+            St::Width => px(24),
         ],
+        quality.iter().map(|q| {
+            div![
+                style![
+                    St::Height => px(ecg.lane()),
+                    St::Display => "flex",
+                    St::AlignItems => "center",
+                ],
+                div![
+                    attrs!{
+                        At::Title => q.describe(),
+                    },
+                    style![
+                        St::Width => px(16),
+                        St::Height => px(16),
+                        St::BorderRadius => "50%",
+                        St::BackgroundColor => q.color(),
+                    ],
+                ]
+            ]
+        }).collect::<Vec<_>>()
     ]
 }
 
@@ -474,6 +2336,22 @@ enum VisSelectedGroup {
     ECG,
     REO,
     ACC_IN,
+    ALL,
+}
+
+impl VisSelectedGroup {
+    fn groups(self) -> Vec<vis::Group> {
+        match self {
+            VisSelectedGroup::ECG => vec![vis::Group::ECG],
+            VisSelectedGroup::REO => vec![vis::Group::REO],
+            VisSelectedGroup::ACC_IN => vec![vis::Group::ACC_IN],
+            VisSelectedGroup::ALL => vec![vis::Group::ECG, vis::Group::REO, vis::Group::ACC_IN],
+        }
+    }
+
+    fn shows(self, group: vis::Group) -> bool {
+        self == VisSelectedGroup::ALL || self.groups()[0] == group
+    }
 }
 
 impl Default for VisSelectedGroup {
@@ -482,13 +2360,40 @@ impl Default for VisSelectedGroup {
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum VisRecordMode {
+    Off,
+    Raw,
+    RawCsv,
+}
+
+impl Default for VisRecordMode {
+    fn default() -> Self {
+        Self::Off
+    }
+}
+
+impl From<String> for VisRecordMode {
+    fn from(v: String) -> Self {
+        match v.as_str() {
+            "Raw" => VisRecordMode::Raw,
+            "RawCsv" => VisRecordMode::RawCsv,
+            _ => VisRecordMode::Off,
+        }
+    }
+}
+
 impl From<String> for VisSelectedGroup {
     fn from(v: String) -> Self {
         match v.as_str() {
             "ECG" => VisSelectedGroup::ECG,
             "REO" => VisSelectedGroup::REO,
             "ACC_IN" => VisSelectedGroup::ACC_IN,
-            _ => unimplemented!(),
+            "ALL" => VisSelectedGroup::ALL,
+            v => {
+                log::warn!("Unknown vis group {:?}", v);
+                VisSelectedGroup::default()
+            }
         }
     }
 }
@@ -504,11 +2409,18 @@ impl From<String> for VisSelectedGroup {
 //    ]
 //}
 
-//fn after_mount(_url: Url, orders: &mut impl Orders<Msg>) -> AfterMount<Model> {
-//    orders
-//        .perform_cmd(async {});
-//    AfterMount::default()
-//}
+fn after_mount(_url: Url, orders: &mut impl Orders<Msg>) -> AfterMount<Model> {
+    let (attached, detached) = (orders.msg_sender(), orders.msg_sender());
+    device::Device::watch(
+        move |desc| attached(Some(Msg::UsbAttached(desc))),
+        move |desc| detached(Some(Msg::UsbDetached(desc))),
+    );
+    orders.perform_cmd(async {
+        Msg::RegistryLoaded(Rc::new(Registry::load().await))
+    });
+    orders.perform_cmd(heartbeat_tick());
+    AfterMount::default()
+}
 
 // ------ ------
 // Bindings
@@ -516,7 +2428,6 @@ impl From<String> for VisSelectedGroup {
 
 #[wasm_bindgen]
 extern "C" {
-    fn alert(s: &str);
     fn js_debug(v: &JsValue);
 }
 
@@ -530,7 +2441,7 @@ pub fn render() {
 
     App::builder(update, view)
         //.window_events(window_events)
-//        .after_mount(after_mount)
+        .after_mount(after_mount)
         .build_and_start();
 }
 
@@ -0,0 +1,150 @@
+use std::rc::Rc;
+
+use futures::future::{self, Either};
+use gloo_timers::future::TimeoutFuture;
+use ellocopo2::owned::{Msg as DevMsg, Value};
+use ellocopo2::AnswerCode;
+
+use crate::device;
+
+pub const HEARTBEAT_MS: u32 = 2000;
+// Misses in a row before the handle is given up
+const LOST_AFTER: u32 = 3;
+// Round trip above this marks the link degraded
+const SLOW_MS: f64 = 300.;
+// Heartbeat without an answer by then is a miss
+const PROBE_TIMEOUT_MS: u32 = 1500;
+// A pending transfer proves the link only while others complete
+const STALL_MS: f64 = 3. * HEARTBEAT_MS as f64;
+// Picker left open or closed without a choice
+const CONNECT_TIMEOUT_MS: f64 = 30_000.;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum State {
+    Disconnected,
+    // Since when, ms since the epoch
+    Connecting(f64),
+    Ready,
+    // Transfer in flight, it proves the link by itself
+    Busy,
+    Degraded(String),
+    Lost(String),
+}
+
+impl Default for State {
+    fn default() -> Self {
+        State::Disconnected
+    }
+}
+
+impl State {
+    pub fn describe(&self) -> String {
+        match self {
+            State::Disconnected => "Not connected".into(),
+            State::Connecting(_) => "Connecting".into(),
+            State::Ready => "Ready".into(),
+            State::Busy => "Busy".into(),
+            State::Degraded(why) => format!("Link unstable: {}", why),
+            State::Lost(why) => format!("Link lost: {}", why),
+        }
+    }
+}
+
+/// Health of the active device's connection
+#[derive(Default)]
+pub struct Link {
+    pub state: State,
+    pub latency: Option<f64>,
+    misses: u32,
+}
+
+impl Link {
+    pub fn connecting(&mut self) {
+        if let State::Disconnected | State::Lost(_) = self.state {
+            self.state = State::Connecting(js_sys::Date::now());
+        }
+    }
+
+    pub fn connected(&mut self) {
+        self.misses = 0;
+        self.latency = None;
+        self.state = State::Ready;
+    }
+
+    /// Follows the device between heartbeats
+    pub fn refresh(&mut self, device: &device::Device) {
+        self.state = match &self.state {
+            _ if device.is_connected() && is_active(device) => State::Busy,
+            _ if device.is_connected() => match &self.state {
+                State::Degraded(why) => State::Degraded(why.clone()),
+                _ => State::Ready,
+            },
+            State::Connecting(since) if js_sys::Date::now() - since < CONNECT_TIMEOUT_MS => State::Connecting(*since),
+            _ => match device.lost_reason() {
+                Some(why) => State::Lost(why),
+                None => State::Disconnected,
+            },
+        };
+    }
+
+    /// Takes a heartbeat result, true when the handle should be given up
+    pub fn beat(&mut self, r: Result<f64, device::Error>) -> bool {
+        match r {
+            Ok(ms) => {
+                self.misses = 0;
+                self.latency = Some(ms);
+                self.state = if ms > SLOW_MS {
+                    State::Degraded(format!("answer took {:.0} ms", ms))
+                } else {
+                    State::Ready
+                };
+                false
+            }
+            // Device already dropped the handle
+            Err(e) if e.is_gone() => {
+                self.state = State::Lost(format!("{:?}", e));
+                false
+            }
+            Err(e) => {
+                self.misses += 1;
+                if self.misses >= LOST_AFTER {
+                    self.state = State::Lost(format!("{} requests in a row unanswered", self.misses));
+                    true
+                } else {
+                    self.state = State::Degraded(format!("no answer ({:?})", e));
+                    false
+                }
+            }
+        }
+    }
+
+    pub fn is_lost(&self) -> bool {
+        match self.state {
+            State::Lost(_) => true,
+            _ => false,
+        }
+    }
+}
+
+/// Transfers are in flight and still completing, no heartbeat needed
+///
+/// A transfer pending with nothing finished for a while, like a vis read
+/// from a silent device, doesn't count.
+pub fn is_active(device: &device::Device) -> bool {
+    device.is_busy() && !is_stalled(device)
+}
+
+/// Nothing completed for long enough that a running flow is suspect
+pub fn is_stalled(device: &device::Device) -> bool {
+    device.last_seen().map_or(true, |t| js_sys::Date::now() - t >= STALL_MS)
+}
+
+/// Reads a cheap register and returns the round trip in ms, the inspector and capture don't see it
+pub async fn heartbeat(device: Rc<device::Device>) -> Result<f64, device::Error> {
+    let start = js_sys::Date::now();
+    let probe = device.probe(DevMsg(AnswerCode::OK_READ, String::from("/time"), Value::UNIT(())));
+    match future::select(Box::pin(probe), TimeoutFuture::new(PROBE_TIMEOUT_MS)).await {
+        Either::Left((r, _)) => r.map(|_| js_sys::Date::now() - start),
+        Either::Right(_) => Err(device::Error::Timeout),
+    }
+}
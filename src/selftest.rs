@@ -0,0 +1,241 @@
+use std::fmt::Write;
+use std::rc::Rc;
+
+use serde::Deserialize;
+
+use ellocopo2::owned::{Msg as DevMsg, Value};
+use ellocopo2::AnswerCode;
+
+use crate::dbg;
+use crate::device;
+use crate::download::BLOCK_SIZE;
+
+/// Largest value payload a single protocol message carries
+pub const MAX_PAYLOAD: usize = 256;
+const ECHO_ROUNDS: usize = 200;
+// File reads are timed per transfer
+const FILE_TRANSFERS: u32 = 8;
+const TRANSFER_BLOCKS: u32 = 16;
+
+/// Pass limits of the production line, `selftest` of the device profile
+///
+/// Without them the timings are only reported.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct Limits {
+    #[serde(default)]
+    pub max_cmd_p99_ms: Option<f64>,
+    #[serde(default)]
+    pub min_file_kib_s: Option<f64>,
+}
+
+/// Percentiles of round-trip times, ms
+#[derive(Debug, Clone, Default)]
+pub struct Latency {
+    pub p50: f64,
+    pub p90: f64,
+    pub p99: f64,
+    pub max: f64,
+}
+
+impl Latency {
+    fn from_samples(mut samples: Vec<f64>) -> Self {
+        if samples.is_empty() {
+            return Self::default();
+        }
+        samples.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        // Nearest rank
+        let at = |p: f64| samples[((p * samples.len() as f64).ceil() as usize).max(1) - 1];
+        Self {
+            p50: at(0.5),
+            p90: at(0.9),
+            p99: at(0.99),
+            max: *samples.last().unwrap(),
+        }
+    }
+}
+
+impl std::fmt::Display for Latency {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "p50 {:.1} ms, p90 {:.1} ms, p99 {:.1} ms, max {:.1} ms", self.p50, self.p90, self.p99, self.max)
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Report {
+    pub serial: String,
+    pub rounds: usize,
+    pub mismatches: usize,
+    pub errors: usize,
+    pub cmd_latency: Latency,
+    // Payload bytes both ways per second
+    pub cmd_bytes_s: f64,
+    pub file_bytes: usize,
+    pub file_latency: Latency,
+    pub file_bytes_s: f64,
+    pub failures: Vec<String>,
+}
+
+impl Report {
+    pub fn passed(&self) -> bool {
+        self.failures.is_empty()
+    }
+
+    /// Plain text for the line test log
+    pub fn to_text(&self) -> String {
+        let mut s = String::new();
+        let _ = writeln!(s, "Device: {}", self.serial);
+        let _ = writeln!(s, "Result: {}", if self.passed() { "PASS" } else { "FAIL" });
+        let _ = writeln!(s, "Echo rounds: {}, mismatches: {}, errors: {}", self.rounds, self.mismatches, self.errors);
+        let _ = writeln!(s, "Echo latency: {}", self.cmd_latency);
+        let _ = writeln!(s, "Echo throughput: {:.0} B/s", self.cmd_bytes_s);
+        let _ = writeln!(s, "File read: {} bytes, {:.0} KiB/s", self.file_bytes, self.file_bytes_s / 1024.);
+        let _ = writeln!(s, "File transfer latency: {}", self.file_latency);
+        for f in &self.failures {
+            let _ = writeln!(s, "FAIL: {}", f);
+        }
+        s
+    }
+}
+
+fn now() -> f64 {
+    web_sys::window().unwrap().performance().unwrap().now()
+}
+
+/// Random length, the first rounds cover the edges
+fn payload(round: usize) -> Vec<u8> {
+    let len = match round {
+        0 => 1,
+        1 => MAX_PAYLOAD,
+        _ => 1 + (js_sys::Math::random() * MAX_PAYLOAD as f64) as usize % MAX_PAYLOAD,
+    };
+    (0 .. len).map(|_| (js_sys::Math::random() * 256.) as u8).collect()
+}
+
+/// Sends the payload to `/test/echo` and returns what came back
+async fn echo(device: &Rc<device::Device>, data: Vec<u8>) -> Result<Vec<u8>, device::Error> {
+    let ans = device
+        .send_recv_cmd(DevMsg(AnswerCode::OK_WRITE, String::from("/test/echo"), Value::BYTES(data)))
+        .await?;
+    match ans.2 {
+        Value::BYTES(back) => Ok(back),
+        // Firmware that only stores the value, read it back
+        _ => match device
+            .send_recv_cmd(DevMsg(AnswerCode::OK_READ, String::from("/test/echo"), Value::UNIT(())))
+            .await?
+            .2
+        {
+            Value::BYTES(back) => Ok(back),
+            _ => Ok(Vec::new()),
+        },
+    }
+}
+
+/// Echo round-trips followed by bulk file reads
+pub async fn run(device: Rc<device::Device>) -> Report {
+    let mut report = Report {
+        serial: device.descriptor().map(|d| d.serial().to_string()).unwrap_or_default(),
+        ..Report::default()
+    };
+    let limits = device.profile().selftest.clone().unwrap_or_default();
+
+    let mut samples = Vec::with_capacity(ECHO_ROUNDS);
+    let mut moved = 0;
+    let started = now();
+    for round in 0 .. ECHO_ROUNDS {
+        let data = payload(round);
+        let t = now();
+        match echo(&device, data.clone()).await {
+            Ok(back) => {
+                samples.push(now() - t);
+                moved += data.len() + back.len();
+                if back != data {
+                    log::error!("Echo mismatch in round {}: sent {} bytes, got {}", round, data.len(), back.len());
+                    report.mismatches += 1;
+                }
+            }
+            Err(e) => {
+                log::error!("Echo round {}: {:?}", round, e);
+                report.errors += 1;
+                if !device.is_connected() {
+                    report.failures.push("connection lost during the echo test".into());
+                    break;
+                }
+            }
+        }
+        report.rounds += 1;
+    }
+    report.cmd_bytes_s = moved as f64 * 1000. / (now() - started);
+    report.cmd_latency = Latency::from_samples(samples);
+
+    if report.mismatches > 0 {
+        report.failures.push(format!("{} answers did not match the request", report.mismatches));
+    }
+    if report.errors > 0 {
+        report.failures.push(format!("{} requests unanswered", report.errors));
+    }
+    match limits.max_cmd_p99_ms {
+        Some(max) if report.cmd_latency.p99 > max => {
+            report.failures.push(format!("p99 latency {:.1} ms above {} ms", report.cmd_latency.p99, max));
+        }
+        _ => (),
+    }
+
+    if device.is_connected() {
+        if let Err(e) = file_reads(&device, &limits, &mut report).await {
+            report.failures.push(format!("file read: {}", e));
+        }
+    }
+    log::info!("Self-test of {}:\n{}", report.serial, report.to_text());
+    report
+}
+
+async fn file_reads(device: &Rc<device::Device>, limits: &Limits, report: &mut Report) -> Result<(), String> {
+    let blocks = FILE_TRANSFERS * TRANSFER_BLOCKS;
+    let setup = [
+        ("/io/file/pos", Value::U32(0)),
+        ("/io/file/len", Value::U32(blocks)),
+        ("/io/file/start", Value::UNIT(())),
+    ];
+    for (path, value) in setup.iter() {
+        let ans = device
+            .send_recv_cmd(DevMsg(AnswerCode::OK_WRITE, path.to_string(), value.clone()))
+            .await
+            .map_err(|e| format!("no answer to {}: {:?}", path, e))?;
+        match ans {
+            DevMsg(AnswerCode::OK_WRITE, _, _) => (),
+            DevMsg(AnswerCode::ERR_CUSTOM, _, Value::U32(code)) => {
+                return Err(format!("{}: {}", path, dbg::decode(code)));
+            }
+            DevMsg(code, _, _) => return Err(format!("{}: answer {:?}", path, code)),
+        }
+    }
+
+    let size = TRANSFER_BLOCKS * BLOCK_SIZE;
+    let mut samples = Vec::with_capacity(FILE_TRANSFERS as usize);
+    let started = now();
+    for i in 0 .. FILE_TRANSFERS {
+        let t = now();
+        let buf = device
+            .recv_file_block(size)
+            .await
+            .map_err(|e| format!("transfer {}: {:?}", i, e))?;
+        samples.push(now() - t);
+        if buf.len() != size as usize {
+            return Err(format!("transfer {}: {} bytes instead of {}", i, buf.len(), size));
+        }
+        report.file_bytes += buf.len();
+    }
+    report.file_bytes_s = report.file_bytes as f64 * 1000. / (now() - started);
+    report.file_latency = Latency::from_samples(samples);
+
+    match limits.min_file_kib_s {
+        Some(min) if report.file_bytes_s / 1024. < min => {
+            report.failures.push(format!(
+                "file read speed {:.0} KiB/s below {} KiB/s",
+                report.file_bytes_s / 1024., min,
+            ));
+        }
+        _ => (),
+    }
+    Ok(())
+}
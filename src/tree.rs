@@ -2,7 +2,6 @@
 use std::collections::HashMap;
 use std::rc::Rc;
 use std::cell::RefCell;
-use std::convert::TryInto;
 
 use seed::{*, prelude::*};
 use serde_json::Value as JsonValue;
@@ -14,7 +13,7 @@ use ellocopo2::TypeTag;
 use ellocopo2::RequestCode;
 use ellocopo2::AnswerCode;
 
-use holter_support::error::Error as HolterError;
+use crate::error::Error;
 
 mod parse;
 
@@ -38,15 +37,26 @@ pub enum Msg {
     GAnswerUpdate(Result<DevMsg, String>),
     InputUpdated(String, String),
     FoldNode(Rc<RefCell<TNode>>),
+    // Re-reads every value shown before a reconnect
+    Refresh,
+    // Reported to the app, the tree keeps going
+    Failed(Error),
 }
 
 
 pub fn update(msg: Msg, model: &mut Model, orders: &mut impl Orders<Msg>) {
     match msg {
         Msg::SetScheme(scheme) => {
-            let scheme: JsonValue = serde_json::from_str(&scheme).unwrap();
-            //log!(&scheme);
-            let trees = parse::tree_model(scheme).unwrap();
+            let trees = serde_json::from_str::<JsonValue>(&scheme)
+                .map_err(|e| e.to_string())
+                .and_then(parse::tree_model);
+            let trees = match trees {
+                Ok(trees) => trees,
+                Err(e) => {
+                    orders.send_msg(Msg::Failed(Error::Scheme(e)));
+                    return;
+                }
+            };
             //log!(&trees);
             let leafs = parse::build_view_leaf(&trees);
             //log!(&leafs);
@@ -64,7 +74,8 @@ pub fn update(msg: Msg, model: &mut Model, orders: &mut impl Orders<Msg>) {
                         val
                     }
                     Err(err) => {
-                        return crate::alert(&format!("Failed to parse: {:?}", err));
+                        orders.send_msg(Msg::Failed(Error::Input(format!("{}: {}", path, err))));
+                        return;
                     }
                 }
             } else { Value::UNIT(()) };
@@ -76,22 +87,30 @@ pub fn update(msg: Msg, model: &mut Model, orders: &mut impl Orders<Msg>) {
             
             match ans_res {
                 Ok(DevMsg(AnswerCode::OK_READ, path, inval)) => {
-                    let TLeaf{view: ViewLeaf{val, ..}, ..} = &mut *model.leafs[&path].borrow_mut();
-                    *val = Some(inval);
+                    match model.leafs.get(&path) {
+                        Some(leaf) => {
+                            let TLeaf{view: ViewLeaf{val, ..}, ..} = &mut *leaf.borrow_mut();
+                            *val = Some(inval);
+                        }
+                        None => {
+                            let reason = "path is not in the scheme".to_string();
+                            orders.send_msg(Msg::Failed(Error::Answer { path, reason }));
+                        }
+                    }
                 }
                 Ok(DevMsg(AnswerCode::OK_WRITE, path, val)) => {
                     log::info!("OK_WRITE {} {:?}", path, val)
                 }
-                Ok(DevMsg(AnswerCode::ERR_CUSTOM, _path, val)) => {
-                    if let Value::U32(code) = val {
-                        let err: HolterError = code.try_into().unwrap();
-                        crate::alert(&format!("Custom error: {:?}", err));
+                Ok(DevMsg(AnswerCode::ERR_CUSTOM, path, val)) => {
+                    let e = if let Value::U32(code) = val {
+                        Error::Protocol { path, code }
                     } else {
-                        panic!("Bad custom error format");
-                    }
+                        Error::Answer { path, reason: format!("bad custom error format {:?}", val) }
+                    };
+                    orders.send_msg(Msg::Failed(e));
                 }
-                Err(err) => {
-                    crate::alert(&format!("Answer update error: {}", err));
+                Err(reason) => {
+                    orders.send_msg(Msg::Failed(Error::Answer { path: String::new(), reason }));
                 }
                 _ => (),
             }
@@ -105,10 +124,34 @@ pub fn update(msg: Msg, model: &mut Model, orders: &mut impl Orders<Msg>) {
             let fold = &mut node.borrow_mut().view.fold;
             *fold = !*fold;
         }
+        Msg::Refresh => {
+            for (path, leaf) in &model.leafs {
+                if leaf.borrow().view.val.is_some() {
+                    let msg = DevMsg(RequestCode::READ.into(), path.clone(), Value::UNIT(()));
+                    orders.send_msg(Msg::GRequestUpdate(msg));
+                }
+            }
+        }
         msg @ _ => log!(msg),
     }
 }
 
+impl Model {
+    /// No scheme loaded yet
+    pub fn is_empty(&self) -> bool {
+        self.trees.is_empty()
+    }
+
+    /// Converts text to the scheme type of `path`, for writes outside the tree view
+    pub fn parse_value(&self, path: &str, input: &str) -> Result<Value, String> {
+        let leaf = self.leafs
+            .get(path)
+            .ok_or_else(|| format!("{} is not in the scheme", path))?;
+        let ty = leaf.borrow().ty;
+        string_to_value(input, ty).map_err(|e| format!("{}: {}", path, e))
+    }
+}
+
 pub fn view(model: &Model) -> Node<Msg> {
     ul![ 
         span!["Список комманд:"],
@@ -47,10 +47,10 @@ fn visit_tree(path: &String, name: &String, value: &JsonValue, meta: MetaDesc) -
 }
 
 fn visit_node(path: &String, name: &String, fields: &Map<String, JsonValue>, meta: MetaDesc) -> Result<Tree, String> {
-    let meta = extract_meta(fields, meta);
+    let meta = extract_meta(fields, meta)?;
     
     // Test for nested register definition
-    let res = match extract_ty(fields) {
+    let res = match extract_ty(fields)? {
         // It's nested register definition, proceed to creating a leaf
         Some(ty) => {
             visit_leaf(path, name, ty, meta)?
@@ -96,35 +96,34 @@ fn visit_leaf(path: &String, name: &String, ty: TypeTag, meta: MetaDesc) -> Resu
     }))))
 }
 
-fn extract_ty(fields: &Map<String, JsonValue>) -> Option<TypeTag> {
+fn extract_ty(fields: &Map<String, JsonValue>) -> Result<Option<TypeTag>, String> {
     let mut ty = None;
     for (k,v) in fields {
         if k.starts_with(ANNOTATION_TYPE_STR) {
             if let JsonValue::String(tyy) = v {
-                ty = Some(ty_convert(tyy).unwrap());
+                ty = Some(ty_convert(tyy)?);
             } else  {
-                panic!("Wrong type in @type")
+                Err(format!("Wrong type in @type: {:?}", v))?
             }
         }
     }
-    ty
+    Ok(ty)
 }
 
-fn extract_meta(fields: &Map<String, JsonValue>, inhereted_meta: MetaDesc) -> MetaDesc {
+fn extract_meta(fields: &Map<String, JsonValue>, inhereted_meta: MetaDesc) -> Result<MetaDesc, String> {
     let mut meta = inhereted_meta;
     for (k,v) in fields {
         if k.starts_with(ANNOTATION_ACCESS_STR) {
             if let JsonValue::String(rights) = v {
-                let Access{ w, r} = access_convert(rights)
-                    .expect("Malformed access rights format");
+                let Access{ w, r} = access_convert(rights)?;
                 meta.w = w;
                 meta.r = r;
             } else  {
-                panic!("Malformed access rights inner type")
+                Err(format!("Malformed access rights inner type: {:?}", v))?
             }
         }
     }
-    meta
+    Ok(meta)
 }
 
 fn ty_convert(tytag: &String) -> Result<TypeTag, String> {
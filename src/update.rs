@@ -0,0 +1,127 @@
+use std::rc::Rc;
+
+use gloo_timers::future::TimeoutFuture;
+
+use ellocopo2::owned::{Msg as DevMsg, Value};
+use ellocopo2::AnswerCode;
+
+use crate::cmd;
+use crate::device;
+use crate::device::registry::Registry;
+use crate::dfu;
+use crate::download;
+
+// Re-enumeration after goto_loader or a reset
+const WAIT_DEVICE_MS: u32 = 10_000;
+const WAIT_POLL_MS: u32 = 250;
+
+/// Firmware update started from application mode
+#[derive(Debug, Clone, PartialEq)]
+pub enum Step {
+    EnterLoader,
+    WaitLoader,
+    // Loader was never permitted, picker needs a click
+    SelectLoader,
+    Backup,
+    Flash,
+    WaitApp,
+    SelectApp,
+    CheckVersion,
+    Done(String),
+    // Image had no build info, nothing to compare the running version with
+    Unchecked(String),
+    Failed(String),
+}
+
+impl Step {
+    pub fn is_running(&self) -> bool {
+        match self {
+            Step::Done(_) | Step::Unchecked(_) | Step::Failed(_) => false,
+            _ => true,
+        }
+    }
+
+    /// Device type the flow waits for, if any
+    pub fn awaits(&self) -> Option<device::Type> {
+        match self {
+            Step::WaitLoader | Step::SelectLoader => Some(device::Type::Loader),
+            Step::WaitApp | Step::SelectApp => Some(device::Type::Holter),
+            _ => None,
+        }
+    }
+
+    pub fn describe(&self) -> String {
+        match self {
+            Step::EnterLoader => "Entering the loader".into(),
+            Step::WaitLoader => "Waiting for the loader".into(),
+            Step::SelectLoader => "Select the loader in the device list".into(),
+            Step::Backup => "Saving the current firmware".into(),
+            Step::Flash => "Writing the firmware".into(),
+            Step::WaitApp => "Waiting for the firmware to start".into(),
+            Step::SelectApp => "Select the device in the list".into(),
+            Step::CheckVersion => "Checking the version".into(),
+            Step::Done(version) => format!("Updated, version {}", version),
+            Step::Unchecked(version) => format!("Updated, running {}, version not checked: the image has no build info", version),
+            Step::Failed(reason) => format!("Update failed: {}", reason),
+        }
+    }
+}
+
+pub struct Update {
+    pub step: Step,
+    // Serial of the recorder being updated, the fleet may hold others
+    pub serial: String,
+    pub image: Rc<dfu::container::Firmware>,
+    // Version from the image build info, only targets whose profile
+    // names a build record have one, the running version is shown unchecked otherwise
+    pub expected: Option<String>,
+}
+
+/// Asks the application to reboot into the loader
+pub async fn enter_loader(device: Rc<device::Device>) {
+    // Device drops off the bus on reboot, a missing answer is expected
+    let _ = cmd(&device, DevMsg(AnswerCode::OK_WRITE, String::from("/ctrl/goto_loader"), Value::UNIT(()))).await;
+}
+
+/// Polls permitted devices until the one with `serial` shows up as the given type
+///
+/// Devices in `held` are open elsewhere, opening resets them, so they are never picked.
+pub async fn wait_device(
+    registry: &Registry,
+    ty: device::Type,
+    serial: &str,
+    held: &[String],
+) -> Option<device::Device> {
+    let mut waited = 0;
+    while waited < WAIT_DEVICE_MS {
+        TimeoutFuture::new(WAIT_POLL_MS).await;
+        waited += WAIT_POLL_MS;
+        let pred = |p: &device::registry::Profile, d: &device::Desc| {
+            p.kind == ty && d.serial() == serial && !held.iter().any(|h| h == d.serial())
+        };
+        match device::Device::find_permitted(registry, pred).await {
+            Ok(Some(dev)) => return Some(dev),
+            Ok(None) => (),
+            Err(e) => log::debug!("Waiting for {:?}: {:?}", ty, e),
+        }
+    }
+    log::warn!("{:?} not found among permitted devices", ty);
+    None
+}
+
+/// Saves the flash contents before anything is overwritten
+pub async fn backup(device: &device::Device, dfu: &dfu::Dfu<'_>) -> Result<(), String> {
+    let image = dfu.upload().await.map_err(|e| format!("backup: {}", e))?;
+    let name = format!("holter-firmware-backup-{}.bin", device.descriptor().map_or("", |d| d.serial()));
+    download::download_file(name, image).await.map_err(|e| format!("backup: {:?}", e))?;
+    Ok(())
+}
+
+/// Reads `/build/version` of the running application
+pub async fn read_version(device: Rc<device::Device>) -> Result<String, String> {
+    match cmd(&device, DevMsg(AnswerCode::OK_READ, String::from("/build/version"), Value::UNIT(()))).await {
+        Ok(Value::STR(version)) => Ok(version),
+        Ok(v) => Err(format!("unexpected /build/version answer {:?}", v)),
+        Err(()) => Err("no answer to /build/version".into()),
+    }
+}
@@ -2,12 +2,24 @@
 use std::cell::RefCell;
 use std::rc::Rc;
 use std::sync::mpsc;
-use std::collections::VecDeque;
 
 use wasm_bindgen::prelude::*;
 use wasm_bindgen::{JsCast, JsValue};
-use web_sys::{WebGlProgram, WebGlRenderingContext, WebGlShader, WebGlUniformLocation};
-use serde::Serialize;
+
+pub mod quality;
+pub mod panel;
+pub mod history;
+pub mod cursor;
+pub mod render;
+pub mod stats;
+
+pub use panel::Group;
+use history::Store;
+use cursor::{Cursor, Measurement};
+use render::TraceRenderer;
+
+// Used until the canvas reports its own size
+pub const CANVAS_HEIGHT: u32 = 900;
 
 
 fn window() -> web_sys::Window {
@@ -20,131 +32,211 @@ fn request_animation_frame(f: &Closure<dyn FnMut()>) {
         .expect("should register `requestAnimationFrame` OK");
 }
 
-pub fn vis_run(rx: mpsc::Receiver<Vec<i32>>) -> Result<(), JsValue> {
-    
-    let document = web_sys::window().unwrap().document().unwrap();
-    let canvas = document.get_element_by_id("canvas").unwrap();
-    let canvas: web_sys::HtmlCanvasElement = canvas.dyn_into::<web_sys::HtmlCanvasElement>()?;
-
-    let height = 900;//canvas.offset_height() as u32;
-    let width = canvas.offset_width() as u32;
-    canvas.set_height(height);
-    canvas.set_width(width);
-
-    // Buf
-    let mut buf = vec![VecDeque::<f32>::from(vec![0f32;width as usize * 2]);8];
-
-    // Context settings
-    #[derive(Serialize)]
-    struct CxtCfg {
-        antialias : bool,
-        depth     : bool,
-    };
+/// Data passed from the stream decoder to the renderer
+pub enum Frame {
+    // Groups to show, stacked top to bottom
+    Layout(Vec<Group>),
+    Sample(Group, Vec<i32>),
+    // Event marker at the current trace position
+    Marker,
+}
+
+/// View state shared between the UI and the renderer
+#[derive(Default)]
+pub struct Control {
+    // Renderer instance allowed to run
+    session: u32,
+    // Bumped on every change to redraw a frozen view
+    gen: u32,
+    width: usize,
+    height: u32,
+    groups: Vec<Group>,
+    // Newest sample index of every group at the moment of pause
+    frozen: Option<Vec<(Group, u64)>>,
+    // Seconds back from the freeze point
+    scroll: f32,
+    cursors: Vec<Cursor>,
+    // Marked spans of a recording, sample indexes of the group
+    spans: Vec<(Group, u64, u64)>,
+}
+
+impl Control {
+    /// Canvas height in CSS pixels
+    pub fn height(&self) -> u32 {
+        if self.height == 0 { CANVAS_HEIGHT } else { self.height }
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.frozen.is_some()
+    }
+
+    pub fn pause(&mut self, store: &Store) {
+        self.frozen = Some(
+            self.groups
+                .iter()
+                .filter_map(|&g| store.get(g).map(|h| (g, h.end())))
+                .collect()
+        );
+        self.touch();
+    }
+
+    /// Shows a decoded recording from its newest samples
+    pub fn freeze(&mut self, groups: Vec<Group>, store: &Store) {
+        self.groups = groups;
+        self.scroll = 0.;
+        self.cursors.clear();
+        self.spans.clear();
+        self.pause(store);
+    }
+
+    /// Spans drawn over the frozen view, replaces the previous ones
+    pub fn set_spans(&mut self, spans: Vec<(Group, u64, u64)>) {
+        self.spans = spans;
+        self.touch();
+    }
 
-    let cxt_cfg = CxtCfg { antialias : false, depth : false };
-    let cxt_cfg = JsValue::from_serde(&cxt_cfg).unwrap();
-
-    let context = canvas
-        //.get_context("webgl")?
-        .get_context_with_context_options("webgl", &cxt_cfg)?
-        .unwrap()
-        .dyn_into::<WebGlRenderingContext>()?;
-        
-
-    // Shaders
-    let vert_shader = compile_shader(
-        &context,
-        WebGlRenderingContext::VERTEX_SHADER,
-        r#"
-        attribute vec2 a_position;
-        uniform vec2 u_resolution;
-        uniform vec2 u_shift;
-        uniform vec2 u_xmod;
-
-        void main() 
-        {
-          vec2 Pos = a_position + u_shift; 
-          
-          //if(u_xmod.x != 0.0 )
-          //{
-          //  Pos.x = mod(Pos.x,u_xmod.x);
-          //};
-          
-          Pos.x = abs(Pos.x);
-          Pos.x = mod(Pos.x, u_resolution.x);
-
-          vec2 zeroToOne = Pos / u_resolution; // преобразуем положение в пикселях к диапазону от 0.0 до 1.0
-       
-          // преобразуем из 0->1 в 0->2
-          vec2 zeroToTwo = zeroToOne * 2.0;
-          // преобразуем из 0->2 в -1->+1 (пространство отсечения)
-          vec2 clipSpace = zeroToTwo - 1.0;
-          vec2 clipSpaceN = clipSpace * vec2(1, -1); // переворачиваем систему коооординат (0,0) в левом верхнем углу
-     
-          gl_Position = vec4(clipSpaceN, 0, 1);  
+    pub fn resume(&mut self) {
+        self.frozen = None;
+        self.scroll = 0.;
+        self.cursors.clear();
+        self.touch();
+    }
+
+    pub fn scroll(&mut self, sec: f32) {
+        self.scroll = (self.scroll + sec).max(0.);
+        self.touch();
+    }
+
+    /// Sample index at the right edge of a frozen panel
+    pub fn end(&self, group: Group) -> Option<u64> {
+        let (_, end) = self.frozen.as_ref()?.iter().find(|(g, _)| *g == group)?;
+        let back = (self.scroll * group.sample_rate()) as u64;
+        Some(end.saturating_sub(back))
+    }
+
+    /// Places a cursor at canvas coordinates, a third pick starts a new pair
+    pub fn pick(&mut self, store: &Store, x: f32, y: f32) {
+        if !self.is_paused() {
+            self.pause(store);
         }
-    "#,
-    )?;
-    let frag_shader = compile_shader(
-        &context,
-        WebGlRenderingContext::FRAGMENT_SHADER,
-        r#"
-        void main() {
-            gl_FragColor = vec4(0.0, 0.0, 0.0, 1.0);
+        let panels = panel::layout(&self.groups, self.width, self.height() as f32);
+        let cursor = panels.iter().find(|p| p.contains(y)).and_then(|p| {
+            let idx = p.x_to_idx(x, self.end(p.group)?)?;
+            Some(Cursor { group: p.group, ch: p.channel_at(y), idx })
+        });
+        if let Some(cursor) = cursor {
+            if self.cursors.len() == 2 {
+                self.cursors.clear();
+            }
+            self.cursors.push(cursor);
+            self.touch();
         }
-    "#,
-    )?;
-    let program = link_program(&context, &vert_shader, &frag_shader)?;
-    context.use_program(Some(&program));
-
-    //ATR
-    //
-    let position_attribute_location = context.get_attrib_location(&program, "a_position");
-    let resolution_uniform_location = context.get_uniform_location(&program, "u_resolution");
-    //context.get_uniform_location(&program, "u_color");
-    let shift_location = context.get_uniform_location(&program, "u_shift");
-    let xmod_location = context.get_uniform_location(&program, "u_xmod");
-
-    let position_buffer = context.create_buffer().ok_or("failed to create buffer")?;
-    context.bind_buffer(WebGlRenderingContext::ARRAY_BUFFER, Some(&position_buffer));
-
-    context.enable_vertex_attrib_array(position_attribute_location as u32);
-    context.vertex_attrib_pointer_with_i32(0, 2, WebGlRenderingContext::FLOAT, false, 0, 0);
-    context.uniform2f(resolution_uniform_location.as_ref(), width as f32, height as f32);
-    context.viewport(0,0, width as i32, height as i32);
+    }
+
+    pub fn clear_cursors(&mut self) {
+        self.cursors.clear();
+        self.touch();
+    }
+
+    pub fn measurement(&self, store: &Store) -> Option<Measurement> {
+        match self.cursors.as_slice() {
+            [a, b] => Measurement::between(store, a, b),
+            _ => None,
+        }
+    }
+
+    fn touch(&mut self) {
+        self.gen = self.gen.wrapping_add(1);
+    }
+}
+
+pub fn vis_run(
+    rx: mpsc::Receiver<Frame>,
+    ctl: Rc<RefCell<Control>>,
+    store: Rc<RefCell<Store>>,
+) 
+    -> Result<(), JsValue> 
+{
+    let canvas = render::canvas_by_id("canvas")?;
+    let mut renderer = render::create(&canvas)?;
+    let mut size = fit_canvas(&canvas, renderer.as_mut());
+    let mut panels = Vec::<panel::Panel>::new();
 
     let f = Rc::new(RefCell::new(None));
     let g = f.clone();
 
-    let mut cnt = 0usize;
+    let session = {
+        let mut c = ctl.borrow_mut();
+        c.session = c.session.wrapping_add(1);
+        c.width = size.0 as usize;
+        c.height = size.1;
+        c.touch();
+        c.session
+    };
+    let mut drawn_gen = None;
 
     *g.borrow_mut() = Some(Closure::wrap(Box::new(move || {
-        match rx.try_recv() {
-            Ok(sample) => {
-                context.clear_color(1.0, 1.0, 1.0, 1.0);
-                context.clear(WebGlRenderingContext::COLOR_BUFFER_BIT);
-
-                for i in 0 .. sample.len() {
-                    let _ = buf[i].pop_back();
-                    let _ = buf[i].pop_back();
-                    buf[i].push_front(sample[0] as f32);
-                    buf[i].push_front(cnt as f32);
-                    let (p1, p2) = buf[i].as_slices();
-
-                    draw_plot_scrolling(
-                        &context,
-                        &xmod_location,
-                        &shift_location,
-                        -(cnt as f32),
-                        100. + i as f32 * 100.0,
-                        p1, p2
-                    );
+        // Replaced by a newer renderer
+        if ctl.borrow().session != session {
+            return;
+        }
+
+        let mut dirty = false;
+        let mut alive = true;
+
+        if canvas_size(&canvas) != size {
+            size = fit_canvas(&canvas, renderer.as_mut());
+            panel::relayout(&mut panels, size.0 as usize, size.1 as f32);
+            let mut c = ctl.borrow_mut();
+            c.width = size.0 as usize;
+            c.height = size.1;
+            c.touch();
+            dirty = true;
+        }
+
+        loop {
+            match rx.try_recv() {
+                Ok(Frame::Layout(groups)) => {
+                    panels = panel::layout(&groups, size.0 as usize, size.1 as f32);
+                    let mut c = ctl.borrow_mut();
+                    c.groups = groups;
+                    c.touch();
+                    dirty = true;
+                }
+                Ok(Frame::Sample(group, sample)) => {
+                    if let Some(p) = panels.iter_mut().find(|p| p.group == group) {
+                        dirty |= p.push(&sample);
+                    }
+                }
+                Ok(Frame::Marker) => {
+                    for p in panels.iter_mut() {
+                        p.mark();
+                    }
+                }
+                Err(mpsc::TryRecvError::Empty) => break,
+                Err(mpsc::TryRecvError::Disconnected) => {
+                    alive = false;
+                    break;
                 }
-                //context.finish();
-                cnt = cnt.wrapping_add(1);
             }
-            _ => (),
-          }
+        }
+
+        let c = ctl.borrow();
+        if c.is_paused() {
+            if drawn_gen != Some(c.gen) {
+                draw_frozen(renderer.as_mut(), &panels, &c, &store.borrow());
+                drawn_gen = Some(c.gen);
+            }
+        } else {
+            // Stream decoder is gone, stop rendering
+            if !alive {
+                return;
+            }
+            if dirty || drawn_gen.is_some() {
+                drawn_gen = None;
+                draw_live(renderer.as_mut(), &panels);
+            }
+        }
 
         request_animation_frame(f.borrow().as_ref().unwrap());
     }) as Box<dyn FnMut()>));
@@ -154,102 +246,88 @@ pub fn vis_run(rx: mpsc::Receiver<Vec<i32>>) -> Result<(), JsValue> {
     Ok(())
 }
 
-pub fn draw_plot_scrolling(
-    context: &WebGlRenderingContext, 
-    xmod_location: &Option<WebGlUniformLocation>, 
-    shift_location: &Option<WebGlUniformLocation>,
-    shift_h: f32,
-    shift_v: f32,
-    part1: &[f32],
-    part2: &[f32],
-) {
-    if part1.len() != 0 {
-        let vert_array1 = unsafe { js_sys::Float32Array::view(part1) };
-        context.buffer_data_with_array_buffer_view(
-            WebGlRenderingContext::ARRAY_BUFFER,
-            &vert_array1,
-            WebGlRenderingContext::STREAM_DRAW,
-        );
+/// CSS size and device pixel ratio of the canvas
+fn canvas_size(canvas: &web_sys::HtmlCanvasElement) -> (u32, u32, u64) {
+    let height = match canvas.client_height() {
+        h if h > 0 => h as u32,
+        _ => CANVAS_HEIGHT,
+    };
+    let dpr = window().device_pixel_ratio();
+    // Ratio compared bitwise, it's only checked for changes
+    (canvas.client_width().max(1) as u32, height, dpr.to_bits())
+}
 
-        context.uniform2f(xmod_location.as_ref(), 0f32, 0f32);
-        context.uniform2f(shift_location.as_ref(), shift_h, shift_v);
-        context.draw_arrays(
-            WebGlRenderingContext::LINE_STRIP,
-            //WebGlRenderingContext::POINTS,
-            0,
-            part1.len() as i32 / 2,
-        );
-    }
-    
-    if part2.len() != 0 {
-        let vert_array2 = unsafe { js_sys::Float32Array::view(part2) };
-        context.buffer_data_with_array_buffer_view(
-            WebGlRenderingContext::ARRAY_BUFFER,
-            &vert_array2,
-            WebGlRenderingContext::STREAM_DRAW,
-        );
+/// Matches the backing store to the displayed size so traces stay sharp
+fn fit_canvas(canvas: &web_sys::HtmlCanvasElement, r: &mut dyn TraceRenderer) -> (u32, u32, u64) {
+    let size = canvas_size(canvas);
+    let (width, height, dpr) = size;
+    let dpr = f64::from_bits(dpr);
+    canvas.set_width((width as f64 * dpr) as u32);
+    canvas.set_height((height as f64 * dpr) as u32);
+    r.resize(width, height, dpr);
+    size
+}
 
-        context.uniform2f(xmod_location.as_ref(), 0f32, 0f32);
-        context.uniform2f(shift_location.as_ref(), shift_h, shift_v);
-        context.draw_arrays(
-            WebGlRenderingContext::LINE_STRIP,
-            //WebGlRenderingContext::POINTS,
-            0,
-            part2.len() as i32 / 2,
-        );
+/// Scrolling view of the latest points
+pub fn draw_live(r: &mut dyn TraceRenderer, panels: &[panel::Panel]) {
+    r.clear();
+    for p in panels {
+        let shift_h = -(p.cnt as f32);
+        for (i, buf) in p.bufs.iter().enumerate() {
+            let (p1, p2) = buf.as_slices();
+            r.polyline(shift_h, p.lane_center(i), p1);
+            r.polyline(shift_h, p.lane_center(i), p2);
+        }
+
+        for &m in &p.markers {
+            r.polyline(shift_h, 0., &[m as f32, p.top, m as f32, p.top + p.height]);
+        }
+
+        draw_separator(r, p);
     }
 }
 
-pub fn compile_shader(
-    context: &WebGlRenderingContext,
-    shader_type: u32,
-    source: &str,
-) 
-    -> Result<WebGlShader, String> 
-{
-    let shader = context
-        .create_shader(shader_type)
-        .ok_or_else(|| String::from("Unable to create shader object"))?;
-    context.shader_source(&shader, source);
-    context.compile_shader(&shader);
-
-    if context
-        .get_shader_parameter(&shader, WebGlRenderingContext::COMPILE_STATUS)
-        .as_bool()
-        .unwrap_or(false)
-    {
-        Ok(shader)
-    } else {
-        Err(context
-            .get_shader_info_log(&shader)
-            .unwrap_or_else(|| String::from("Unknown error creating shader")))
+/// History around the freeze point with cursors
+pub fn draw_frozen(r: &mut dyn TraceRenderer, panels: &[panel::Panel], c: &Control, store: &Store) {
+    r.clear();
+    for p in panels {
+        let (h, end) = match (store.get(p.group), c.end(p.group)) {
+            (Some(h), Some(end)) => (h, end),
+            _ => continue,
+        };
+        for (i, pts) in p.frozen(h, end).iter().enumerate() {
+            r.polyline(0., p.lane_center(i), pts);
+        }
+        for cur in c.cursors.iter().filter(|cur| cur.group == p.group) {
+            if let Some(x) = p.idx_to_x(cur.idx, end) {
+                r.polyline(0., 0., &[x, p.top, x, p.top + p.height]);
+            }
+        }
+        for &(_, from, to) in c.spans.iter().filter(|s| s.0 == p.group) {
+            draw_span(r, p, from, to, end);
+        }
+        draw_separator(r, p);
     }
 }
 
-pub fn link_program(
-    context: &WebGlRenderingContext,
-    vert_shader: &WebGlShader,
-    frag_shader: &WebGlShader,
-) -> Result<WebGlProgram, String> {
-    let program = context
-        .create_program()
-        .ok_or_else(|| String::from("Unable to create shader object"))?;
-
-    context.attach_shader(&program, vert_shader);
-    context.attach_shader(&program, frag_shader);
-    context.link_program(&program);
-
-    if context
-        .get_program_parameter(&program, WebGlRenderingContext::LINK_STATUS)
-        .as_bool()
-        .unwrap_or(false)
-    {
-        Ok(program)
-    } else {
-        Err(context
-            .get_program_info_log(&program)
-            .unwrap_or_else(|| String::from("Unknown error creating program object")))
+// Bracket over the visible part of a span, at the top of the panel
+fn draw_span(r: &mut dyn TraceRenderer, p: &panel::Panel, from: u64, to: u64, end: u64) {
+    const TICK: f32 = 6.;
+    let first = p.x_to_idx(0., end).unwrap_or(0);
+    if to <= first || from >= end || to <= from {
+        return;
+    }
+    let x0 = p.idx_to_x(from.max(first), end);
+    let x1 = p.idx_to_x(to.min(end) - 1, end);
+    if let (Some(x0), Some(x1)) = (x0, x1) {
+        let (top, tick) = (p.top + 1., p.top + TICK);
+        r.polyline(0., 0., &[x0, tick, x0, top, x1, top, x1, tick]);
     }
 }
 
-
+fn draw_separator(r: &mut dyn TraceRenderer, p: &panel::Panel) {
+    if p.top > 0. {
+        let (width, _) = r.size();
+        r.polyline(0., 0., &[0., p.top, width as f32 - 1., p.top]);
+    }
+}
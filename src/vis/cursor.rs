@@ -0,0 +1,60 @@
+use super::Group;
+use super::history::{History, Store};
+
+/// Point picked on a trace
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Cursor {
+    pub group: Group,
+    pub ch: usize,
+    // Absolute sample index in the group history
+    pub idx: u64,
+}
+
+/// Interval and amplitude between two cursors
+#[derive(Debug, Clone, Copy)]
+pub struct Measurement {
+    // Seconds
+    pub dt: f32,
+    // Raw units, only for cursors on the same channel
+    pub da: Option<i32>,
+}
+
+impl Measurement {
+    pub fn between(store: &Store, a: &Cursor, b: &Cursor) -> Option<Self> {
+        let ha = store.get(a.group)?;
+        let hb = store.get(b.group)?;
+        let dt = hb.time(b.idx) - ha.time(a.idx);
+        let da = if a.group == b.group && a.ch == b.ch {
+            Some(value(hb, b)? - value(ha, a)?)
+        } else {
+            None
+        };
+        Some(Self { dt, da })
+    }
+
+    /// Beats per minute if the interval is an RR, none for cursors at the same time
+    pub fn rate(&self) -> Option<f32> {
+        if self.dt == 0. {
+            None
+        } else {
+            Some(60. / self.dt.abs())
+        }
+    }
+}
+
+impl std::fmt::Display for Measurement {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Δt = {:.0} ms", self.dt * 1000.)?;
+        if let Some(rate) = self.rate() {
+            write!(f, " ({:.0} bpm)", rate)?;
+        }
+        if let Some(da) = self.da {
+            write!(f, ", ΔA = {}", da)?;
+        }
+        Ok(())
+    }
+}
+
+fn value(h: &History, c: &Cursor) -> Option<i32> {
+    h.value(c.idx, c.ch)
+}
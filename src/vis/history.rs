@@ -0,0 +1,184 @@
+use std::collections::VecDeque;
+
+use gloo_timers::future::TimeoutFuture;
+
+use super::Group;
+
+// Depth of the scroll-back buffer
+pub const HISTORY_MINUTES: usize = 5;
+
+/// Raw samples of one group, channels interleaved
+pub struct History {
+    pub group: Group,
+    // Absolute index of the oldest kept sample
+    start: u64,
+    cap: usize,
+    data: VecDeque<i32>,
+}
+
+impl History {
+    pub fn new(group: Group, minutes: usize) -> Self {
+        Self {
+            group,
+            start: 0,
+            // At least one sample, a zero rate would leave nothing to drop on push
+            cap: ((group.sample_rate() as usize) * 60 * minutes).max(1),
+            data: VecDeque::new(),
+        }
+    }
+
+    pub fn push(&mut self, sample: &[i32]) {
+        let ch_cnt = self.group.ch_cnt();
+        if self.len() == self.cap {
+            self.data.drain(.. ch_cnt);
+            self.start += 1;
+        }
+        for ch in 0 .. ch_cnt {
+            self.data.push_back(sample.get(ch).copied().unwrap_or(0));
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.data.len() / self.group.ch_cnt()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    pub fn start(&self) -> u64 {
+        self.start
+    }
+
+    /// Absolute index one past the newest sample
+    pub fn end(&self) -> u64 {
+        self.start + self.len() as u64
+    }
+
+    pub fn value(&self, idx: u64, ch: usize) -> Option<i32> {
+        if idx < self.start || idx >= self.end() || ch >= self.group.ch_cnt() {
+            return None;
+        }
+        let pos = (idx - self.start) as usize * self.group.ch_cnt() + ch;
+        self.data.get(pos).copied()
+    }
+
+    /// Seconds from the stream start
+    pub fn time(&self, idx: u64) -> f32 {
+        idx as f32 / self.group.sample_rate()
+    }
+}
+
+/// Histories of all groups of a live session or a decoded recording
+pub struct Store {
+    histories: Vec<History>,
+}
+
+impl Default for Store {
+    fn default() -> Self {
+        Self::new(HISTORY_MINUTES)
+    }
+}
+
+impl Store {
+    pub fn new(minutes: usize) -> Self {
+        Self {
+            histories: [Group::ECG, Group::REO, Group::ACC_IN]
+                .iter()
+                .map(|&g| History::new(g, minutes))
+                .collect(),
+        }
+    }
+
+    pub fn clear(&mut self) {
+        *self = Self::new(HISTORY_MINUTES);
+    }
+
+    pub fn push(&mut self, group: Group, sample: &[i32]) {
+        if let Some(h) = self.histories.iter_mut().find(|h| h.group == group) {
+            h.push(sample);
+        }
+    }
+
+    pub fn get(&self, group: Group) -> Option<&History> {
+        self.histories.iter().find(|h| h.group == group)
+    }
+
+    /// Decodes a file downloaded from `/io/file`, keeping the whole recording
+    ///
+    /// Yields to the event loop between chunks of blocks, hours of data take a while.
+    pub async fn decode(data: &[u8]) -> Self {
+        const BLOCK_SZ: usize = 0x800;
+        const BLOCKS_PER_YIELD: usize = 64;
+        // Upper bound, a block never holds a minute of samples
+        let minutes = data.len() / BLOCK_SZ + 1;
+        let mut store = Self::new(minutes);
+
+        for (i, blk) in data.chunks(BLOCK_SZ).enumerate() {
+            store.decode_block(i, blk);
+            if i % BLOCKS_PER_YIELD == BLOCKS_PER_YIELD - 1 {
+                TimeoutFuture::new(0).await;
+            }
+        }
+
+        store
+    }
+
+    fn decode_block(&mut self, i: usize, blk: &[u8]) {
+        use delta::block::parse::{BlockParser, PntResult, Point};
+        use delta::point::decode::PointDesc;
+        use delta::error::DecodingError;
+        use delta::defs::GroupId;
+
+        let mut parser = BlockParser::new();
+        let r = parser.try_open_block(blk);
+        if let DecodingError::Ok = r {
+            while let PntResult::Ok(p) = parser.iter_point() {
+                match p {
+                    Point::PointV(PointDesc{group_id: GroupId::ECG, ..}, sample) => {
+                        self.push(Group::ECG, &Vec::from(sample));
+                    }
+                    Point::PointV(PointDesc{group_id: GroupId::REO, ..}, sample) => {
+                        self.push(Group::REO, &Vec::from(sample));
+                    }
+                    Point::PointV(PointDesc{group_id: GroupId::ACC_IN, ..}, sample) => {
+                        self.push(Group::ACC_IN, &Vec::from(sample));
+                    }
+                    _ => (),
+                }
+            }
+        } else {
+            log::error!("Failed to parse blk {}: {:?}", i, r);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vis::panel::{set_rates, Rates};
+
+    #[test]
+    fn zero_rate_keeps_newest() {
+        set_rates(Rates { reo: 0., ..Rates::default() });
+        let mut h = History::new(Group::REO, 1);
+        h.push(&[1]);
+        h.push(&[2]);
+        assert_eq!(h.len(), 1);
+        assert_eq!(h.value(h.start(), 0), Some(2));
+        set_rates(Rates::default());
+    }
+
+    #[test]
+    fn cap_drops_oldest() {
+        set_rates(Rates { acc_in: 1., ..Rates::default() });
+        let mut h = History::new(Group::ACC_IN, 1);
+        for i in 0 .. 62 {
+            h.push(&[i, i, i]);
+        }
+        assert_eq!((h.start(), h.end()), (2, 62));
+        assert_eq!(h.value(1, 0), None);
+        assert_eq!(h.value(2, 2), Some(2));
+        set_rates(Rates::default());
+    }
+}
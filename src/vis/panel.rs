@@ -0,0 +1,231 @@
+use std::cell::Cell;
+use std::collections::VecDeque;
+
+use serde::Deserialize;
+
+use super::history::History;
+
+/// Signal groups of the vis stream
+#[allow(non_camel_case_types)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Group {
+    ECG,
+    REO,
+    ACC_IN,
+}
+
+impl Group {
+    pub fn ch_cnt(self) -> usize {
+        match self {
+            Group::ECG    => 8,
+            Group::REO    => 1,
+            Group::ACC_IN => 3,
+        }
+    }
+
+    pub fn sample_rate(self) -> f32 {
+        let rates = RATES.with(Cell::get);
+        match self {
+            Group::ECG    => rates.ecg,
+            Group::REO    => rates.reo,
+            Group::ACC_IN => rates.acc_in,
+        }
+    }
+
+    // Every n-th sample is drawn, sets the panel time base
+    pub fn decimation(self) -> usize {
+        match self {
+            Group::ECG    => 8,
+            Group::REO    => 1,
+            Group::ACC_IN => 1,
+        }
+    }
+
+    // Raw units per pixel for a 100px lane
+    pub fn divider(self) -> f32 {
+        match self {
+            Group::ECG    => 100.,
+            Group::REO    => 50.,
+            Group::ACC_IN => 8.,
+        }
+    }
+}
+
+/// Sample rates of the vis stream groups, Hz, `rates` of the device profile
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+pub struct Rates {
+    pub ecg: f32,
+    pub reo: f32,
+    pub acc_in: f32,
+}
+
+impl Default for Rates {
+    // Holter profile values, used until a device with its own rates is opened
+    fn default() -> Self {
+        Self { ecg: 1000., reo: 125., acc_in: 25. }
+    }
+}
+
+thread_local! {
+    // Time axes, history, analysis and export all read it, the page runs on one thread
+    static RATES: Cell<Rates> = Cell::new(Rates::default());
+}
+
+/// Switches the group rates for the next stream or recording
+pub fn set_rates(rates: Rates) {
+    RATES.with(|r| r.set(rates));
+}
+
+/// Stacked area of the canvas showing one group
+pub struct Panel {
+    pub group: Group,
+    pub top: f32,
+    pub height: f32,
+    // Drawn points count, x coordinate of the newest point
+    pub cnt: usize,
+    decim: usize,
+    width: usize,
+    // Per channel (x, y) pairs, newest first
+    pub bufs: Vec<VecDeque<f32>>,
+    pub markers: VecDeque<usize>,
+}
+
+impl Panel {
+    fn new(group: Group, top: f32, height: f32, width: usize) -> Self {
+        Self {
+            group,
+            top,
+            height,
+            cnt: 0,
+            decim: 0,
+            width,
+            bufs: vec![VecDeque::from(vec![0f32; width * 2]); group.ch_cnt()],
+            markers: VecDeque::new(),
+        }
+    }
+
+    pub fn lane(&self) -> f32 {
+        self.height / self.group.ch_cnt() as f32
+    }
+
+    pub fn lane_center(&self, ch: usize) -> f32 {
+        self.top + self.lane() * (ch as f32 + 0.5)
+    }
+
+    pub fn contains(&self, y: f32) -> bool {
+        y >= self.top && y < self.top + self.height
+    }
+
+    pub fn channel_at(&self, y: f32) -> usize {
+        let ch = ((y - self.top) / self.lane()) as usize;
+        ch.min(self.group.ch_cnt() - 1)
+    }
+
+    fn scale(&self) -> f32 {
+        self.lane() / 100. / self.group.divider()
+    }
+
+    /// Returns true if the sample produced a new point
+    pub fn push(&mut self, sample: &[i32]) -> bool {
+        self.decim += 1;
+        if self.decim < self.group.decimation() {
+            return false;
+        }
+        self.decim = 0;
+
+        let k = self.scale();
+        for (buf, &s) in self.bufs.iter_mut().zip(sample) {
+            let _ = buf.pop_back();
+            let _ = buf.pop_back();
+            buf.push_front(s as f32 * k);
+            buf.push_front(self.cnt as f32);
+        }
+        self.cnt = self.cnt.wrapping_add(1);
+
+        while self.markers.front().map_or(false, |&m| self.cnt.wrapping_sub(m) >= self.width) {
+            let _ = self.markers.pop_front();
+        }
+        true
+    }
+
+    pub fn mark(&mut self) {
+        self.markers.push_back(self.cnt);
+    }
+
+    /// Per channel points from history ending at `end`, newest at the right edge
+    pub fn frozen(&self, h: &History, end: u64) -> Vec<Vec<f32>> {
+        let k = self.scale();
+        (0 .. self.group.ch_cnt())
+            .map(|ch| {
+                let mut pts = Vec::with_capacity(self.width * 2);
+                for x in (0 .. self.width).rev() {
+                    match self.x_to_idx(x as f32, end).and_then(|idx| h.value(idx, ch)) {
+                        Some(v) => {
+                            pts.push(x as f32);
+                            pts.push(v as f32 * k);
+                        }
+                        None => break,
+                    }
+                }
+                pts
+            })
+            .collect()
+    }
+
+    pub fn x_to_idx(&self, x: f32, end: u64) -> Option<u64> {
+        let back = (self.width as f32 - 1. - x).max(0.) as u64 * self.group.decimation() as u64 + 1;
+        end.checked_sub(back)
+    }
+
+    pub fn idx_to_x(&self, idx: u64, end: u64) -> Option<f32> {
+        let back = end.checked_sub(idx + 1)? / self.group.decimation() as u64;
+        if back < self.width as u64 {
+            Some((self.width as u64 - 1 - back) as f32)
+        } else {
+            None
+        }
+    }
+}
+
+/// Splits canvas height between groups proportionally to channel count
+pub fn layout(groups: &[Group], width: usize, height: f32) -> Vec<Panel> {
+    groups
+        .iter()
+        .zip(geometry(groups, height))
+        .map(|(g, (top, h))| Panel::new(*g, top, h, width))
+        .collect()
+}
+
+/// Applies a new canvas size keeping the drawn points
+pub fn relayout(panels: &mut [Panel], width: usize, height: f32) {
+    let groups: Vec<Group> = panels.iter().map(|p| p.group).collect();
+    for (p, (top, h)) in panels.iter_mut().zip(geometry(&groups, height)) {
+        let k = p.scale();
+        p.top = top;
+        p.height = h;
+        let k = p.scale() / k;
+        p.width = width;
+        for buf in p.bufs.iter_mut() {
+            for y in buf.iter_mut().skip(1).step_by(2) {
+                *y *= k;
+            }
+            // Newest points are at the front
+            buf.resize(width * 2, 0.);
+        }
+    }
+}
+
+fn geometry(groups: &[Group], height: f32) -> Vec<(f32, f32)> {
+    let weight = |g: &Group| g.ch_cnt().max(2) as f32;
+    let total: f32 = groups.iter().map(weight).sum();
+    let mut top = 0.;
+    groups
+        .iter()
+        .map(|g| {
+            let h = height * weight(g) / total;
+            let r = (top, h);
+            top += h;
+            r
+        })
+        .collect()
+}
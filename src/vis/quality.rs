@@ -0,0 +1,235 @@
+use std::collections::VecDeque;
+
+// Mains frequency checked for contamination, Hz
+const MAINS_FREQ: f32 = 50.0;
+// Analysis window, seconds
+const WINDOW_SEC: f32 = 2.0;
+
+// Thresholds in raw ADC units (24 bit front end)
+const ADC_LIMIT: i32 = 0x7F_FF00;
+const FLAT_STD: f32 = 20.0;
+const NOISE_RATIO_BAD: f32 = 0.5;
+const NOISE_RATIO_FAIR: f32 = 0.25;
+const MAINS_RATIO_BAD: f32 = 0.5;
+const MAINS_RATIO_FAIR: f32 = 0.2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Good,
+    Fair,
+    Bad,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Issue {
+    Flat,
+    Saturated,
+    Noisy,
+    Mains,
+}
+
+#[derive(Debug, Clone)]
+pub struct ChannelQuality {
+    pub level: Level,
+    pub issues: Vec<Issue>,
+}
+
+impl Default for ChannelQuality {
+    fn default() -> Self {
+        Self {
+            level: Level::Bad,
+            issues: vec![Issue::Flat],
+        }
+    }
+}
+
+impl ChannelQuality {
+    pub fn color(&self) -> &'static str {
+        match self.level {
+            Level::Good => "#2ecc40",
+            Level::Fair => "#ffdc00",
+            Level::Bad  => "#ff4136",
+        }
+    }
+
+    pub fn describe(&self) -> String {
+        if self.issues.is_empty() {
+            return "OK".into();
+        }
+        self.issues
+            .iter()
+            .map(|i| format!("{:?}", i))
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
+/// Sliding window signal quality estimator, one window per channel
+pub struct Estimator {
+    rate: f32,
+    len: usize,
+    windows: Vec<VecDeque<i32>>,
+}
+
+impl Estimator {
+    pub fn new(ch_cnt: usize, rate: f32) -> Self {
+        let len = (rate * WINDOW_SEC) as usize;
+        Self {
+            rate,
+            len,
+            windows: vec![VecDeque::with_capacity(len); ch_cnt],
+        }
+    }
+
+    pub fn push(&mut self, sample: &[i32]) {
+        for (w, &s) in self.windows.iter_mut().zip(sample) {
+            if w.len() == self.len {
+                let _ = w.pop_front();
+            }
+            w.push_back(s);
+        }
+    }
+
+    pub fn is_ready(&self) -> bool {
+        self.windows.iter().all(|w| w.len() == self.len)
+    }
+
+    pub fn estimate(&self) -> Vec<ChannelQuality> {
+        self.windows
+            .iter()
+            .map(|w| self.estimate_channel(w))
+            .collect()
+    }
+
+    fn estimate_channel(&self, w: &VecDeque<i32>) -> ChannelQuality {
+        if w.len() < 3 {
+            return ChannelQuality::default();
+        }
+
+        let n = w.len() as f32;
+        let mean = w.iter().map(|&s| s as f32).sum::<f32>() / n;
+        let var = w.iter().map(|&s| (s as f32 - mean).powi(2)).sum::<f32>() / n;
+        let std = var.sqrt();
+
+        let mut level = Level::Good;
+        let mut issues = Vec::new();
+        let mut raise = |l: Level, i: Issue| {
+            if l > level { level = l; }
+            issues.push(i);
+        };
+
+        let saturated = w.iter().filter(|s| s.abs() >= ADC_LIMIT).count();
+        if saturated > 0 {
+            raise(if saturated * 10 > w.len() { Level::Bad } else { Level::Fair }, Issue::Saturated);
+        }
+
+        if std < FLAT_STD {
+            raise(Level::Bad, Issue::Flat);
+        } else {
+            // High frequency content: second difference energy relative to signal spread
+            let samples: Vec<f32> = w.iter().map(|&s| s as f32).collect();
+            let d2 = samples
+                .windows(3)
+                .map(|x| (x[2] - 2.0 * x[1] + x[0]).abs())
+                .sum::<f32>() / (n - 2.0);
+            let noise = d2 / std;
+            if noise > NOISE_RATIO_BAD {
+                raise(Level::Bad, Issue::Noisy);
+            } else if noise > NOISE_RATIO_FAIR {
+                raise(Level::Fair, Issue::Noisy);
+            }
+
+            let mains = goertzel(&samples, mean, MAINS_FREQ, self.rate) / std;
+            if mains > MAINS_RATIO_BAD {
+                raise(Level::Bad, Issue::Mains);
+            } else if mains > MAINS_RATIO_FAIR {
+                raise(Level::Fair, Issue::Mains);
+            }
+        }
+
+        ChannelQuality { level, issues }
+    }
+}
+
+/// Amplitude of a single frequency component
+fn goertzel(samples: &[f32], mean: f32, freq: f32, rate: f32) -> f32 {
+    let w = 2.0 * std::f32::consts::PI * freq / rate;
+    let coeff = 2.0 * w.cos();
+    let (mut s1, mut s2) = (0f32, 0f32);
+    for &x in samples {
+        let s0 = x - mean + coeff * s1 - s2;
+        s2 = s1;
+        s1 = s0;
+    }
+    let power = s1 * s1 + s2 * s2 - coeff * s1 * s2;
+    2.0 * power.max(0.0).sqrt() / samples.len() as f32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RATE: f32 = 1000.0;
+
+    // One full window of a single channel signal, t in seconds
+    fn classify(signal: impl Fn(f32) -> f32) -> ChannelQuality {
+        let mut est = Estimator::new(1, RATE);
+        let mut i = 0;
+        while !est.is_ready() {
+            est.push(&[signal(i as f32 / RATE) as i32]);
+            i += 1;
+        }
+        est.estimate().remove(0)
+    }
+
+    fn sine(freq: f32, amp: f32, t: f32) -> f32 {
+        amp * (2.0 * std::f32::consts::PI * freq * t).sin()
+    }
+
+    #[test]
+    fn not_ready_until_window_full() {
+        let mut est = Estimator::new(2, RATE);
+        est.push(&[0, 0]);
+        assert!(!est.is_ready());
+        assert_eq!(est.estimate()[0].level, Level::Bad);
+    }
+
+    #[test]
+    fn clean() {
+        let q = classify(|t| sine(1.2, 100_000.0, t));
+        assert_eq!(q.level, Level::Good);
+        assert!(q.issues.is_empty());
+        assert_eq!(q.describe(), "OK");
+    }
+
+    #[test]
+    fn flat() {
+        let q = classify(|t| 1000.0 + sine(7.0, 5.0, t));
+        assert_eq!(q.level, Level::Bad);
+        assert_eq!(q.issues, vec![Issue::Flat]);
+    }
+
+    #[test]
+    fn clipped() {
+        let limit = ADC_LIMIT as f32;
+        let q = classify(|t| sine(1.2, 2.0 * limit, t).clamp(-limit, limit));
+        assert_eq!(q.level, Level::Bad);
+        assert!(q.issues.contains(&Issue::Saturated));
+        assert!(!q.issues.contains(&Issue::Flat));
+    }
+
+    #[test]
+    fn mains() {
+        let q = classify(|t| sine(1.2, 100_000.0, t) + sine(MAINS_FREQ, 50_000.0, t));
+        assert_eq!(q.level, Level::Bad);
+        assert!(q.issues.contains(&Issue::Mains));
+        assert!(!q.issues.contains(&Issue::Noisy));
+    }
+
+    #[test]
+    fn noisy() {
+        let q = classify(|t| if (t * RATE).round() as i32 % 2 == 0 { 10_000.0 } else { -10_000.0 });
+        assert_eq!(q.level, Level::Bad);
+        assert_eq!(q.issues, vec![Issue::Noisy]);
+    }
+}
@@ -0,0 +1,46 @@
+use wasm_bindgen::{JsCast, JsValue};
+
+pub mod webgl;
+pub mod canvas2d;
+pub mod headless;
+
+/// Drawing backend of the trace view
+///
+/// Coordinates are canvas pixels with (0,0) in the top left corner.
+/// Horizontal position is wrapped as `|x + shift_h| mod width`, so a
+/// scrolling buffer with absolute sample counters as x is drawn right to left.
+pub trait TraceRenderer {
+    /// Size in CSS pixels
+    fn size(&self) -> (u32, u32);
+    /// Called after the canvas backing store got `dpr` times the CSS size
+    fn resize(&mut self, width: u32, height: u32, dpr: f64);
+    fn clear(&mut self);
+    /// Line strip of interleaved (x, y) pairs
+    fn polyline(&mut self, shift_h: f32, shift_v: f32, pts: &[f32]);
+}
+
+/// Horizontal wrap rule shared by all backends, matches the WebGL vertex shader
+pub fn wrap_x(x: f32, shift_h: f32, width: u32) -> f32 {
+    (x + shift_h).abs() % width as f32
+}
+
+/// Picks WebGL and falls back to Canvas2D on machines without it
+pub fn create(canvas: &web_sys::HtmlCanvasElement) -> Result<Box<dyn TraceRenderer>, JsValue> {
+    if let Some(r) = webgl::WebGl::new(canvas)? {
+        return Ok(Box::new(r));
+    }
+    log::warn!("WebGL is not available, using Canvas2D");
+    match canvas2d::Canvas2d::new(canvas)? {
+        Some(r) => Ok(Box::new(r)),
+        None => Err(JsValue::from_str("No drawing context available")),
+    }
+}
+
+pub fn canvas_by_id(id: &str) -> Result<web_sys::HtmlCanvasElement, JsValue> {
+    web_sys::window()
+        .and_then(|w| w.document())
+        .and_then(|d| d.get_element_by_id(id))
+        .ok_or_else(|| JsValue::from_str(&format!("No #{} element", id)))?
+        .dyn_into::<web_sys::HtmlCanvasElement>()
+        .map_err(|_| JsValue::from_str(&format!("#{} is not a canvas", id)))
+}
@@ -0,0 +1,65 @@
+use wasm_bindgen::{JsCast, JsValue};
+use web_sys::CanvasRenderingContext2d;
+
+use super::{TraceRenderer, wrap_x};
+
+pub struct Canvas2d {
+    context: CanvasRenderingContext2d,
+    width: u32,
+    height: u32,
+}
+
+impl Canvas2d {
+    pub fn new(canvas: &web_sys::HtmlCanvasElement) -> Result<Option<Self>, JsValue> {
+        let context = match canvas.get_context("2d")? {
+            Some(context) => context.dyn_into::<CanvasRenderingContext2d>()?,
+            None => return Ok(None),
+        };
+        let mut r = Self {
+            context,
+            width: canvas.width(),
+            height: canvas.height(),
+        };
+        r.resize(r.width, r.height, 1.);
+
+        Ok(Some(r))
+    }
+}
+
+impl TraceRenderer for Canvas2d {
+    fn size(&self) -> (u32, u32) {
+        (self.width, self.height)
+    }
+
+    fn resize(&mut self, width: u32, height: u32, dpr: f64) {
+        self.width = width;
+        self.height = height;
+        // Resizing the canvas resets the context state
+        let _ = self.context.set_transform(dpr, 0., 0., dpr, 0., 0.);
+        self.context.set_stroke_style(&JsValue::from_str("black"));
+        self.context.set_line_width(1.);
+    }
+
+    fn clear(&mut self) {
+        self.context.set_fill_style(&JsValue::from_str("white"));
+        self.context.fill_rect(0., 0., self.width as f64, self.height as f64);
+    }
+
+    fn polyline(&mut self, shift_h: f32, shift_v: f32, pts: &[f32]) {
+        if pts.len() < 4 {
+            return;
+        }
+        let cxt = &self.context;
+        cxt.begin_path();
+        for (i, p) in pts.chunks_exact(2).enumerate() {
+            let x = wrap_x(p[0], shift_h, self.width) as f64;
+            let y = (p[1] + shift_v) as f64;
+            if i == 0 {
+                cxt.move_to(x, y);
+            } else {
+                cxt.line_to(x, y);
+            }
+        }
+        cxt.stroke();
+    }
+}
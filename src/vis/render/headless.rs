@@ -0,0 +1,193 @@
+use super::{TraceRenderer, wrap_x};
+
+/// In-memory backend, keeps the vertices and a monochrome raster of the last frame
+pub struct Headless {
+    width: u32,
+    height: u32,
+    // Wrapped and shifted line strips
+    pub lines: Vec<Vec<(f32, f32)>>,
+    // Row major, 1 for drawn pixels
+    pub pixels: Vec<u8>,
+}
+
+impl Headless {
+    pub fn new(width: u32, height: u32) -> Self {
+        Self {
+            width,
+            height,
+            lines: Vec::new(),
+            pixels: vec![0; (width * height) as usize],
+        }
+    }
+
+    pub fn pixel(&self, x: u32, y: u32) -> bool {
+        x < self.width && y < self.height && self.pixels[(y * self.width + x) as usize] != 0
+    }
+
+    fn plot(&mut self, x: i32, y: i32) {
+        if x >= 0 && y >= 0 && (x as u32) < self.width && (y as u32) < self.height {
+            self.pixels[(y as u32 * self.width + x as u32) as usize] = 1;
+        }
+    }
+
+    // Bresenham
+    fn segment(&mut self, (x0, y0): (f32, f32), (x1, y1): (f32, f32)) {
+        let (mut x0, mut y0, x1, y1) = (x0 as i32, y0 as i32, x1 as i32, y1 as i32);
+        let dx = (x1 - x0).abs();
+        let dy = -(y1 - y0).abs();
+        let sx = if x0 < x1 { 1 } else { -1 };
+        let sy = if y0 < y1 { 1 } else { -1 };
+        let mut err = dx + dy;
+        loop {
+            self.plot(x0, y0);
+            if x0 == x1 && y0 == y1 {
+                break;
+            }
+            let e2 = 2 * err;
+            if e2 >= dy {
+                err += dy;
+                x0 += sx;
+            }
+            if e2 <= dx {
+                err += dx;
+                y0 += sy;
+            }
+        }
+    }
+}
+
+impl TraceRenderer for Headless {
+    fn size(&self) -> (u32, u32) {
+        (self.width, self.height)
+    }
+
+    fn resize(&mut self, width: u32, height: u32, _dpr: f64) {
+        *self = Self::new(width, height);
+    }
+
+    fn clear(&mut self) {
+        self.lines.clear();
+        for p in self.pixels.iter_mut() {
+            *p = 0;
+        }
+    }
+
+    fn polyline(&mut self, shift_h: f32, shift_v: f32, pts: &[f32]) {
+        let line: Vec<(f32, f32)> = pts
+            .chunks_exact(2)
+            .map(|p| (wrap_x(p[0], shift_h, self.width), p[1] + shift_v))
+            .collect();
+        for s in line.windows(2) {
+            self.segment(s[0], s[1]);
+        }
+        if line.len() == 1 {
+            self.plot(line[0].0 as i32, line[0].1 as i32);
+        }
+        self.lines.push(line);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vis::{draw_frozen, draw_live, Control};
+    use crate::vis::history::Store;
+    use crate::vis::panel::{self, Group};
+
+    fn vertices(r: &Headless) -> Vec<(f32, f32)> {
+        r.lines.iter().flatten().cloned().collect()
+    }
+
+    #[test]
+    fn layout_splits_height_by_channels() {
+        let panels = panel::layout(&[Group::ECG, Group::REO], 200, 100.);
+        assert_eq!((panels[0].top, panels[0].height), (0., 80.));
+        assert_eq!((panels[1].top, panels[1].height), (80., 20.));
+
+        let mut r = Headless::new(200, 100);
+        draw_live(&mut r, &panels);
+        assert!(r.lines.contains(&vec![(0., 80.), (199., 80.)]));
+        assert!(r.pixel(100, 80));
+        // No separator above the first panel
+        assert!(!r.lines.iter().any(|l| l.len() == 2 && l[0].1 == 0. && l[1].1 == 0.));
+    }
+
+    #[test]
+    fn ecg_is_decimated() {
+        let mut panels = panel::layout(&[Group::ECG], 100, 80.);
+        let p = &mut panels[0];
+        for _ in 0 .. 7 {
+            assert!(!p.push(&[0; 8]));
+        }
+        assert!(p.push(&[0; 8]));
+        assert_eq!(p.cnt, 1);
+    }
+
+    #[test]
+    fn scrolls_right_to_left() {
+        let mut panels = panel::layout(&[Group::REO], 100, 40.);
+        for &s in &[1250, 0, 1250] {
+            assert!(panels[0].push(&[s]));
+        }
+        let mut r = Headless::new(100, 40);
+        draw_live(&mut r, &panels);
+        let v = vertices(&r);
+        // Newest point is the closest to the left edge, lane center at 20
+        for pt in &[(1., 30.), (2., 20.), (3., 30.)] {
+            assert!(v.contains(pt), "{:?} not in {:?}", pt, &v[.. 8]);
+        }
+        assert!(r.pixel(1, 30));
+    }
+
+    #[test]
+    fn relayout_rescales_points() {
+        let mut panels = panel::layout(&[Group::REO], 100, 40.);
+        for &s in &[1250, 0, 1250] {
+            panels[0].push(&[s]);
+        }
+        panel::relayout(&mut panels, 100, 80.);
+        assert_eq!(panels[0].height, 80.);
+
+        let mut r = Headless::new(100, 80);
+        draw_live(&mut r, &panels);
+        let v = vertices(&r);
+        assert!(v.contains(&(1., 60.)));
+        assert!(v.contains(&(2., 40.)));
+    }
+
+    #[test]
+    fn marker_scrolls_with_trace() {
+        let mut panels = panel::layout(&[Group::REO], 100, 40.);
+        for _ in 0 .. 3 {
+            panels[0].push(&[0]);
+        }
+        panels[0].mark();
+        for _ in 0 .. 2 {
+            panels[0].push(&[0]);
+        }
+        let mut r = Headless::new(100, 40);
+        draw_live(&mut r, &panels);
+        assert!(r.lines.contains(&vec![(2., 0.), (2., 40.)]));
+        assert!(r.pixel(2, 39));
+    }
+
+    #[test]
+    fn spans_are_marked_on_frozen_view() {
+        let mut store = Store::new(1);
+        for _ in 0 .. 200 {
+            store.push(Group::REO, &[0]);
+        }
+        let mut c = Control::default();
+        c.freeze(vec![Group::REO], &store);
+        // Second span starts before the left edge, the third is out of view
+        c.set_spans(vec![(Group::REO, 150, 170), (Group::REO, 90, 110), (Group::REO, 0, 50)]);
+        let panels = panel::layout(&[Group::REO], 100, 40.);
+
+        let mut r = Headless::new(100, 40);
+        draw_frozen(&mut r, &panels, &c, &store);
+        assert!(r.lines.contains(&vec![(50., 6.), (50., 1.), (69., 1.), (69., 6.)]));
+        assert!(r.lines.contains(&vec![(0., 6.), (0., 1.), (9., 1.), (9., 6.)]));
+        assert_eq!(r.lines.iter().filter(|l| l.len() == 4).count(), 2);
+        assert!(r.pixel(60, 1));
+    }
+}
@@ -0,0 +1,202 @@
+use wasm_bindgen::{JsCast, JsValue};
+use web_sys::{WebGlProgram, WebGlRenderingContext, WebGlShader, WebGlUniformLocation};
+use serde::Serialize;
+
+use super::TraceRenderer;
+
+pub struct WebGl {
+    context: WebGlRenderingContext,
+    resolution_location: Option<WebGlUniformLocation>,
+    shift_location: Option<WebGlUniformLocation>,
+    xmod_location: Option<WebGlUniformLocation>,
+    width: u32,
+    height: u32,
+}
+
+impl WebGl {
+    /// Returns None if the browser has no WebGL support
+    pub fn new(canvas: &web_sys::HtmlCanvasElement) -> Result<Option<Self>, JsValue> {
+        let (width, height) = (canvas.width(), canvas.height());
+
+        // Context settings
+        #[derive(Serialize)]
+        struct CxtCfg {
+            antialias : bool,
+            depth     : bool,
+        };
+
+        let cxt_cfg = CxtCfg { antialias : false, depth : false };
+        let cxt_cfg = JsValue::from_serde(&cxt_cfg).unwrap();
+
+        let context = match canvas
+            //.get_context("webgl")?
+            .get_context_with_context_options("webgl", &cxt_cfg)?
+        {
+            Some(context) => context.dyn_into::<WebGlRenderingContext>()?,
+            None => return Ok(None),
+        };
+
+        // Shaders
+        let vert_shader = compile_shader(
+            &context,
+            WebGlRenderingContext::VERTEX_SHADER,
+            r#"
+            attribute vec2 a_position;
+            uniform vec2 u_resolution;
+            uniform vec2 u_shift;
+            uniform vec2 u_xmod;
+
+            void main() 
+            {
+              vec2 Pos = a_position + u_shift; 
+          
+              //if(u_xmod.x != 0.0 )
+              //{
+              //  Pos.x = mod(Pos.x,u_xmod.x);
+              //};
+          
+              Pos.x = abs(Pos.x);
+              Pos.x = mod(Pos.x, u_resolution.x);
+
+              vec2 zeroToOne = Pos / u_resolution; // преобразуем положение в пикселях к диапазону от 0.0 до 1.0
+       
+              // преобразуем из 0->1 в 0->2
+              vec2 zeroToTwo = zeroToOne * 2.0;
+              // преобразуем из 0->2 в -1->+1 (пространство отсечения)
+              vec2 clipSpace = zeroToTwo - 1.0;
+              vec2 clipSpaceN = clipSpace * vec2(1, -1); // переворачиваем систему коооординат (0,0) в левом верхнем углу
+     
+              gl_Position = vec4(clipSpaceN, 0, 1);  
+            }
+        "#,
+        )?;
+        let frag_shader = compile_shader(
+            &context,
+            WebGlRenderingContext::FRAGMENT_SHADER,
+            r#"
+            void main() {
+                gl_FragColor = vec4(0.0, 0.0, 0.0, 1.0);
+            }
+        "#,
+        )?;
+        let program = link_program(&context, &vert_shader, &frag_shader)?;
+        context.use_program(Some(&program));
+
+        //ATR
+        //
+        let position_attribute_location = context.get_attrib_location(&program, "a_position");
+        let resolution_location = context.get_uniform_location(&program, "u_resolution");
+        //context.get_uniform_location(&program, "u_color");
+        let shift_location = context.get_uniform_location(&program, "u_shift");
+        let xmod_location = context.get_uniform_location(&program, "u_xmod");
+
+        let position_buffer = context.create_buffer().ok_or("failed to create buffer")?;
+        context.bind_buffer(WebGlRenderingContext::ARRAY_BUFFER, Some(&position_buffer));
+
+        context.enable_vertex_attrib_array(position_attribute_location as u32);
+        context.vertex_attrib_pointer_with_i32(0, 2, WebGlRenderingContext::FLOAT, false, 0, 0);
+        context.uniform2f(resolution_location.as_ref(), width as f32, height as f32);
+        context.viewport(0,0, width as i32, height as i32);
+
+        Ok(Some(Self {
+            context,
+            resolution_location,
+            shift_location,
+            xmod_location,
+            width,
+            height,
+        }))
+    }
+}
+
+impl TraceRenderer for WebGl {
+    fn size(&self) -> (u32, u32) {
+        (self.width, self.height)
+    }
+
+    fn resize(&mut self, width: u32, height: u32, dpr: f64) {
+        self.width = width;
+        self.height = height;
+        self.context.uniform2f(self.resolution_location.as_ref(), width as f32, height as f32);
+        self.context.viewport(0, 0, (width as f64 * dpr) as i32, (height as f64 * dpr) as i32);
+    }
+
+    fn clear(&mut self) {
+        self.context.clear_color(1.0, 1.0, 1.0, 1.0);
+        self.context.clear(WebGlRenderingContext::COLOR_BUFFER_BIT);
+    }
+
+    fn polyline(&mut self, shift_h: f32, shift_v: f32, pts: &[f32]) {
+        if pts.len() == 0 {
+            return;
+        }
+        let context = &self.context;
+        let vert_array = unsafe { js_sys::Float32Array::view(pts) };
+        context.buffer_data_with_array_buffer_view(
+            WebGlRenderingContext::ARRAY_BUFFER,
+            &vert_array,
+            WebGlRenderingContext::STREAM_DRAW,
+        );
+
+        context.uniform2f(self.xmod_location.as_ref(), 0f32, 0f32);
+        context.uniform2f(self.shift_location.as_ref(), shift_h, shift_v);
+        context.draw_arrays(
+            WebGlRenderingContext::LINE_STRIP,
+            //WebGlRenderingContext::POINTS,
+            0,
+            pts.len() as i32 / 2,
+        );
+    }
+}
+
+pub fn compile_shader(
+    context: &WebGlRenderingContext,
+    shader_type: u32,
+    source: &str,
+) 
+    -> Result<WebGlShader, String> 
+{
+    let shader = context
+        .create_shader(shader_type)
+        .ok_or_else(|| String::from("Unable to create shader object"))?;
+    context.shader_source(&shader, source);
+    context.compile_shader(&shader);
+
+    if context
+        .get_shader_parameter(&shader, WebGlRenderingContext::COMPILE_STATUS)
+        .as_bool()
+        .unwrap_or(false)
+    {
+        Ok(shader)
+    } else {
+        Err(context
+            .get_shader_info_log(&shader)
+            .unwrap_or_else(|| String::from("Unknown error creating shader")))
+    }
+}
+
+pub fn link_program(
+    context: &WebGlRenderingContext,
+    vert_shader: &WebGlShader,
+    frag_shader: &WebGlShader,
+) -> Result<WebGlProgram, String> {
+    let program = context
+        .create_program()
+        .ok_or_else(|| String::from("Unable to create shader object"))?;
+
+    context.attach_shader(&program, vert_shader);
+    context.attach_shader(&program, frag_shader);
+    context.link_program(&program);
+
+    if context
+        .get_program_parameter(&program, WebGlRenderingContext::LINK_STATUS)
+        .as_bool()
+        .unwrap_or(false)
+    {
+        Ok(program)
+    } else {
+        Err(context
+            .get_program_info_log(&program)
+            .unwrap_or_else(|| String::from("Unknown error creating program object")))
+    }
+}
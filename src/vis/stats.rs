@@ -0,0 +1,136 @@
+use std::collections::VecDeque;
+
+use serde::Serialize;
+
+// Averaging window for rates, ms
+const RATE_WINDOW: f64 = 1000.;
+
+/// Per session statistics of the vis stream
+#[derive(Default)]
+pub struct LinkStats {
+    // Time of the first block, startup latency is not a loss
+    start: Option<f64>,
+    blocks: u64,
+    bytes: u64,
+    decode_errors: u64,
+    // Sequence number of the last decoded block header
+    last_seq: Option<u32>,
+    gaps: u64,
+    lost_blocks: u64,
+    last_error: Option<String>,
+    window: VecDeque<(f64, usize)>,
+}
+
+/// Exported view of the statistics
+#[derive(Serialize, Debug, Clone)]
+pub struct Snapshot {
+    pub duration_sec: f64,
+    pub blocks: u64,
+    pub bytes: u64,
+    pub blocks_per_sec: f64,
+    pub bytes_per_sec: f64,
+    pub decode_errors: u64,
+    pub gaps: u64,
+    pub lost_blocks: u64,
+    pub last_error: Option<String>,
+}
+
+impl LinkStats {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn block(&mut self, now: f64, bytes: usize) {
+        self.start.get_or_insert(now);
+        self.blocks += 1;
+        self.bytes += bytes as u64;
+        self.window.push_back((now, bytes));
+        while self.window.front().map_or(false, |&(t, _)| now - t > RATE_WINDOW) {
+            let _ = self.window.pop_front();
+        }
+    }
+
+    pub fn decode_error(&mut self, err: String) {
+        self.decode_errors += 1;
+        self.last_error = Some(err);
+    }
+
+    /// Checks the sequence number of a decoded block header against the previous one
+    pub fn sequence(&mut self, seq: u32) {
+        if let Some(last) = self.last_seq {
+            let lost = seq.wrapping_sub(last).wrapping_sub(1);
+            // A counter going back means the device restarted the stream
+            if lost != 0 && lost < u32::MAX / 2 {
+                self.gaps += 1;
+                self.lost_blocks += lost as u64;
+                log::warn!("Vis stream gap, {} blocks lost", lost);
+            }
+        }
+        self.last_seq = Some(seq);
+    }
+
+    pub fn snapshot(&self, now: f64) -> Snapshot {
+        let span = match self.window.front() {
+            Some(&(t, _)) if now > t => (now - t) / 1000.,
+            _ => 1.,
+        };
+        let win_bytes: usize = self.window.iter().map(|&(_, b)| b).sum();
+        Snapshot {
+            duration_sec: self.start.map_or(0., |t| (now - t) / 1000.),
+            blocks: self.blocks,
+            bytes: self.bytes,
+            blocks_per_sec: self.window.len() as f64 / span,
+            bytes_per_sec: win_bytes as f64 / span,
+            decode_errors: self.decode_errors,
+            gaps: self.gaps,
+            lost_blocks: self.lost_blocks,
+            last_error: self.last_error.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn contiguous_sequence_has_no_gaps() {
+        let mut stats = LinkStats::new();
+        for seq in 10..20 {
+            stats.sequence(seq);
+        }
+        let s = stats.snapshot(0.);
+        assert_eq!((s.gaps, s.lost_blocks), (0, 0));
+    }
+
+    #[test]
+    fn skipped_blocks_are_counted() {
+        let mut stats = LinkStats::new();
+        for &seq in &[1, 2, 5, 6, 9] {
+            stats.sequence(seq);
+        }
+        let s = stats.snapshot(0.);
+        assert_eq!((s.gaps, s.lost_blocks), (2, 4));
+    }
+
+    #[test]
+    fn counter_wrap_and_restart() {
+        let mut stats = LinkStats::new();
+        stats.sequence(u32::MAX - 1);
+        stats.sequence(u32::MAX);
+        stats.sequence(1);
+        assert_eq!(stats.snapshot(0.).lost_blocks, 1);
+        stats.sequence(0);
+        let s = stats.snapshot(0.);
+        assert_eq!((s.gaps, s.lost_blocks), (1, 1));
+    }
+
+    #[test]
+    fn duration_starts_at_first_block() {
+        let mut stats = LinkStats::new();
+        assert_eq!(stats.snapshot(5000.).duration_sec, 0.);
+        stats.block(3000., 100);
+        stats.block(4000., 100);
+        assert_eq!(stats.snapshot(5000.).duration_sec, 2.);
+    }
+}